@@ -0,0 +1,381 @@
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, Sender, SyncSender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::context::REQ_CONTEXT;
+
+/// Access log output, if enabled.
+static ACCESS_LOG: OnceCell<AccessLog> = OnceCell::new();
+
+/// Maximum number of lines waiting to be written. Lines are dropped when the
+/// output can't keep up, rather than slowing down the connections.
+const QUEUE_LEN: usize = 4096;
+
+/// Access log configuration.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct AccessLogConfig {
+    /// Format of the access log lines.
+    #[serde(default)]
+    pub(crate) format: Format,
+    /// File to append the access log to. Defaults to the standard output.
+    pub(crate) path: Option<PathBuf>,
+}
+
+/// Access log formats.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Format {
+    #[default]
+    Logfmt,
+    Json,
+}
+
+/// Access log output. Lines are written by a dedicated thread, so the
+/// connections are never blocked on the output.
+struct AccessLog {
+    format: Format,
+    queue: SyncSender<Message>,
+}
+
+/// Messages sent to the access log writer thread.
+enum Message {
+    Line(String),
+    /// Notifies the sender once the previous lines were written.
+    Flush(Sender<()>),
+}
+
+/// Enables the access log. Must be called once, before any connection is
+/// handled.
+pub(crate) fn init(config: &AccessLogConfig) -> Result<()> {
+    let mut out: Box<dyn Write + Send> = match &config.path {
+        Some(path) => Box::new(LineWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow!("Could not open access log '{}': {e}", path.display()))?,
+        )),
+        None => Box::new(io::stdout()),
+    };
+
+    let (queue, lines) = mpsc::sync_channel(QUEUE_LEN);
+    ACCESS_LOG
+        .set(AccessLog {
+            format: config.format,
+            queue,
+        })
+        .map_err(|_| anyhow!("Access log was already initialized"))?;
+
+    thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || writer(lines, &mut out))?;
+    Ok(())
+}
+
+/// Writes the access log lines received on `lines` to `out`, until all the
+/// senders are gone.
+fn writer(lines: Receiver<Message>, out: &mut dyn Write) {
+    for message in lines {
+        match message {
+            // Not much we can do to report the errors.
+            Message::Line(line) => {
+                let _ = writeln!(out, "{line}");
+            }
+            Message::Flush(done) => {
+                let _ = out.flush();
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Waits for the access log lines of the closed connections to be written, for
+/// up to `timeout`.
+pub(crate) fn flush(timeout: Duration) {
+    if let Some(log) = ACCESS_LOG.get() {
+        let (done, wait) = mpsc::channel();
+        if log.queue.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv_timeout(timeout);
+        }
+    }
+}
+
+/// Per-connection access log record, filled while the connection is handled.
+#[derive(Serialize)]
+pub(crate) struct Record {
+    #[serde(skip)]
+    start: Instant,
+    #[serde(serialize_with = "serialize_time")]
+    pub(crate) time: OffsetDateTime,
+    pub(crate) id: String,
+    pub(crate) duration: f64,
+    pub(crate) peer: String,
+    pub(crate) local: String,
+    pub(crate) sni: Option<String>,
+    pub(crate) alpn: Option<String>,
+    pub(crate) challenge: bool,
+    pub(crate) ja3: Option<String>,
+    pub(crate) ja4: Option<String>,
+    pub(crate) ech_config_id: Option<u8>,
+    pub(crate) route: Option<String>,
+    pub(crate) backend: Option<String>,
+    pub(crate) backend_addr: Option<String>,
+    pub(crate) proxy_protocol: Option<u8>,
+    pub(crate) bytes_to_backend: u64,
+    pub(crate) bytes_to_client: u64,
+    pub(crate) termination: Option<&'static str>,
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            time: OffsetDateTime::now_utc(),
+            id: String::new(),
+            duration: 0.0,
+            peer: String::new(),
+            local: String::new(),
+            sni: None,
+            alpn: None,
+            challenge: false,
+            ja3: None,
+            ja4: None,
+            ech_config_id: None,
+            route: None,
+            backend: None,
+            backend_addr: None,
+            proxy_protocol: None,
+            bytes_to_backend: 0,
+            bytes_to_client: 0,
+            termination: None,
+        }
+    }
+}
+
+impl Record {
+    /// Time elapsed since the connection was accepted.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Formats the record as a logfmt line, without the trailing newline.
+    fn logfmt(&self) -> String {
+        let mut out = String::new();
+        let mut field = |key: &str, value: &str| {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            // Quote values when needed.
+            match value.is_empty() || value.contains([' ', '"', '=', '\\']) {
+                true => {
+                    let _ = write!(out, "{key}={value:?}");
+                }
+                false => {
+                    let _ = write!(out, "{key}={value}");
+                }
+            }
+        };
+
+        field("time", &format_time(&self.time));
+        field("id", &self.id);
+        field("duration", &format!("{:.6}", self.duration));
+        field("peer", &self.peer);
+        field("local", &self.local);
+        field("sni", self.sni.as_deref().unwrap_or_default());
+        field("alpn", self.alpn.as_deref().unwrap_or_default());
+        field("challenge", &self.challenge.to_string());
+        field("ja3", self.ja3.as_deref().unwrap_or_default());
+        field("ja4", self.ja4.as_deref().unwrap_or_default());
+        field(
+            "ech_config_id",
+            &self
+                .ech_config_id
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+        );
+        field("route", self.route.as_deref().unwrap_or_default());
+        field("backend", self.backend.as_deref().unwrap_or_default());
+        field(
+            "backend_addr",
+            self.backend_addr.as_deref().unwrap_or_default(),
+        );
+        field(
+            "proxy_protocol",
+            &self
+                .proxy_protocol
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+        );
+        field("bytes_to_backend", &self.bytes_to_backend.to_string());
+        field("bytes_to_client", &self.bytes_to_client.to_string());
+        field("termination", self.termination.unwrap_or_default());
+        out
+    }
+
+    /// Formats the record as a JSON object, without the trailing newline.
+    fn json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+fn format_time(time: &OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+fn serialize_time<S: serde::Serializer>(time: &OffsetDateTime, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format_time(time))
+}
+
+/// Updates the access log record of the current connection. Silently does
+/// nothing outside of a request context.
+pub(crate) fn update<F: FnOnce(&mut Record)>(f: F) {
+    let _ = REQ_CONTEXT.try_with(|context| f(&mut context.borrow().access.lock().unwrap()));
+}
+
+/// Writes the access log line of the current connection, if the access log is
+/// enabled. `result` is the connection handling outcome, used to report the
+/// termination reason if none was set.
+pub(crate) fn write(result: &Result<()>) {
+    let log = match ACCESS_LOG.get() {
+        Some(log) => log,
+        None => return,
+    };
+
+    let line = REQ_CONTEXT.try_with(|context| {
+        let context = context.borrow();
+        let mut record = context.access.lock().unwrap();
+
+        record.duration = record.start.elapsed().as_secs_f64();
+        if record.termination.is_none() {
+            record.termination = Some(match result {
+                Ok(_) => "closed",
+                Err(_) => "error",
+            });
+        }
+
+        match log.format {
+            Format::Logfmt => Ok(record.logfmt()),
+            Format::Json => record.json(),
+        }
+    });
+
+    if let Ok(Ok(line)) = line {
+        // Do not wait for the writer thread if it lags behind, the line is
+        // dropped.
+        let _ = log.queue.try_send(Message::Line(line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            time: datetime!(2024-05-04 13:37:42.5 UTC),
+            id: "01HX2Z9GC8VX3M4K7Q1R5T6W8Y".to_string(),
+            duration: 1.5,
+            peer: "10.0.42.132:1337".to_string(),
+            local: "172.16.99.1:443".to_string(),
+            sni: Some("example.net".to_string()),
+            alpn: Some("h2,http/1.1".to_string()),
+            ja3: Some("6a75a26f76b4b28b58ac04b868cebdd7".to_string()),
+            ja4: Some("t13d3611a1_018971650b2c_24611a1bfa3f".to_string()),
+            ech_config_id: Some(42),
+            route: Some("*.example.net".to_string()),
+            backend: Some("localhost:8443".to_string()),
+            backend_addr: Some("127.0.0.1:8443".to_string()),
+            proxy_protocol: Some(2),
+            bytes_to_backend: 517,
+            bytes_to_client: 4242,
+            termination: Some("closed"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn logfmt() {
+        assert_eq!(
+            record().logfmt(),
+            "time=2024-05-04T13:37:42.5Z id=01HX2Z9GC8VX3M4K7Q1R5T6W8Y duration=1.500000 \
+             peer=10.0.42.132:1337 local=172.16.99.1:443 sni=example.net alpn=h2,http/1.1 \
+             challenge=false ja3=6a75a26f76b4b28b58ac04b868cebdd7 \
+             ja4=t13d3611a1_018971650b2c_24611a1bfa3f ech_config_id=42 route=*.example.net \
+             backend=localhost:8443 backend_addr=127.0.0.1:8443 proxy_protocol=2 bytes_to_backend=517 \
+             bytes_to_client=4242 termination=closed"
+        );
+
+        let record = Record {
+            sni: Some("a b".to_string()),
+            termination: Some("no_route"),
+            ..Default::default()
+        };
+        let line = record.logfmt();
+        assert!(line.contains(
+            " sni=\"a b\" alpn=\"\" challenge=false ja3=\"\" ja4=\"\" ech_config_id=\"\" \
+                 route=\"\" "
+        ));
+        assert!(line.ends_with(" termination=no_route"));
+    }
+
+    #[test]
+    fn json() {
+        let json: serde_json::Value = serde_json::from_str(&record().json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "time": "2024-05-04T13:37:42.5Z",
+                "id": "01HX2Z9GC8VX3M4K7Q1R5T6W8Y",
+                "duration": 1.5,
+                "peer": "10.0.42.132:1337",
+                "local": "172.16.99.1:443",
+                "sni": "example.net",
+                "alpn": "h2,http/1.1",
+                "challenge": false,
+                "ja3": "6a75a26f76b4b28b58ac04b868cebdd7",
+                "ja4": "t13d3611a1_018971650b2c_24611a1bfa3f",
+                "ech_config_id": 42,
+                "route": "*.example.net",
+                "backend": "localhost:8443",
+                "backend_addr": "127.0.0.1:8443",
+                "proxy_protocol": 2,
+                "bytes_to_backend": 517,
+                "bytes_to_client": 4242,
+                "termination": "closed",
+            })
+        );
+    }
+
+    #[test]
+    fn write() {
+        let (queue, lines) = mpsc::sync_channel(QUEUE_LEN);
+        let writer = thread::spawn(move || {
+            let mut out = Vec::new();
+            writer(lines, &mut out);
+            out
+        });
+
+        queue.send(Message::Line("a=1".to_string())).unwrap();
+        let (done, wait) = mpsc::channel();
+        queue.send(Message::Flush(done)).unwrap();
+        wait.recv_timeout(Duration::from_secs(5)).unwrap();
+        queue.send(Message::Line("b=2".to_string())).unwrap();
+
+        // The writer returns once the queue is closed.
+        drop(queue);
+        assert_eq!(writer.join().unwrap(), b"a=1\nb=2\n");
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::{AdminBind, Backend, BackendState},
+    registry,
+    reload::{self, SharedConfig},
+};
+
+/// Starts an HTTP server on `bind` serving the admin interface, until
+/// `shutdown` is cancelled. `path` is the configuration file, used for
+/// reloads.
+pub(crate) async fn serve(
+    bind: AdminBind,
+    config: SharedConfig,
+    path: PathBuf,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let admin = Arc::new(Admin { config, path });
+
+    match bind {
+        AdminBind::Tcp(bind) => {
+            let listener = TcpListener::bind(bind).await?;
+            loop {
+                let stream = tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        debug!("Closing listener on {bind}");
+                        return Ok(());
+                    }
+                    conn = listener.accept() => match conn {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            error!("Connection error: {e}");
+                            continue;
+                        }
+                    },
+                };
+                tokio::spawn(Arc::clone(&admin).handle_request(stream));
+            }
+        }
+        AdminBind::Unix(bind) => {
+            // Remove a stale socket left by a previous run, if any.
+            let _ = fs::remove_file(&bind);
+            let listener = UnixListener::bind(&bind)?;
+            fs::set_permissions(&bind, fs::Permissions::from_mode(0o600))?;
+            loop {
+                let stream = tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        debug!("Closing listener on {}", bind.display());
+                        let _ = fs::remove_file(&bind);
+                        return Ok(());
+                    }
+                    conn = listener.accept() => match conn {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            error!("Connection error: {e}");
+                            continue;
+                        }
+                    },
+                };
+                tokio::spawn(Arc::clone(&admin).handle_request(stream));
+            }
+        }
+    }
+}
+
+/// Admin interface state.
+struct Admin {
+    config: SharedConfig,
+    path: PathBuf,
+}
+
+/// Response to an admin request.
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        let mut body = body.into();
+        body.push('\n');
+        Self {
+            status,
+            content_type: "text/plain",
+            body,
+        }
+    }
+
+    fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_string_pretty(value) {
+            Ok(body) => Self {
+                status: "200 OK",
+                content_type: "application/json",
+                body: body + "\n",
+            },
+            Err(e) => Self::text("500 Internal Server Error", e.to_string()),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self::text("404 Not Found", "Not found")
+    }
+}
+
+/// Backend state, as reported by the admin interface.
+#[derive(Serialize)]
+struct BackendStatus<'a> {
+    route: &'a str,
+    address: &'a str,
+    backup: bool,
+    state: BackendState,
+    healthy: bool,
+    active_connections: usize,
+}
+
+impl Admin {
+    /// Handles a single HTTP request to the admin interface.
+    async fn handle_request<S>(self: Arc<Self>, mut stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // We only care about the request line, which fits in the first read.
+        let mut buf = [0; 1024];
+        let len = match timeout(Duration::from_secs(3), stream.read(&mut buf)).await {
+            Ok(Ok(len)) => len,
+            _ => return,
+        };
+
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+        let (method, target) = (
+            request_line.next().unwrap_or_default(),
+            request_line.next().unwrap_or_default(),
+        );
+
+        let response = self.route(method, target);
+        let response = format!(
+            "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            response.status,
+            response.content_type,
+            response.body.len(),
+            response.body
+        );
+
+        if let Err(e) = stream.write_all(response.as_bytes()).await {
+            debug!("Could not serve admin request: {e}");
+        }
+        let _ = stream.shutdown().await;
+    }
+
+    /// Executes an admin request and returns its response.
+    fn route(&self, method: &str, target: &str) -> Response {
+        let path: Vec<&str> = target.trim_matches('/').split('/').collect();
+
+        match (method, path.as_slice()) {
+            ("GET", ["connections"]) => Response::json(&registry::list()),
+            ("POST", ["connections", id, "kill"]) => match registry::kill(id) {
+                true => {
+                    info!("Killing connection {id}");
+                    Response::text("200 OK", format!("Connection {id} killed"))
+                }
+                false => Response::not_found(),
+            },
+            ("GET", ["config"]) => match serde_yaml::to_string(&**self.config.load()) {
+                Ok(body) => Response {
+                    status: "200 OK",
+                    content_type: "application/yaml",
+                    body,
+                },
+                Err(e) => Response::text("500 Internal Server Error", e.to_string()),
+            },
+            ("GET", ["backends"]) => {
+                let config = self.config.load();
+                let backends: Vec<BackendStatus> = config
+                    .routes
+                    .iter()
+                    .flat_map(|route| {
+                        route.all_backends().map(|backend| BackendStatus {
+                            route: route.name(),
+                            address: &backend.address,
+                            backup: backend.backup,
+                            state: backend.state(),
+                            healthy: backend.is_healthy(),
+                            active_connections: backend.active_connections(),
+                        })
+                    })
+                    .collect();
+                Response::json(&backends)
+            }
+            ("POST", ["backends", route, address, action]) => {
+                let state = match *action {
+                    "enable" => BackendState::Enabled,
+                    "drain" => BackendState::Draining,
+                    "disable" => BackendState::Disabled,
+                    _ => return Response::not_found(),
+                };
+                self.set_backend_state(route, address, state)
+            }
+            ("POST", ["reload"]) => match reload::reload(&self.path, &self.config) {
+                Ok(_) => {
+                    info!("Configuration reloaded from the admin interface");
+                    Response::text("200 OK", "Configuration reloaded")
+                }
+                Err(e) => {
+                    error!("Could not reload the configuration, keeping the current one: {e}");
+                    Response::text("500 Internal Server Error", e.to_string())
+                }
+            },
+            _ => Response::not_found(),
+        }
+    }
+
+    /// Sets the administrative state of a backend, of any kind. Backends
+    /// sharing the address in the route, eg. a regular and an ALPN one, are
+    /// all updated. Disabling a backend closes the connections proxied to it.
+    fn set_backend_state(&self, route: &str, address: &str, state: BackendState) -> Response {
+        let config = self.config.load();
+        let backends: Vec<&Backend> = config
+            .routes
+            .iter()
+            .filter(|r| r.name() == route)
+            .flat_map(|r| r.all_backends())
+            .filter(|b| b.address == address)
+            .collect();
+        if backends.is_empty() {
+            return Response::not_found();
+        }
+
+        info!("Setting backend {address} of route {route} as {state}");
+        backends.iter().for_each(|b| b.set_state(state));
+
+        let mut msg = format!("Backend {address} of route {route} is now {state}");
+        if state == BackendState::Disabled {
+            let killed = registry::kill_backend(route, address);
+            msg.push_str(&format!(", {killed} connection(s) closed"));
+        }
+        Response::text("200 OK", msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn admin() -> Admin {
+        Admin {
+            config: Arc::new(ArcSwap::from_pointee(
+                Config::from_str(
+                    "
+routes:
+  - name: example
+    domains:
+      - example.net
+      - \"*.example.net\"
+    backends:
+      - address: 127.0.0.1:8443
+      - address: 127.0.0.1:9443
+        backup: true
+    alpn_challenge_backend:
+      address: 127.0.0.1:10443
+                    ",
+                )
+                .unwrap(),
+            )),
+            path: PathBuf::from("/nonexistent/sniproxy.yaml"),
+        }
+    }
+
+    #[test]
+    fn route() {
+        let admin = admin();
+
+        let response = admin.route("GET", "/connections");
+        assert_eq!(response.status, "200 OK");
+        assert!(response.body.starts_with('['));
+
+        let response = admin.route("GET", "/config");
+        assert_eq!(response.status, "200 OK");
+        let config: serde_yaml::Value = serde_yaml::from_str(&response.body).unwrap();
+        assert_eq!(config["bind_https"], "[::]:443");
+        assert_eq!(config["routes"][0]["name"], "example");
+        assert_eq!(config["routes"][0]["domains"][1], "*.example.net");
+        assert_eq!(config["routes"][0]["backends"][1]["backup"], true);
+
+        // Backends administrative state.
+        let config = admin.config.load_full();
+        let backend = || &config.routes[0].backends[0];
+        let response = admin.route("POST", "/backends/example/127.0.0.1:8443/drain");
+        assert_eq!(response.status, "200 OK");
+        assert_eq!(backend().state(), BackendState::Draining);
+        assert!(!backend().is_available());
+        admin.route("POST", "/backends/example/127.0.0.1:8443/disable");
+        assert_eq!(backend().state(), BackendState::Disabled);
+        admin.route("POST", "/backends/example/127.0.0.1:8443/enable");
+        assert!(backend().is_available());
+
+        let response = admin.route("GET", "/backends");
+        let backends: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(backends[0]["route"], "example");
+        assert_eq!(backends[0]["state"], "enabled");
+        assert_eq!(backends[1]["backup"], true);
+
+        // ALPN and ECH backends are handled too.
+        assert_eq!(backends[2]["address"], "127.0.0.1:10443");
+        admin.route("POST", "/backends/example/127.0.0.1:10443/drain");
+        let backend = &config.routes[0].alpn_backends[0].backend;
+        assert_eq!(backend.state(), BackendState::Draining);
+
+        for (method, target) in [
+            ("POST", "/backends/example/127.0.0.1:8443/foo"),
+            ("POST", "/backends/example/127.0.0.1:1/drain"),
+            ("POST", "/backends/foo/127.0.0.1:8443/drain"),
+            ("POST", "/connections/01J4ZHMASWH9F4YZ814D2PF2DB/kill"),
+            ("GET", "/reload"),
+            ("GET", "/"),
+        ] {
+            assert_eq!(admin.route(method, target).status, "404 Not Found");
+        }
+
+        // The configuration file does not exist.
+        let response = admin.route("POST", "/reload");
+        assert_eq!(response.status, "500 Internal Server Error");
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::Rejection;
+
+/// Interval between two removals of the clients we no longer need to track.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Per-client limits on new connections rate and on concurrent connections.
+/// Clients are identified by their IP address, truncated to `ipv4_prefix` or
+/// `ipv6_prefix` bits.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ClientLimits {
+    /// Number of new connections per second allowed per client, on average.
+    /// Unlimited if not set.
+    pub(crate) rate: Option<f64>,
+    /// Number of new connections a client can open in a row, before being
+    /// limited to `rate`. Defaults to `rate`, rounded up.
+    pub(crate) burst: Option<u32>,
+    /// Maximum number of connections per client at the same time. Unlimited
+    /// if not set.
+    pub(crate) max_connections: Option<usize>,
+    /// Prefix length IPv4 addresses are truncated to. Defaults to 32 (each
+    /// address is a client).
+    #[serde(default = "default_ipv4_prefix")]
+    pub(crate) ipv4_prefix: u8,
+    /// Prefix length IPv6 addresses are truncated to. Defaults to 64.
+    #[serde(default = "default_ipv6_prefix")]
+    pub(crate) ipv6_prefix: u8,
+    /// Clients in those IP ranges are not limited.
+    #[serde(default)]
+    pub(crate) exempt_ranges: Vec<IpNet>,
+}
+
+impl ClientLimits {
+    /// Checks the limits values are valid.
+    pub(crate) fn check(&self) -> Result<()> {
+        if let Some(rate) = self.rate {
+            if !rate.is_finite() || rate <= 0.0 {
+                bail!("client_limits rate must be a positive number");
+            }
+        }
+        if self.burst == Some(0) || self.max_connections == Some(0) {
+            bail!("client_limits burst and max_connections cannot be null");
+        }
+        if self.ipv4_prefix > 32 || self.ipv6_prefix > 128 {
+            bail!("client_limits prefixes must be at most 32 (IPv4) and 128 (IPv6)");
+        }
+        Ok(())
+    }
+
+    fn burst(&self) -> f64 {
+        match (self.burst, self.rate) {
+            (Some(burst), _) => burst as f64,
+            (None, Some(rate)) => rate.ceil(),
+            (None, None) => 0.0,
+        }
+    }
+}
+
+/// Reasons for refusing a connection from a client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Refusal {
+    /// The client opens new connections too fast.
+    Rate,
+    /// The client has too many connections opened.
+    Connections,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Refusal::Rate => "connection rate limit reached",
+            Refusal::Connections => "concurrent connections limit reached",
+        })
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+impl From<Refusal> for Rejection {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::Rate => Rejection::RateLimited,
+            Refusal::Connections => Rejection::ClientLimit,
+        }
+    }
+}
+
+/// State of a single client.
+struct Client {
+    /// Tokens left in the client bucket, a new connection consuming one.
+    tokens: f64,
+    /// Last time `tokens` was refilled.
+    refilled: Instant,
+    /// Number of connections currently opened by the client.
+    active: usize,
+}
+
+/// Enforces `ClientLimits`, keeping track of the clients.
+pub(crate) struct ClientLimiter {
+    pub(crate) limits: ClientLimits,
+    clients: Mutex<Clients>,
+}
+
+struct Clients {
+    map: HashMap<IpAddr, Client>,
+    pruned: Instant,
+}
+
+impl fmt::Debug for ClientLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientLimiter")
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+/// Keeps track of a connection opened by a client, for as long as it lives.
+pub(crate) struct ClientSlot {
+    limiter: Arc<ClientLimiter>,
+    key: IpAddr,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        if let Some(client) = self.limiter.clients.lock().unwrap().map.get_mut(&self.key) {
+            client.active = client.active.saturating_sub(1);
+        }
+    }
+}
+
+impl ClientLimiter {
+    pub(crate) fn new(limits: ClientLimits) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            clients: Mutex::new(Clients {
+                map: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        })
+    }
+
+    /// Accounts for a new connection from `ip` at `now`, or refuses it if the
+    /// client reached one of its limits. The connection is accounted for until
+    /// the returned value is dropped; exempted clients are not tracked.
+    pub(crate) fn admit(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        now: Instant,
+    ) -> Result<Option<ClientSlot>, Refusal> {
+        let ip = ip.to_canonical();
+        if self.limits.exempt_ranges.iter().any(|r| r.contains(&ip)) {
+            return Ok(None);
+        }
+        let key = self.key(ip);
+
+        let burst = self.limits.burst();
+        let mut clients = self.clients.lock().unwrap();
+        if now.duration_since(clients.pruned) >= PRUNE_INTERVAL {
+            self.prune(&mut clients, now);
+        }
+
+        let client = clients.map.entry(key).or_insert(Client {
+            tokens: burst,
+            refilled: now,
+            active: 0,
+        });
+        if let Some(max) = self.limits.max_connections {
+            if client.active >= max {
+                return Err(Refusal::Connections);
+            }
+        }
+        if self.limits.rate.is_some() {
+            self.refill(client, now);
+            if client.tokens < 1.0 {
+                return Err(Refusal::Rate);
+            }
+            client.tokens -= 1.0;
+        }
+        client.active += 1;
+
+        Ok(Some(ClientSlot {
+            limiter: Arc::clone(self),
+            key,
+        }))
+    }
+
+    /// Returns the key identifying the client using `ip`.
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        let prefix = match ip {
+            IpAddr::V4(_) => self.limits.ipv4_prefix,
+            IpAddr::V6(_) => self.limits.ipv6_prefix,
+        };
+        // Prefixes are checked when parsing the configuration.
+        IpNet::new(ip, prefix)
+            .map(|net| net.network())
+            .unwrap_or(ip)
+    }
+
+    /// Refills the client bucket for the time elapsed since the last refill.
+    fn refill(&self, client: &mut Client, now: Instant) {
+        let rate = self.limits.rate.unwrap_or_default();
+        let elapsed = now.duration_since(client.refilled).as_secs_f64();
+        client.tokens = (client.tokens + elapsed * rate).min(self.limits.burst());
+        client.refilled = now;
+    }
+
+    /// Stops tracking clients with no connection and a full bucket, as they
+    /// are in the same state as new ones.
+    fn prune(&self, clients: &mut Clients, now: Instant) {
+        let burst = self.limits.burst();
+        clients.map.retain(|_, client| {
+            self.refill(client, now);
+            client.active > 0 || client.tokens < burst
+        });
+        clients.pruned = now;
+    }
+}
+
+/// Accounts for a new connection from `peer` in `limiter`, if any. See
+/// `ClientLimiter::admit`.
+pub(crate) fn admit(
+    limiter: Option<&Arc<ClientLimiter>>,
+    peer: &SocketAddr,
+) -> Result<Option<ClientSlot>, Refusal> {
+    match limiter {
+        Some(limiter) => limiter.admit(peer.ip(), Instant::now()),
+        None => Ok(None),
+    }
+}
+
+// Default values.
+fn default_ipv4_prefix() -> u8 {
+    32
+}
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: &str) -> Arc<ClientLimiter> {
+        let limits: ClientLimits = serde_yaml::from_str(limits).unwrap();
+        limits.check().unwrap();
+        ClientLimiter::new(limits)
+    }
+
+    #[test]
+    fn rate() {
+        let limiter = limiter(
+            "
+rate: 10
+burst: 2
+ipv4_prefix: 24
+exempt_ranges:
+  - 10.0.42.0/28
+            ",
+        );
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        // The burst is shared by the clients of a /24.
+        assert!(limiter.admit(ip("10.0.0.1"), now).is_ok());
+        assert!(limiter.admit(ip("::ffff:10.0.0.2"), now).is_ok());
+        assert_eq!(
+            limiter.admit(ip("10.0.0.3"), now).err(),
+            Some(Refusal::Rate)
+        );
+        assert!(limiter.admit(ip("10.0.1.1"), now).is_ok());
+
+        // Tokens are refilled over time.
+        let now = now + Duration::from_millis(120);
+        assert!(limiter.admit(ip("10.0.0.3"), now).is_ok());
+        assert_eq!(
+            limiter.admit(ip("10.0.0.3"), now).err(),
+            Some(Refusal::Rate)
+        );
+
+        // Up to the burst.
+        let now = now + Duration::from_secs(60);
+        assert!(limiter.admit(ip("10.0.0.3"), now).is_ok());
+        assert!(limiter.admit(ip("10.0.0.3"), now).is_ok());
+        assert_eq!(
+            limiter.admit(ip("10.0.0.3"), now).err(),
+            Some(Refusal::Rate)
+        );
+
+        // Exempted clients are not limited.
+        for _ in 0..10 {
+            assert!(limiter.admit(ip("10.0.42.1"), now).unwrap().is_none());
+        }
+        assert!(limiter.admit(ip("10.0.42.42"), now).unwrap().is_some());
+    }
+
+    #[test]
+    fn max_connections() {
+        let limiter = limiter("max_connections: 2");
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let now = Instant::now();
+
+        // IPv6 clients are identified by their /64.
+        let a = limiter.admit(ip("2001:db8::1"), now).unwrap();
+        let _b = limiter.admit(ip("2001:db8::2"), now).unwrap();
+        assert_eq!(
+            limiter.admit(ip("2001:db8::3"), now).err(),
+            Some(Refusal::Connections)
+        );
+        assert!(limiter.admit(ip("2001:db8:0:1::1"), now).is_ok());
+
+        // Slots are released when connections close.
+        drop(a);
+        assert!(limiter.admit(ip("2001:db8::3"), now).is_ok());
+
+        // Idle clients are pruned.
+        let mut clients = limiter.clients.lock().unwrap();
+        limiter.prune(&mut clients, now);
+        assert_eq!(clients.map.len(), 1);
+    }
+
+    #[test]
+    fn invalid() {
+        for limits in [
+            "rate: 0",
+            "rate: -1",
+            "rate: .nan",
+            "burst: 0",
+            "max_connections: 0",
+            "ipv4_prefix: 33",
+            "ipv6_prefix: 129",
+        ] {
+            let limits: ClientLimits = serde_yaml::from_str(limits).unwrap();
+            assert!(limits.check().is_err());
+        }
+    }
+}
@@ -1,14 +1,29 @@
 use std::{
     cmp, fmt, fs,
-    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::{anyhow, bail, Result};
 use ipnet::IpNet;
 use regex::RegexSet;
-use serde::{de, Deserialize};
+use serde::{de, Deserialize, Serialize, Serializer};
 use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::{
+    access_log::AccessLogConfig,
+    client_limits::{ClientLimiter, ClientLimits},
+    dns::{DnsConfig, Resolver},
+    fingerprint::{self, Fingerprint},
+    limits::{ConnectionLimit, Limiter},
+    tls::{ClientHello, TlsVersion, ACME_TLS_ALPN},
+};
 
 #[derive(Error, Debug)]
 pub(crate) enum Error {
@@ -16,12 +31,18 @@ pub(crate) enum Error {
     HostnameNotFound,
     #[error("no backend")]
     NoBackend,
+    #[error("no healthy backend")]
+    NoHealthyBackend,
     #[error("access denied")]
     AccessDenied,
+    #[error("TLS version {0:#x} is below the route minimum")]
+    ProtocolVersion(u16),
+    #[error("encrypted client hello denied")]
+    EchDenied,
 }
 
 /// Main (internal) configuration.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Config {
     /// TCP address & port to bind to for the TLS SNI proxy. Defaults to
     /// `[::]:443`.
@@ -31,21 +52,138 @@ pub(crate) struct Config {
     /// Defaults to `[::]:80`.
     #[serde(default = "default_bind_http")]
     pub(crate) bind_http: SocketAddr,
+    /// Time in seconds given to established connections to finish when
+    /// shutting down, before they are forcibly closed. Defaults to 30s.
+    #[serde(default = "default_drain_timeout")]
+    pub(crate) drain_timeout: u64,
+    /// Default connection timeouts, see `Timeouts`.
+    #[serde(default)]
+    pub(crate) timeouts: Timeouts,
+    /// Maximum length in bytes of the ClientHello handshake message, which
+    /// can span multiple TLS records. The records holding it can't exceed
+    /// twice this length, their headers included, so a message close to the
+    /// limit split in tiny records is refused. Defaults to 64KiB.
+    #[serde(default = "default_max_client_hello_size")]
+    pub(crate) max_client_hello_size: usize,
+    /// Maximum number of connections proxied at the same time, for all routes
+    /// combined. Unlimited if not set.
+    pub(crate) max_connections: Option<usize>,
+    /// Behavior when a connection limit is reached, see `ConnectionLimit`.
+    #[serde(default)]
+    pub(crate) connection_limit: ConnectionLimit,
+    /// Enforces `max_connections`.
+    #[serde(skip)]
+    pub(crate) limiter: Option<Arc<Limiter>>,
+    /// Per-client connection limits, enforced on all connections as soon as
+    /// they are accepted. See `ClientLimits`.
+    pub(crate) client_limits: Option<ClientLimits>,
+    /// Enforces `client_limits`.
+    #[serde(skip)]
+    pub(crate) client_limiter: Option<Arc<ClientLimiter>>,
+    /// Resolution of backend hostnames, see `DnsConfig`.
+    #[serde(default)]
+    pub(crate) dns: DnsConfig,
+    /// Resolves backend hostnames, as configured in `dns`.
+    #[serde(skip)]
+    pub(crate) resolver: Arc<Resolver>,
+    /// TCP address & port to bind to for serving Prometheus metrics. Metrics
+    /// are not served if not set.
+    pub(crate) bind_metrics: Option<SocketAddr>,
+    /// Address to bind to for serving the admin interface, either a loopback
+    /// TCP address & port or the path of a unix socket. The admin interface
+    /// is not served if not set.
+    pub(crate) bind_admin: Option<AdminBind>,
+    /// Per-connection access log. Disabled if not set.
+    pub(crate) access_log: Option<AccessLogConfig>,
+    /// Accept HAProxy protocol headers on incoming connections, eg. when
+    /// running behind a load balancer.
+    pub(crate) accept_proxy_protocol: Option<AcceptProxyProtocol>,
     /// List of routes.
-    routes: Vec<Route>,
+    pub(crate) routes: Vec<Route>,
 }
 
 impl Config {
     /// Parses a file in YAML formatted str and converts it to a `Config`
     /// representation.
     pub(crate) fn from_str(input: &str) -> Result<Config> {
-        let config: Self = serde_yaml::from_str(input)?;
+        let mut config: Self = serde_yaml::from_str(input)?;
 
         // Sanity check the configuration:
-        for (i, route) in config.routes.iter().enumerate() {
+        match &config.bind_admin {
+            Some(AdminBind::Tcp(addr)) if !addr.ip().is_loopback() => {
+                bail!("bind_admin must use a loopback address, not {addr}")
+            }
+            Some(AdminBind::Unix(path)) if !path.is_absolute() => {
+                bail!("bind_admin must be a loopback address:port or an absolute path")
+            }
+            _ => (),
+        }
+        config.timeouts.check()?;
+        if config.max_client_hello_size == 0 {
+            bail!("max_client_hello_size cannot be null");
+        }
+        config.limiter = limiter(config.max_connections)?;
+        config.client_limiter = client_limiter(&config.client_limits)?;
+        config.resolver = Arc::new(Resolver::new(config.dns.clone())?);
+        if let Some(accept) = &config.accept_proxy_protocol {
+            if accept.trusted_ranges.is_empty() {
+                bail!("accept_proxy_protocol requires at least one trusted range");
+            }
+        }
+
+        for (i, route) in config.routes.iter_mut().enumerate() {
+            // A single backend is a shorthand for a list of backends.
+            if let Some(backend) = route.backend.take() {
+                if !route.backends.is_empty() {
+                    bail!("Route {i} has both a backend and a list of backends");
+                }
+                route.backends.push(backend);
+            }
+
+            // The ALPN challenge backend is a shorthand for an ALPN backend
+            // matching tls-alpn-01 challenges, taking precedence over the
+            // others.
+            if let Some(backend) = route.alpn_challenge_backend.take() {
+                route.alpn_backends.insert(
+                    0,
+                    AlpnBackend {
+                        protocols: vec![ACME_TLS_ALPN.to_string()],
+                        backend,
+                        bypass_acl: route.alpn_challenge_bypass_acl,
+                    },
+                );
+            }
+
+            // Routes are named after their first domain by default. Convert the
+            // pattern back to its configuration form.
+            if route.name.is_none() {
+                route.name = route
+                    .domains
+                    .patterns()
+                    .first()
+                    .map(|p| pattern_to_domain(p));
+            }
+
             // Routes must have at least one of the backend types.
-            if route.backend.is_none() && route.alpn_challenge_backend.is_none() {
-                bail!("Route {i} has neither a backend nor an alpn_challenge_backend");
+            if route.backends.is_empty()
+                && route.alpn_backends.is_empty()
+                && route.ech_backend.is_none()
+            {
+                bail!("Route {i} has neither a backend nor an ALPN or ECH backend");
+            }
+            if route.deny_ech && route.ech_backend.is_some() {
+                bail!("Route {i} has both an ECH backend and deny_ech set");
+            }
+            if (route.deny_ech || route.ech_backend.is_some()) && route.ech_config_ids.is_empty() {
+                bail!("Route {i} has an ECH backend or deny_ech set, but no ECH config ids");
+            }
+
+            for f in route
+                .denied_fingerprints
+                .iter_mut()
+                .chain(route.allowed_fingerprints.iter_mut())
+            {
+                *f = fingerprint::parse(f)?;
             }
 
             let check_address = |address: &str| {
@@ -72,11 +210,63 @@ impl Config {
                 }
                 Ok(())
             };
-            if let Some(backend) = &route.backend {
+            let check_tlvs = |backend: &Backend| {
+                // TLVs are only supported by the v2 protocol.
+                if !backend.proxy_protocol_tlvs.is_empty() && backend.proxy_protocol != Some(2) {
+                    bail!(
+                        "Backend {} uses proxy_protocol_tlvs without proxy_protocol v2",
+                        backend.address
+                    );
+                }
+                Ok(())
+            };
+            // The route is not known yet when receiving the client handshake.
+            let check_timeouts = |timeouts: &Timeouts| {
+                if timeouts.client_hello.is_some() {
+                    bail!("Route {i} sets a client_hello timeout, which can only be set globally");
+                }
+                timeouts.check()
+            };
+            check_timeouts(&route.timeouts)?;
+            route.limiter = limiter(route.max_connections)?;
+            route.client_limiter = client_limiter(&route.client_limits)?;
+            for backend in route.backends.iter_mut() {
+                check_address(&backend.address)?;
+                check_tlvs(backend)?;
+                check_timeouts(&backend.timeouts)?;
+                backend.limiter = limiter(backend.max_connections)?;
+
+                // A backend with no weight would never be used.
+                if backend.weight == 0 {
+                    bail!("Backend {} has a null weight", backend.address);
+                }
+            }
+            for AlpnBackend {
+                protocols, backend, ..
+            } in route.alpn_backends.iter_mut()
+            {
+                if protocols.is_empty() {
+                    bail!("ALPN backend {} has no protocol", backend.address);
+                }
                 check_address(&backend.address)?;
+                check_tlvs(backend)?;
+                check_timeouts(&backend.timeouts)?;
+                backend.limiter = limiter(backend.max_connections)?;
             }
-            if let Some(backend) = &route.alpn_challenge_backend {
+            if let Some(backend) = &mut route.ech_backend {
                 check_address(&backend.address)?;
+                check_tlvs(backend)?;
+                check_timeouts(&backend.timeouts)?;
+                backend.limiter = limiter(backend.max_connections)?;
+            }
+
+            if let Some(check) = &route.health_check {
+                if check.r#type == HealthCheckType::Tls && check.sni.is_none() {
+                    bail!("Route {i} uses TLS health checks but has no sni defined");
+                }
+                if check.interval == 0 || check.timeout == 0 || check.rise == 0 || check.fall == 0 {
+                    bail!("Route {i} has invalid health checks parameters (null value)");
+                }
             }
         }
 
@@ -98,38 +288,150 @@ impl Config {
         self.routes.iter().find(|r| r.domains.is_match(domain))
     }
 
-    /// Returns a reference to a backend matching the input domain, if any.
+    /// Returns a reference to a backend matching the input domain and the
+    /// client ClientHello, if any, and to its route.
     pub(crate) fn get_backend(
         &self,
         hostname: &str,
         peer: &SocketAddr,
-        is_challenge: bool,
-    ) -> Result<&Backend> {
+        hello: &ClientHello,
+        fingerprint: &Fingerprint,
+    ) -> Result<(&Route, &Backend)> {
         // Get the corresponding route.
         let route = match self.get_route(hostname) {
             Some(route) => route,
             None => bail!(Error::HostnameNotFound),
         };
+        // Encrypted Client Hello connections go to the ECH backend, if any:
+        // the ALPN protocols of the outer ClientHello are not the real ones.
+        let ech_backend = route.ech_backend.as_ref().filter(|_| route.uses_ech(hello));
+        let alpn_backend = match ech_backend {
+            Some(_) => None,
+            None => route.alpn_backend(hello),
+        };
 
-        // Check ACLs (or opt-in bypass for ALPN challenges).
-        if !is_challenge || !route.alpn_challenge_bypass_acl {
+        // Check ACLs (or opt-in bypass for ALPN backends).
+        if !alpn_backend.is_some_and(|b| b.bypass_acl) {
             // Check ACLs.
-            if !route.is_allowed(peer) {
+            if !route.is_allowed(peer) || !route.is_fingerprint_allowed(fingerprint) {
                 bail!(Error::AccessDenied);
             }
         }
 
-        // Get the right backend.
-        let backend = match is_challenge {
-            false => route.backend.as_ref(),
-            true => route
-                .alpn_challenge_backend
-                .as_ref()
-                .or(route.backend.as_ref()),
+        // Check the client can use the minimum TLS version.
+        if route
+            .min_tls_version
+            .is_some_and(|min| hello.max_version() < min as u16)
+        {
+            bail!(Error::ProtocolVersion(hello.max_version()));
+        }
+
+        // Refuse Encrypted Client Hello connections, if asked to.
+        if route.deny_ech && route.uses_ech(hello) {
+            bail!(Error::EchDenied);
         }
-        .ok_or_else(|| anyhow!(Error::NoBackend))?;
 
-        Ok(backend)
+        // Get the right backend. ECH connections can't be handled by the
+        // other backends, they fail if the ECH backend is not available.
+        let backend = match (ech_backend, alpn_backend) {
+            (Some(ech_backend), _) => Some(ech_backend).filter(|b| b.is_available()),
+            (_, Some(alpn_backend)) => Some(&alpn_backend.backend),
+            _ => route.select_backend(),
+        };
+
+        let backend = match backend {
+            Some(backend) => backend,
+            None if route.backends.is_empty() => bail!(Error::NoBackend),
+            None => bail!(Error::NoHealthyBackend),
+        };
+
+        Ok((route, backend))
+    }
+
+    /// Checks if an HAProxy protocol header is expected on a connection from
+    /// `peer` to `listener`.
+    pub(crate) fn accepts_proxy_protocol(&self, listener: Listener, peer: &SocketAddr) -> bool {
+        let accept = match &self.accept_proxy_protocol {
+            Some(accept) => accept,
+            None => return false,
+        };
+        let enabled = match listener {
+            Listener::Https => accept.https,
+            Listener::Http => accept.http,
+        };
+
+        enabled
+            && accept
+                .trusted_ranges
+                .iter()
+                .any(|r| Route::contains(r, &peer.ip()))
+    }
+
+    /// Returns the behavior to use when a connection limit is reached on
+    /// `route`: its own or the global one.
+    pub(crate) fn connection_limit<'a>(&'a self, route: &'a Route) -> &'a ConnectionLimit {
+        route
+            .connection_limit
+            .as_ref()
+            .unwrap_or(&self.connection_limit)
+    }
+
+    /// Copies the backends administrative state, their active connection
+    /// counts and the connection limiters from `other`, eg. the configuration
+    /// being replaced on reloads, so established connections keep being
+    /// accounted for. Routes are matched using their name and backends using
+    /// their address. Limiters are only kept if their limits did not change,
+    /// and the backends health status if the route health checks did not
+    /// change. The resolver, and its cache, is kept too if the DNS
+    /// configuration did not change.
+    pub(crate) fn inherit_state(&mut self, other: &Config) {
+        if self.dns == other.dns {
+            self.resolver = Arc::clone(&other.resolver);
+        }
+        inherit_limiter(&mut self.limiter, &other.limiter);
+        inherit_client_limiter(&mut self.client_limiter, &other.client_limiter);
+        for route in self.routes.iter_mut() {
+            let other = match other.routes.iter().find(|r| r.name() == route.name()) {
+                Some(other) => other,
+                None => continue,
+            };
+            inherit_limiter(&mut route.limiter, &other.limiter);
+            inherit_client_limiter(&mut route.client_limiter, &other.client_limiter);
+            for backend in route.backends.iter_mut() {
+                if let Some(b) = other.backends.iter().find(|b| b.address == backend.address) {
+                    backend.set_state(b.state());
+                    backend.active = Arc::clone(&b.active);
+                    if route.health_check == other.health_check {
+                        backend.set_healthy(b.is_healthy());
+                    }
+                    inherit_limiter(&mut backend.limiter, &b.limiter);
+                }
+            }
+            for AlpnBackend { backend, .. } in route.alpn_backends.iter_mut() {
+                if let Some(b) = other
+                    .alpn_backends
+                    .iter()
+                    .find(|b| b.backend.address == backend.address)
+                {
+                    backend.set_state(b.backend.state());
+                    backend.active = Arc::clone(&b.backend.active);
+                    if route.health_check == other.health_check {
+                        backend.set_healthy(b.backend.is_healthy());
+                    }
+                    inherit_limiter(&mut backend.limiter, &b.backend.limiter);
+                }
+            }
+            if let (Some(backend), Some(b)) = (&mut route.ech_backend, &other.ech_backend) {
+                if b.address == backend.address {
+                    backend.set_state(b.state());
+                    backend.active = Arc::clone(&b.active);
+                    if route.health_check == other.health_check {
+                        backend.set_healthy(b.is_healthy());
+                    }
+                    inherit_limiter(&mut backend.limiter, &b.limiter);
+                }
+            }
+        }
     }
 
     /// Do we need an HTTP server.
@@ -138,19 +440,126 @@ impl Config {
     }
 }
 
+/// Listeners accepting client connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Listener {
+    /// TLS SNI proxy, see `bind_https`.
+    Https,
+    /// HTTP to HTTPS redirection, see `bind_http`.
+    Http,
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Listener::Https => "HTTPS",
+            Listener::Http => "HTTP",
+        })
+    }
+}
+
+/// Admin interface listening address.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub(crate) enum AdminBind {
+    /// TCP address & port.
+    Tcp(SocketAddr),
+    /// Path of a unix socket.
+    Unix(PathBuf),
+}
+
+/// Inbound HAProxy protocol parameters.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AcceptProxyProtocol {
+    /// Expect headers on the HTTPS listener.
+    #[serde(default)]
+    pub(crate) https: bool,
+    /// Expect headers on the HTTP listener.
+    #[serde(default)]
+    pub(crate) http: bool,
+    /// Headers are only expected from, and accepted from, those IP ranges.
+    /// Other clients are handled as if they connected directly.
+    pub(crate) trusted_ranges: Vec<IpNet>,
+}
+
 /// Represents a single route between an SNI and a backend.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Route {
+    /// Name of the route, used in metrics. Defaults to its first domain.
+    name: Option<String>,
     /// List of valid domains for this route (regexp).
-    #[serde(deserialize_with = "deserialize_regex")]
+    #[serde(
+        deserialize_with = "deserialize_regex",
+        serialize_with = "serialize_regex"
+    )]
     domains: RegexSet,
-    /// Backend to proxy the connection to when the route is used.
-    pub(crate) backend: Option<Backend>,
-    /// Backend to use if the request is an ALPN challenge.
-    pub(crate) alpn_challenge_backend: Option<Backend>,
+    /// Backend to proxy the connection to when the route is used. This is a
+    /// shorthand for a single entry in `backends` and is moved there when the
+    /// configuration is parsed.
+    #[serde(skip_serializing)]
+    backend: Option<Backend>,
+    /// Backends to proxy the connection to when the route is used. A backend
+    /// is selected for each new connection using the `load_balancing` policy.
+    #[serde(default)]
+    pub(crate) backends: Vec<Backend>,
+    /// Policy used to select a backend out of `backends`.
+    #[serde(default)]
+    pub(crate) load_balancing: LoadBalancing,
+    /// Active health checks for all the route backends, ALPN and ECH ones
+    /// included. Unhealthy backends are not selected for new connections.
+    pub(crate) health_check: Option<HealthCheck>,
+    /// Number of additional attempts to connect to a backend when the first
+    /// one fails, using the backup backends in order. All the addresses of a
+    /// backend are tried in a single attempt (Happy Eyeballs). Defaults to 0
+    /// (no retry).
+    #[serde(default)]
+    pub(crate) retries: u32,
+    /// Connection timeouts overriding the global ones, see `Timeouts`.
+    #[serde(default)]
+    pub(crate) timeouts: Timeouts,
+    /// Maximum number of connections proxied at the same time for this route.
+    /// Unlimited if not set.
+    pub(crate) max_connections: Option<usize>,
+    /// Behavior when a connection limit is reached, overriding the global
+    /// one. See `ConnectionLimit`.
+    pub(crate) connection_limit: Option<ConnectionLimit>,
+    /// Enforces `max_connections`.
+    #[serde(skip)]
+    pub(crate) limiter: Option<Arc<Limiter>>,
+    /// Per-client connection limits for this route, enforced in addition to
+    /// the global ones once the route is known. See `ClientLimits`.
+    pub(crate) client_limits: Option<ClientLimits>,
+    /// Enforces `client_limits`.
+    #[serde(skip)]
+    pub(crate) client_limiter: Option<Arc<ClientLimiter>>,
+    /// Counter used by the round-robin based load balancing policies.
+    #[serde(skip)]
+    next_backend: AtomicUsize,
+    /// Backends to use instead of `backends` depending on the ALPN protocols
+    /// offered by the client, see `AlpnBackend`.
+    #[serde(default)]
+    pub(crate) alpn_backends: Vec<AlpnBackend>,
+    /// Backend to use if the request is an ALPN challenge. This is a
+    /// shorthand for a first entry in `alpn_backends` matching `acme-tls/1`
+    /// and is moved there when the configuration is parsed.
+    #[serde(skip_serializing)]
+    alpn_challenge_backend: Option<Backend>,
     /// Bypass ACLs for ALPN challenges, if an ALPN challenge backend is used.
+    #[serde(default, skip_serializing)]
+    alpn_challenge_bypass_acl: bool,
+    /// Backend to use for Encrypted Client Hello connections, instead of
+    /// `backends` and `alpn_backends`. The SNI of those connections is the
+    /// public name of the ECH configuration, and only a backend holding the
+    /// ECH keys can handle them.
+    pub(crate) ech_backend: Option<Backend>,
+    /// Refuse Encrypted Client Hello connections.
     #[serde(default)]
-    pub(crate) alpn_challenge_bypass_acl: bool,
+    pub(crate) deny_ech: bool,
+    /// Identifiers of the ECH configurations using the route domains as their
+    /// public name. Other ECH extensions are GREASE (sent by clients having no
+    /// ECH configuration) and the connections are handled as non-ECH ones.
+    #[serde(default)]
+    pub(crate) ech_config_ids: Vec<u8>,
     /// Allow and deny ACLs, containing a list of IP ranges to allow or deny for
     /// this route. If 'allow' is used, all non-matching addresses are denied.
     /// A 'deny' rule wins over an 'allow' one and the most specific subnet
@@ -170,6 +579,18 @@ pub(crate) struct Route {
     /// one.
     #[serde(default)]
     allowed_ranges: Vec<IpNet>,
+    /// TLS client fingerprints, either JA3 or JA4 ones, to deny on this route.
+    /// Denied fingerprints win over allowed ones.
+    #[serde(default)]
+    denied_fingerprints: Vec<String>,
+    /// TLS client fingerprints, either JA3 or JA4 ones, to allow on this
+    /// route. When used, all non-matching clients are denied. Fingerprint and
+    /// IP ranges ACLs must both allow a client.
+    #[serde(default)]
+    allowed_fingerprints: Vec<String>,
+    /// Minimum TLS version clients must offer, either in the supported_versions
+    /// extension or as the legacy version. Any version is accepted if not set.
+    pub(crate) min_tls_version: Option<TlsVersion>,
     /// Redirect HTTP requests comming to `bind_http` and `bind_https` to their
     /// HTTPS counterparts (using the request Host header).
     #[serde(default = "default_true")]
@@ -177,6 +598,102 @@ pub(crate) struct Route {
 }
 
 impl Route {
+    /// Returns the name of the route.
+    pub(crate) fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_default()
+    }
+
+    /// Selects a healthy backend out of `backends` using the route load
+    /// balancing policy. Backup backends are only used, in order, when no
+    /// other backend is healthy. Backends below their connection limit are
+    /// preferred. Returns None if the route has no healthy backend.
+    pub(crate) fn select_backend(&self) -> Option<&Backend> {
+        let mut backends: Vec<&Backend> = self
+            .backends
+            .iter()
+            .filter(|b| !b.backup && b.is_available())
+            .collect();
+        if backends.iter().any(|b| b.has_capacity()) {
+            backends.retain(|b| b.has_capacity());
+        }
+        if backends.is_empty() {
+            return self
+                .backup_backends()
+                .find(|b| b.has_capacity())
+                .or_else(|| self.backup_backends().next());
+        } else if backends.len() == 1 {
+            return backends.first().copied();
+        }
+
+        Some(match self.load_balancing {
+            LoadBalancing::RoundRobin => {
+                backends[self.next_backend.fetch_add(1, Ordering::Relaxed) % backends.len()]
+            }
+            LoadBalancing::Random => backends[fastrand::usize(..backends.len())],
+            LoadBalancing::LeastConnections => backends.iter().min_by(|a, b| {
+                // Compare active / weight ratios, without dividing.
+                (a.active_connections() * b.weight as usize)
+                    .cmp(&(b.active_connections() * a.weight as usize))
+            })?,
+            LoadBalancing::Weighted => {
+                let total: usize = backends.iter().map(|b| b.weight as usize).sum();
+                let mut n = self.next_backend.fetch_add(1, Ordering::Relaxed) % total;
+                backends.iter().copied().find(|b| {
+                    if n < b.weight as usize {
+                        return true;
+                    }
+                    n -= b.weight as usize;
+                    false
+                })?
+            }
+        })
+    }
+
+    /// Returns the first available ALPN backend matching one of the protocols
+    /// offered in `hello`, if any. `acme-tls/1` only matches tls-alpn-01
+    /// challenges, where it is the single protocol offered, so clients can't
+    /// add it to their list to get to the challenge backend (and bypass the
+    /// ACLs).
+    pub(crate) fn alpn_backend(&self, hello: &ClientHello) -> Option<&AlpnBackend> {
+        self.alpn_backends.iter().find(|b| {
+            b.backend.is_available()
+                && b.protocols.iter().any(|p| match p.as_str() {
+                    ACME_TLS_ALPN => hello.is_challenge(),
+                    _ => hello.alpn_protocols.iter().any(|a| a == p.as_bytes()),
+                })
+        })
+    }
+
+    /// Does `hello` use Encrypted Client Hello with one of the route ECH
+    /// configurations.
+    pub(crate) fn uses_ech(&self, hello: &ClientHello) -> bool {
+        hello
+            .ech
+            .is_some_and(|ech| self.ech_config_ids.contains(&ech.config_id))
+    }
+
+    /// Returns the timeouts to use for connections to `backend`: its own,
+    /// then the route ones and finally `defaults` (the global ones).
+    pub(crate) fn timeouts(&self, backend: &Backend, defaults: &Timeouts) -> Timeouts {
+        backend.timeouts.or(&self.timeouts).or(defaults)
+    }
+
+    /// Returns all the route backends: the regular ones, then the ALPN and
+    /// ECH ones.
+    pub(crate) fn all_backends(&self) -> impl Iterator<Item = &Backend> {
+        self.backends
+            .iter()
+            .chain(self.alpn_backends.iter().map(|b| &b.backend))
+            .chain(self.ech_backend.iter())
+    }
+
+    /// Returns the available backup backends, in order.
+    pub(crate) fn backup_backends(&self) -> impl Iterator<Item = &Backend> {
+        self.backends
+            .iter()
+            .filter(|b| b.backup && b.is_available())
+    }
+
     /// Checks if a client IP address is allowed by the route ACLs.
     pub(crate) fn is_allowed(&self, addr: &SocketAddr) -> bool {
         if self.denied_ranges.is_empty() && self.allowed_ranges.is_empty() {
@@ -215,6 +732,23 @@ impl Route {
         true
     }
 
+    /// Checks if a client TLS fingerprint is allowed by the route ACLs.
+    pub(crate) fn is_fingerprint_allowed(&self, fingerprint: &Fingerprint) -> bool {
+        if self
+            .denied_fingerprints
+            .iter()
+            .any(|f| fingerprint.matches(f))
+        {
+            return false;
+        }
+
+        self.allowed_fingerprints.is_empty()
+            || self
+                .allowed_fingerprints
+                .iter()
+                .any(|f| fingerprint.matches(f))
+    }
+
     /// Check if a subnet contains an IP address, including IPv4-mapped IPv6
     /// addresses in IPv4 subnets.
     fn contains(net: &IpNet, ip: &IpAddr) -> bool {
@@ -228,22 +762,359 @@ impl Route {
     }
 }
 
+/// Load balancing policies, used to select a backend for new connections when
+/// a route has more than one.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LoadBalancing {
+    /// Backends are used one after the other.
+    #[default]
+    RoundRobin,
+    /// Backends are selected randomly.
+    Random,
+    /// The backend with the least active connections, relative to its
+    /// weight, is used.
+    LeastConnections,
+    /// Backends are used one after the other, each one being used `weight`
+    /// times in a row.
+    Weighted,
+}
+
+/// Active health checks parameters.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct HealthCheck {
+    /// Type of health check to perform.
+    #[serde(default)]
+    pub(crate) r#type: HealthCheckType,
+    /// SNI hostname to use for TLS health checks.
+    pub(crate) sni: Option<String>,
+    /// Time in seconds between two checks. Defaults to 5s.
+    #[serde(default = "default_health_check_interval")]
+    pub(crate) interval: u64,
+    /// Time in seconds after which a check is considered failed. Defaults to
+    /// 2s.
+    #[serde(default = "default_health_check_timeout")]
+    pub(crate) timeout: u64,
+    /// Number of consecutive successful checks for an unhealthy backend to be
+    /// considered healthy again. Defaults to 2.
+    #[serde(default = "default_health_check_rise")]
+    pub(crate) rise: u32,
+    /// Number of consecutive failed checks for a healthy backend to be
+    /// considered unhealthy. Defaults to 3.
+    #[serde(default = "default_health_check_fall")]
+    pub(crate) fall: u32,
+    /// Send an HAProxy protocol header to backends using it, telling them the
+    /// connection was initiated by the proxy itself (v2 'LOCAL' command, v1
+    /// 'UNKNOWN' protocol).
+    #[serde(default)]
+    pub(crate) proxy_protocol: bool,
+}
+
+/// Health check types.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HealthCheckType {
+    /// A TCP connection can be established.
+    #[default]
+    Tcp,
+    /// A TLS handshake can be completed.
+    Tls,
+}
+
+/// Connection timeouts and TCP keepalive parameters, in seconds. They can be
+/// set globally and overridden per route and per backend, unset values being
+/// inherited.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Timeouts {
+    /// Maximum time to receive the client handshake (TLS ClientHello or HTTP
+    /// request), including the HAProxy protocol header if any. Can only be set
+    /// globally. Defaults to 3s.
+    pub(crate) client_hello: Option<u64>,
+    /// Maximum time for a single connection attempt to a backend, and for the
+    /// handshake to be replayed to it. Defaults to 3s.
+    pub(crate) connect: Option<u64>,
+    /// Proxied connections are closed when no data was moved in either
+    /// direction for this long. Defaults to 0 (disabled).
+    pub(crate) idle: Option<u64>,
+    /// Proxied connections are closed after this long. Defaults to 0
+    /// (unlimited).
+    pub(crate) max_lifetime: Option<u64>,
+    /// Time a connection must be idle before TCP keepalive probes are sent. 0
+    /// disables keepalive. Defaults to 60s.
+    pub(crate) keepalive_time: Option<u64>,
+    /// Time between two TCP keepalive probes. Defaults to 60s.
+    pub(crate) keepalive_interval: Option<u64>,
+    /// Number of unanswered TCP keepalive probes before the connection is
+    /// closed. Defaults to the system setting.
+    pub(crate) keepalive_retries: Option<u32>,
+}
+
+impl Timeouts {
+    /// Checks the timeouts values are valid.
+    fn check(&self) -> Result<()> {
+        if self.client_hello == Some(0) || self.connect == Some(0) {
+            bail!("client_hello and connect timeouts cannot be null");
+        }
+        if self.keepalive_interval == Some(0) || self.keepalive_retries == Some(0) {
+            bail!("keepalive_interval and keepalive_retries cannot be null");
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of the timeouts, unset values being taken from `other`.
+    fn or(&self, other: &Timeouts) -> Timeouts {
+        Timeouts {
+            client_hello: self.client_hello.or(other.client_hello),
+            connect: self.connect.or(other.connect),
+            idle: self.idle.or(other.idle),
+            max_lifetime: self.max_lifetime.or(other.max_lifetime),
+            keepalive_time: self.keepalive_time.or(other.keepalive_time),
+            keepalive_interval: self.keepalive_interval.or(other.keepalive_interval),
+            keepalive_retries: self.keepalive_retries.or(other.keepalive_retries),
+        }
+    }
+
+    /// Maximum time to receive the client handshake.
+    pub(crate) fn client_hello(&self) -> Duration {
+        Duration::from_secs(self.client_hello.unwrap_or(3))
+    }
+
+    /// Maximum time for a single connection attempt to a backend.
+    pub(crate) fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect.unwrap_or(3))
+    }
+
+    /// Idle timeout of proxied connections, if any.
+    pub(crate) fn idle(&self) -> Option<Duration> {
+        self.idle.filter(|t| *t > 0).map(Duration::from_secs)
+    }
+
+    /// Maximum lifetime of proxied connections, if any.
+    pub(crate) fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime
+            .filter(|t| *t > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// TCP keepalive parameters, if keepalive is enabled.
+    pub(crate) fn keepalive(&self) -> Option<socket2::TcpKeepalive> {
+        let time = self.keepalive_time.unwrap_or(60);
+        if time == 0 {
+            return None;
+        }
+
+        let keepalive = socket2::TcpKeepalive::new()
+            .with_time(Duration::from_secs(time))
+            .with_interval(Duration::from_secs(self.keepalive_interval.unwrap_or(60)));
+        Some(match self.keepalive_retries {
+            Some(retries) => keepalive.with_retries(retries),
+            None => keepalive,
+        })
+    }
+}
+
+/// Backend used for clients offering specific ALPN protocols.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AlpnBackend {
+    /// ALPN protocols to match. The backend is used if the client offers at
+    /// least one of them.
+    pub(crate) protocols: Vec<String>,
+    /// Backend to proxy the connection to.
+    pub(crate) backend: Backend,
+    /// Bypass the route ACLs for connections to this backend.
+    #[serde(default)]
+    pub(crate) bypass_acl: bool,
+}
+
+/// HAProxy PROXY protocol v2 TLVs which can be sent to backends.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProxyProtocolTlv {
+    /// Hostname the connection was routed on (PP2_TYPE_AUTHORITY).
+    Authority,
+    /// Most preferred protocol offered by the client in its ALPN extension
+    /// (PP2_TYPE_ALPN).
+    Alpn,
+    /// Connection identifier (PP2_TYPE_UNIQUE_ID).
+    UniqueId,
+    /// Checksum of the header (PP2_TYPE_CRC32C).
+    Crc32c,
+}
+
 /// Represents a backend (host and its specific options).
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Backend {
     /// Backend address in the <addr>:<port> form; <addr> can be either an IP
     /// address or an hostname.
     pub(crate) address: String,
     /// HAProxy PROXY protocol. Disable: None, v1: Some(1), v2: Some(2).
     pub(crate) proxy_protocol: Option<u8>,
+    /// TLVs to append to HAProxy PROXY protocol v2 headers.
+    #[serde(default)]
+    pub(crate) proxy_protocol_tlvs: Vec<ProxyProtocolTlv>,
+    /// Weight of the backend, used by some load balancing policies. Defaults
+    /// to 1.
+    #[serde(default = "default_weight")]
+    pub(crate) weight: u32,
+    /// Backup backends are only used when no other backend is healthy, or
+    /// when connecting to the selected backend failed and retries are
+    /// allowed.
+    #[serde(default)]
+    pub(crate) backup: bool,
+    /// Connection timeouts overriding the route and global ones, see
+    /// `Timeouts`.
+    #[serde(default)]
+    pub(crate) timeouts: Timeouts,
+    /// Maximum number of connections proxied at the same time to this
+    /// backend. Unlimited if not set.
+    pub(crate) max_connections: Option<usize>,
+    /// Enforces `max_connections`.
+    #[serde(skip)]
+    pub(crate) limiter: Option<Arc<Limiter>>,
+    /// Number of connections currently proxied to this backend. Shared with
+    /// the same backend in previous configurations, as their connections are
+    /// still running.
+    #[serde(skip)]
+    active: Arc<AtomicUsize>,
+    /// Is the backend considered healthy. Backends are healthy until health
+    /// checks report otherwise.
+    #[serde(skip, default = "default_true_atomic")]
+    healthy: AtomicBool,
+    /// Administrative state of the backend, see `BackendState`.
+    #[serde(skip)]
+    state: AtomicU8,
+}
+
+/// Administrative state of a backend, set using the admin interface.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BackendState {
+    /// The backend is used normally.
+    Enabled,
+    /// The backend is not selected for new connections, established ones
+    /// keep running.
+    Draining,
+    /// The backend is not selected for new connections and established ones
+    /// are closed.
+    Disabled,
+}
+
+/// Keeps track of a connection to a backend, for as long as it lives.
+pub(crate) struct ActiveConnection<'a>(&'a Backend);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for BackendState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BackendState::Enabled => "enabled",
+            BackendState::Draining => "draining",
+            BackendState::Disabled => "disabled",
+        })
+    }
 }
 
 impl Backend {
-    pub(crate) fn to_socket_addr(&self) -> Result<SocketAddr> {
-        self.address
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| anyhow!("Could not convert {} to an IP:port pair", self.address))
+    /// Accounts for a new connection to the backend. The connection is
+    /// accounted for until the returned value is dropped.
+    pub(crate) fn connection(&self) -> ActiveConnection<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self)
+    }
+
+    /// Number of connections currently proxied to this backend.
+    pub(crate) fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Is the backend considered healthy.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Set the backend health status.
+    pub(crate) fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed)
+    }
+
+    /// Returns the backend administrative state.
+    pub(crate) fn state(&self) -> BackendState {
+        match self.state.load(Ordering::Relaxed) {
+            0 => BackendState::Enabled,
+            1 => BackendState::Draining,
+            _ => BackendState::Disabled,
+        }
+    }
+
+    /// Set the backend administrative state.
+    pub(crate) fn set_state(&self, state: BackendState) {
+        self.state.store(state as u8, Ordering::Relaxed)
+    }
+
+    /// Can the backend be selected for new connections: it must be healthy
+    /// and enabled.
+    pub(crate) fn is_available(&self) -> bool {
+        self.is_healthy() && self.state() == BackendState::Enabled
+    }
+
+    /// Is the backend below its connection limit, if any.
+    pub(crate) fn has_capacity(&self) -> bool {
+        self.limiter.as_ref().is_none_or(|l| l.has_capacity())
+    }
+
+    /// Resolves the backend address using `resolver` and returns all the
+    /// IP:port pairs it resolves to.
+    pub(crate) async fn to_socket_addrs(&self, resolver: &Resolver) -> Result<Vec<SocketAddr>> {
+        resolver.resolve(&self.address).await
+    }
+}
+
+/// Builds the limiter enforcing a `max_connections` setting, if set.
+fn limiter(max: Option<usize>) -> Result<Option<Arc<Limiter>>> {
+    match max {
+        None => Ok(None),
+        Some(max) if max == 0 || max > Semaphore::MAX_PERMITS => {
+            bail!("Invalid max_connections value ({max})")
+        }
+        Some(max) => Ok(Some(Limiter::new(max))),
+    }
+}
+
+/// Reuses the limiter of the configuration being replaced, if it enforces the
+/// same maximum.
+fn inherit_limiter(limiter: &mut Option<Arc<Limiter>>, other: &Option<Arc<Limiter>>) {
+    if let (Some(l), Some(o)) = (limiter.as_ref(), other) {
+        if l.max() == o.max() {
+            *limiter = Some(Arc::clone(o));
+        }
+    }
+}
+
+/// Builds the limiter enforcing a `client_limits` setting, if set.
+fn client_limiter(limits: &Option<ClientLimits>) -> Result<Option<Arc<ClientLimiter>>> {
+    match limits {
+        None => Ok(None),
+        Some(limits) => {
+            limits.check()?;
+            Ok(Some(ClientLimiter::new(limits.clone())))
+        }
+    }
+}
+
+/// Reuses the client limiter of the configuration being replaced, if it
+/// enforces the same limits.
+fn inherit_client_limiter(
+    limiter: &mut Option<Arc<ClientLimiter>>,
+    other: &Option<Arc<ClientLimiter>>,
+) {
+    if let (Some(l), Some(o)) = (limiter.as_ref(), other) {
+        if l.limits == o.limits {
+            *limiter = Some(Arc::clone(o));
+        }
     }
 }
 
@@ -283,6 +1154,20 @@ where
     deserializer.deserialize_seq(RegexVisitor)
 }
 
+/// Serialize a `RegexSet` back to the sequence of domains it was built from.
+fn serialize_regex<S: Serializer>(set: &RegexSet, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(set.patterns().iter().map(|p| pattern_to_domain(p)))
+}
+
+/// Converts a domain regex pattern back to its configuration form.
+fn pattern_to_domain(pattern: &str) -> String {
+    pattern
+        .trim_start_matches('^')
+        .trim_end_matches('$')
+        .replace(".*", "*")
+        .replace(r"\.", ".")
+}
+
 // Default values.
 fn default_bind_https() -> SocketAddr {
     "[::]:443".parse().unwrap()
@@ -290,19 +1175,92 @@ fn default_bind_https() -> SocketAddr {
 fn default_bind_http() -> SocketAddr {
     "[::]:80".parse().unwrap()
 }
+fn default_weight() -> u32 {
+    1
+}
+fn default_health_check_interval() -> u64 {
+    5
+}
+fn default_health_check_timeout() -> u64 {
+    2
+}
+fn default_health_check_rise() -> u32 {
+    2
+}
+fn default_health_check_fall() -> u32 {
+    3
+}
+fn default_max_client_hello_size() -> usize {
+    64 * 1024
+}
+fn default_drain_timeout() -> u64 {
+    30
+}
 fn default_true() -> bool {
     true
 }
+fn default_true_atomic() -> AtomicBool {
+    AtomicBool::new(true)
+}
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::{
+        access_log,
+        limits::{self, LimitAction},
+        tls::{AlertDescription, Ech},
+    };
 
     #[test]
     fn invalid_configs() {
         // At least one route should be defined.
         assert!(Config::from_str("").is_err());
 
+        // Inbound HAProxy protocol without trusted ranges.
+        assert!(Config::from_str(
+            "
+accept_proxy_protocol:
+  https: true
+  trusted_ranges: []
+routes:
+  - domains:
+      - example.net
+    backend:
+      address: 127.0.0.1:443
+        "
+        )
+        .is_err());
+
+        // PROXY protocol TLVs require v2.
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    backend:
+      address: 127.0.0.1:443
+      proxy_protocol: 1
+      proxy_protocol_tlvs:
+        - authority
+        "
+        )
+        .is_err());
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    backend:
+      address: 127.0.0.1:443
+      proxy_protocol: 2
+      proxy_protocol_tlvs:
+        - foo
+        "
+        )
+        .is_err());
+
         // Config with a backend but no domain.
         assert!(Config::from_str(
             "
@@ -364,37 +1322,82 @@ routes:
         "
         )
         .is_err());
-    }
 
-    #[test]
-    fn simple_config() {
-        let cfg = Config::from_str(
+        // Config with both a backend and a list of backends.
+        assert!(Config::from_str(
             "
 routes:
   - domains:
       - example.net
     backend:
       address: 127.0.0.1:443
-        ",
+    backends:
+      - address: 127.0.0.2:443
+        "
         )
-        .unwrap();
-        assert_eq!(cfg.bind_https, "[::]:443".parse().unwrap());
-        assert_eq!(cfg.bind_http, "[::]:80".parse().unwrap());
-        assert_eq!(cfg.need_http(), true);
-
-        // Invalid routes.
-        assert!(cfg.get_route("").is_none());
-        assert!(cfg.get_route("foo.example.net").is_none());
-        assert!(cfg.get_route(".*example.*").is_none());
+        .is_err());
 
-        // The one valid route.
-        let route = cfg.get_route("example.net").unwrap();
+        // Config with a backend having a null weight.
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    backends:
+      - address: 127.0.0.1:443
+        weight: 0
+        "
+        )
+        .is_err());
+
+        // Config with an unknown load balancing policy.
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    load_balancing: foo
+    backends:
+      - address: 127.0.0.1:443
+        "
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn simple_config() {
+        let cfg = Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    backend:
+      address: 127.0.0.1:443
+        ",
+        )
+        .unwrap();
+        assert_eq!(cfg.bind_https, "[::]:443".parse().unwrap());
+        assert_eq!(cfg.bind_http, "[::]:80".parse().unwrap());
+        assert_eq!(cfg.drain_timeout, 30);
+        assert_eq!(cfg.max_client_hello_size, 65536);
+        assert!(cfg.bind_metrics.is_none());
+        assert!(cfg.access_log.is_none());
+        assert_eq!(cfg.need_http(), true);
+        assert!(!cfg.accepts_proxy_protocol(Listener::Https, &"10.0.0.1:1337".parse().unwrap()));
+
+        // Invalid routes.
+        assert!(cfg.get_route("").is_none());
+        assert!(cfg.get_route("foo.example.net").is_none());
+        assert!(cfg.get_route(".*example.*").is_none());
+
+        // The one valid route.
+        let route = cfg.get_route("example.net").unwrap();
 
-        assert_eq!(route.backend.as_ref().unwrap().address, "127.0.0.1:443");
-        assert!(route.backend.as_ref().unwrap().proxy_protocol.is_none());
+        assert_eq!(route.backends[0].address, "127.0.0.1:443");
+        assert!(route.backends[0].proxy_protocol.is_none());
+        assert!(route.backends[0].proxy_protocol_tlvs.is_empty());
 
-        assert!(route.alpn_challenge_backend.is_none());
-        assert_eq!(route.alpn_challenge_bypass_acl, false);
+        assert!(route.alpn_backends.is_empty());
 
         assert_eq!(route.is_allowed(&"10.0.42.1:12345".parse().unwrap()), true);
         assert_eq!(route.is_allowed(&"[1111::1]:12345".parse().unwrap()), true);
@@ -442,12 +1445,28 @@ routes:
             "
 bind_https: \"[2222::42]:8433\"
 bind_http: 127.0.0.1:8080
+bind_metrics: 127.0.0.1:9090
+drain_timeout: 120
+max_client_hello_size: 32768
+access_log:
+  format: json
+  path: /var/log/sniproxy/access.log
+accept_proxy_protocol:
+  https: true
+  trusted_ranges:
+    - 10.0.0.0/8
+    - 1111::/16
 routes:
   - domains:
       - example.net
     backend:
       address: 127.0.0.1:443
       proxy_protocol: 2
+      proxy_protocol_tlvs:
+        - authority
+        - alpn
+        - unique_id
+        - crc32c
     alpn_challenge_bypass_acl: true
     alpn_challenge_backend:
       address: 10.0.42.1:443
@@ -459,7 +1478,8 @@ routes:
     allowed_ranges:
       - 10.0.10.128/29
       - 10.0.2.42/32
-  - domains:
+  - name: foo
+    domains:
       - \"*.foo.example.com\"
       - foo.example.net
     http_redirect: false
@@ -472,8 +1492,28 @@ routes:
         .unwrap();
         assert_eq!(cfg.bind_https, "[2222::42]:8433".parse().unwrap());
         assert_eq!(cfg.bind_http, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(cfg.drain_timeout, 120);
+        assert_eq!(cfg.max_client_hello_size, 32768);
+        assert_eq!(cfg.bind_metrics, Some("127.0.0.1:9090".parse().unwrap()));
+        let access_log = cfg.access_log.as_ref().unwrap();
+        assert_eq!(access_log.format, access_log::Format::Json);
+        assert_eq!(
+            access_log.path.as_deref(),
+            Some(std::path::Path::new("/var/log/sniproxy/access.log"))
+        );
         assert_eq!(cfg.need_http(), true);
 
+        // Inbound HAProxy protocol.
+        let peer = "10.0.42.1:1337".parse().unwrap();
+        assert!(cfg.accepts_proxy_protocol(Listener::Https, &peer));
+        assert!(!cfg.accepts_proxy_protocol(Listener::Http, &peer));
+        let peer = "[::ffff:10.0.42.1]:1337".parse().unwrap();
+        assert!(cfg.accepts_proxy_protocol(Listener::Https, &peer));
+        let peer = "[1111::42]:1337".parse().unwrap();
+        assert!(cfg.accepts_proxy_protocol(Listener::Https, &peer));
+        let peer = "192.168.0.1:1337".parse().unwrap();
+        assert!(!cfg.accepts_proxy_protocol(Listener::Https, &peer));
+
         // Invalid routes.
         assert!(cfg.get_route("").is_none());
         assert!(cfg.get_route("foo.example.com").is_none());
@@ -482,13 +1522,24 @@ routes:
 
         // Test first route.
         let route = cfg.get_route("example.net").unwrap();
-        assert_eq!(route.backend.as_ref().unwrap().address, "127.0.0.1:443");
-        assert_eq!(route.backend.as_ref().unwrap().proxy_protocol, Some(2));
+        assert_eq!(route.name(), "example.net");
+        assert_eq!(route.backends[0].address, "127.0.0.1:443");
+        assert_eq!(route.backends[0].proxy_protocol, Some(2));
+        assert_eq!(
+            route.backends[0].proxy_protocol_tlvs,
+            vec![
+                ProxyProtocolTlv::Authority,
+                ProxyProtocolTlv::Alpn,
+                ProxyProtocolTlv::UniqueId,
+                ProxyProtocolTlv::Crc32c
+            ]
+        );
 
-        let alpn_backend = route.alpn_challenge_backend.as_ref().unwrap();
-        assert_eq!(alpn_backend.address, "10.0.42.1:443");
-        assert_eq!(alpn_backend.proxy_protocol, Some(1));
-        assert_eq!(route.alpn_challenge_bypass_acl, true);
+        let alpn_backend = &route.alpn_backends[0];
+        assert_eq!(alpn_backend.protocols, vec!["acme-tls/1"]);
+        assert_eq!(alpn_backend.backend.address, "10.0.42.1:443");
+        assert_eq!(alpn_backend.backend.proxy_protocol, Some(1));
+        assert!(alpn_backend.bypass_acl);
 
         // First route ACLs.
         assert_eq!(
@@ -526,14 +1577,11 @@ routes:
 
         // Test the second route.
         let route = cfg.get_route("a.b.c.d.foo.example.com").unwrap();
-        assert_eq!(
-            route.backend.as_ref().unwrap().address,
-            "[1234::42:1]:10443"
-        );
-        assert!(route.backend.as_ref().unwrap().proxy_protocol.is_none());
+        assert_eq!(route.name(), "foo");
+        assert_eq!(route.backends[0].address, "[1234::42:1]:10443");
+        assert!(route.backends[0].proxy_protocol.is_none());
         assert_eq!(route.http_redirect, false);
-        assert!(route.alpn_challenge_backend.is_none());
-        assert_eq!(route.alpn_challenge_bypass_acl, false);
+        assert!(route.alpn_backends.is_empty());
 
         // Second route ACLs.
         assert_eq!(
@@ -570,9 +1618,816 @@ routes:
         .unwrap();
 
         let route = cfg.get_route("first.example.net").unwrap();
-        assert_eq!(route.backend.as_ref().unwrap().address, "127.0.0.1:443");
+        assert_eq!(route.backends[0].address, "127.0.0.1:443");
+        assert_eq!(cfg.routes[1].name(), "*.example.net");
 
         let route = cfg.get_route("other.example.net").unwrap();
-        assert_eq!(route.backend.as_ref().unwrap().address, "127.0.0.2:443");
+        assert_eq!(route.backends[0].address, "127.0.0.2:443");
+    }
+
+    #[test]
+    fn load_balancing() {
+        let input = "
+routes:
+  - domains:
+      - rr.example.net
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+        weight: 3
+      - address: 127.0.0.3:443
+  - domains:
+      - random.example.net
+    load_balancing: random
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+  - domains:
+      - least.example.net
+    load_balancing: least_connections
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+        weight: 2
+  - domains:
+      - weighted.example.net
+    load_balancing: weighted
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+        weight: 3
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        let select = |route: &Route| route.select_backend().unwrap().address.clone();
+
+        // Round robin, weights are not used.
+        let route = cfg.get_route("rr.example.net").unwrap();
+        assert_eq!(route.load_balancing, LoadBalancing::RoundRobin);
+        assert_eq!(route.backends[0].weight, 1);
+        assert_eq!(route.backends[1].weight, 3);
+        for _ in 0..2 {
+            assert_eq!(select(route), "127.0.0.1:443");
+            assert_eq!(select(route), "127.0.0.2:443");
+            assert_eq!(select(route), "127.0.0.3:443");
+        }
+
+        // Random, we can only check the backends are valid ones.
+        let route = cfg.get_route("random.example.net").unwrap();
+        for _ in 0..16 {
+            assert!(["127.0.0.1:443", "127.0.0.2:443"].contains(&select(route).as_str()));
+        }
+
+        // Least connections, relative to the backend weights.
+        let route = cfg.get_route("least.example.net").unwrap();
+        let _c0 = route.select_backend().unwrap().connection();
+        assert_eq!(route.backends[0].active_connections(), 1);
+        assert_eq!(select(route), "127.0.0.2:443");
+        let _c1 = route.select_backend().unwrap().connection();
+        assert_eq!(select(route), "127.0.0.2:443");
+        let _c2 = route.select_backend().unwrap().connection();
+        assert_eq!(route.backends[1].active_connections(), 2);
+        assert_eq!(select(route), "127.0.0.1:443");
+        drop(_c0);
+        assert_eq!(route.backends[0].active_connections(), 0);
+        assert_eq!(select(route), "127.0.0.1:443");
+
+        // Active connections are still accounted for after a reload.
+        let _c0 = route.select_backend().unwrap().connection();
+        let mut new = Config::from_str(input).unwrap();
+        new.inherit_state(&cfg);
+        let new_route = new.get_route("least.example.net").unwrap();
+        let active: Vec<_> = new_route
+            .backends
+            .iter()
+            .map(|b| b.active_connections())
+            .collect();
+        assert_eq!(active, vec![1, 2]);
+        let _c3 = route.backends[0].connection();
+        assert_eq!(select(new_route), "127.0.0.2:443");
+        drop((_c0, _c3));
+        assert_eq!(new_route.backends[0].active_connections(), 0);
+        drop((_c1, _c2));
+        assert_eq!(new_route.backends[1].active_connections(), 0);
+
+        // Weighted.
+        let route = cfg.get_route("weighted.example.net").unwrap();
+        for _ in 0..2 {
+            assert_eq!(select(route), "127.0.0.1:443");
+            assert_eq!(select(route), "127.0.0.2:443");
+            assert_eq!(select(route), "127.0.0.2:443");
+            assert_eq!(select(route), "127.0.0.2:443");
+        }
+    }
+
+    #[test]
+    fn backup_backends() {
+        let cfg = Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    retries: 2
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+        backup: true
+      - address: 127.0.0.3:443
+        backup: true
+        ",
+        )
+        .unwrap();
+        let route = cfg.get_route("example.net").unwrap();
+        assert_eq!(route.retries, 2);
+        assert!(!route.backends[0].backup);
+        assert!(route.backends[1].backup);
+
+        // Backups are not used while other backends are healthy.
+        for _ in 0..4 {
+            assert_eq!(route.select_backend().unwrap().address, "127.0.0.1:443");
+        }
+        let backups: Vec<&str> = route
+            .backup_backends()
+            .map(|b| b.address.as_str())
+            .collect();
+        assert_eq!(backups, vec!["127.0.0.2:443", "127.0.0.3:443"]);
+
+        // Otherwise the first healthy backup is used.
+        route.backends[0].set_healthy(false);
+        assert_eq!(route.select_backend().unwrap().address, "127.0.0.2:443");
+        route.backends[1].set_healthy(false);
+        assert_eq!(route.select_backend().unwrap().address, "127.0.0.3:443");
+        assert_eq!(route.backup_backends().count(), 1);
+        route.backends[2].set_healthy(false);
+        assert!(route.select_backend().is_none());
+    }
+
+    #[test]
+    fn backend_state() {
+        let input = "
+routes:
+  - domains:
+      - example.net
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+      - address: 127.0.0.3:443
+        backup: true
+    alpn_backends:
+      - protocols:
+          - h2
+        backend:
+          address: 127.0.0.4:443
+    ech_backend:
+      address: 127.0.0.5:443
+    ech_config_ids:
+      - 42
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        let route = cfg.get_route("example.net").unwrap();
+        assert_eq!(route.all_backends().count(), 5);
+        assert!(route
+            .all_backends()
+            .all(|b| b.state() == BackendState::Enabled));
+
+        // Draining and disabled backends are not selected.
+        route.backends[0].set_state(BackendState::Draining);
+        for _ in 0..4 {
+            assert_eq!(route.select_backend().unwrap().address, "127.0.0.2:443");
+        }
+        route.backends[1].set_state(BackendState::Disabled);
+        assert_eq!(route.select_backend().unwrap().address, "127.0.0.3:443");
+        route.backends[2].set_state(BackendState::Draining);
+        assert!(route.select_backend().is_none());
+
+        // The state is kept across reloads, for all kinds of backends.
+        route.alpn_backends[0]
+            .backend
+            .set_state(BackendState::Disabled);
+        route
+            .ech_backend
+            .as_ref()
+            .unwrap()
+            .set_state(BackendState::Draining);
+        let mut new = Config::from_str(input).unwrap();
+        new.inherit_state(&cfg);
+        let states: Vec<_> = new.routes[0].all_backends().map(|b| b.state()).collect();
+        assert_eq!(
+            states,
+            vec![
+                BackendState::Draining,
+                BackendState::Disabled,
+                BackendState::Draining,
+                BackendState::Disabled,
+                BackendState::Draining
+            ]
+        );
+    }
+
+    #[test]
+    fn backend_health() {
+        let input = "
+routes:
+  - domains:
+      - example.net
+    health_check:
+      interval: 5
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+    alpn_challenge_backend:
+      address: 127.0.0.4:443
+    ech_backend:
+      address: 127.0.0.5:443
+    ech_config_ids:
+      - 42
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        cfg.routes[0].backends[0].set_healthy(false);
+        cfg.routes[0].alpn_backends[0].backend.set_healthy(false);
+
+        // The health status is kept across reloads, for all kinds of backends.
+        let mut new = Config::from_str(&input.replace("127.0.0.2", "127.0.0.3")).unwrap();
+        new.inherit_state(&cfg);
+        let healthy: Vec<_> = new.routes[0]
+            .all_backends()
+            .map(|b| b.is_healthy())
+            .collect();
+        assert_eq!(healthy, vec![false, true, false, true]);
+
+        // Unless the health checks changed.
+        let mut new = Config::from_str(&input.replace("interval: 5", "interval: 10")).unwrap();
+        new.inherit_state(&cfg);
+        assert!(new.routes[0].all_backends().all(|b| b.is_healthy()));
+    }
+
+    #[test]
+    fn bind_admin() {
+        let cfg = |bind: &str| {
+            Config::from_str(&format!(
+                "
+bind_admin: {bind}
+routes:
+  - domains:
+      - example.net
+    backend:
+      address: 127.0.0.1:443
+                "
+            ))
+        };
+
+        assert_eq!(
+            cfg("127.0.0.1:9443").unwrap().bind_admin,
+            Some(AdminBind::Tcp("127.0.0.1:9443".parse().unwrap()))
+        );
+        assert_eq!(
+            cfg("/run/sniproxy.sock").unwrap().bind_admin,
+            Some(AdminBind::Unix(PathBuf::from("/run/sniproxy.sock")))
+        );
+        assert!(cfg("0.0.0.0:9443").is_err());
+        assert!(cfg("localhost:9443").is_err());
+        assert!(cfg("sniproxy.sock").is_err());
+    }
+
+    #[test]
+    fn timeouts() {
+        let cfg = Config::from_str(
+            "
+timeouts:
+  client_hello: 5
+  idle: 600
+  keepalive_retries: 4
+routes:
+  - domains:
+      - example.net
+    timeouts:
+      connect: 1
+      max_lifetime: 3600
+    backends:
+      - address: 127.0.0.1:443
+      - address: 127.0.0.2:443
+        timeouts:
+          idle: 0
+          keepalive_time: 0
+  - domains:
+      - example.com
+    backend:
+      address: 127.0.0.1:443
+        ",
+        )
+        .unwrap();
+        assert_eq!(cfg.timeouts.client_hello(), Duration::from_secs(5));
+
+        // Route overrides.
+        let route = &cfg.routes[0];
+        let timeouts = route.timeouts(&route.backends[0], &cfg.timeouts);
+        assert_eq!(timeouts.connect(), Duration::from_secs(1));
+        assert_eq!(timeouts.idle(), Some(Duration::from_secs(600)));
+        assert_eq!(timeouts.max_lifetime(), Some(Duration::from_secs(3600)));
+        assert!(timeouts.keepalive().is_some());
+        assert_eq!(timeouts.keepalive_retries, Some(4));
+
+        // Backend overrides.
+        let timeouts = route.timeouts(&route.backends[1], &cfg.timeouts);
+        assert_eq!(timeouts.connect(), Duration::from_secs(1));
+        assert_eq!(timeouts.idle(), None);
+        assert!(timeouts.keepalive().is_none());
+
+        // Defaults.
+        let route = &cfg.routes[1];
+        let timeouts = route.timeouts(&route.backends[0], &Timeouts::default());
+        assert_eq!(timeouts.client_hello(), Duration::from_secs(3));
+        assert_eq!(timeouts.connect(), Duration::from_secs(3));
+        assert_eq!(timeouts.idle(), None);
+        assert_eq!(timeouts.max_lifetime(), None);
+        assert!(timeouts.keepalive().is_some());
+
+        // Invalid timeouts.
+        let cfg = |timeouts: &str| {
+            Config::from_str(&format!(
+                "
+routes:
+  - domains:
+      - example.net
+    timeouts: {{ {timeouts} }}
+    backend:
+      address: 127.0.0.1:443
+                "
+            ))
+        };
+        assert!(cfg("idle: 10").is_ok());
+        assert!(cfg("client_hello: 10").is_err());
+        assert!(cfg("connect: 0").is_err());
+        assert!(cfg("keepalive_interval: 0").is_err());
+    }
+
+    #[tokio::test]
+    async fn connection_limits() {
+        let input = "
+max_connections: 100
+connection_limit:
+  action: queue
+routes:
+  - domains:
+      - example.net
+    max_connections: 20
+    connection_limit:
+      alert: handshake_failure
+    backends:
+      - address: 127.0.0.1:443
+        max_connections: 1
+      - address: 127.0.0.2:443
+        max_connections: 1
+      - address: 127.0.0.3:443
+        backup: true
+  - domains:
+      - example.com
+    backend:
+      address: 127.0.0.1:443
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        assert_eq!(cfg.limiter.as_ref().unwrap().max(), 100);
+        assert!(cfg.routes[1].limiter.is_none());
+        assert!(cfg.routes[1].backends[0].limiter.is_none());
+
+        // Routes override the global behavior as a whole.
+        let limit = cfg.connection_limit(&cfg.routes[0]);
+        assert_eq!(limit.action, LimitAction::Reject);
+        assert_eq!(limit.alert, AlertDescription::HandshakeFailure);
+        let limit = cfg.connection_limit(&cfg.routes[1]);
+        assert_eq!(limit.action, LimitAction::Queue);
+        assert_eq!(limit.alert, AlertDescription::InternalError);
+        assert_eq!(limit.queue_timeout, 5);
+
+        // Backends below their limit are preferred.
+        let route = &cfg.routes[0];
+        let permits = limits::acquire(&cfg, route, &route.backends[0])
+            .await
+            .unwrap();
+        assert!(!route.backends[0].has_capacity());
+        for _ in 0..4 {
+            assert_eq!(route.select_backend().unwrap().address, "127.0.0.2:443");
+        }
+        // Even if all of them reached it.
+        let _permits = limits::acquire(&cfg, route, &route.backends[1])
+            .await
+            .unwrap();
+        assert!(route.select_backend().is_some());
+
+        // Limiters are kept across reloads, unless their maximum changed.
+        let mut new =
+            Config::from_str(&input.replace("max_connections: 20", "max_connections: 5")).unwrap();
+        new.inherit_state(&cfg);
+        assert!(Arc::ptr_eq(
+            new.limiter.as_ref().unwrap(),
+            cfg.limiter.as_ref().unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            new.routes[0].limiter.as_ref().unwrap(),
+            cfg.routes[0].limiter.as_ref().unwrap()
+        ));
+        assert!(!new.routes[0].backends[0].has_capacity());
+        drop(permits);
+        assert!(new.routes[0].backends[0].has_capacity());
+
+        // Invalid limits.
+        let cfg = |limits: &str| {
+            Config::from_str(&format!(
+                "
+routes:
+  - domains:
+      - example.net
+    {limits}
+    backend:
+      address: 127.0.0.1:443
+                "
+            ))
+        };
+        assert!(cfg("max_connections: 1").is_ok());
+        assert!(cfg("max_connections: 0").is_err());
+        assert!(cfg("max_connections: -1").is_err());
+        assert!(cfg("connection_limit: { action: drop }").is_err());
+        assert!(cfg("connection_limit: { alert: bad_certificate }").is_err());
+    }
+
+    #[test]
+    fn client_limits() {
+        let input = "
+client_limits:
+  rate: 50
+  exempt_ranges:
+    - 10.0.0.0/8
+routes:
+  - domains:
+      - example.net
+    client_limits:
+      max_connections: 10
+      ipv6_prefix: 48
+    backend:
+      address: 127.0.0.1:443
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        let limits = &cfg.client_limiter.as_ref().unwrap().limits;
+        assert_eq!(limits.rate, Some(50.0));
+        assert_eq!(limits.ipv4_prefix, 32);
+        assert_eq!(limits.ipv6_prefix, 64);
+        let limits = &cfg.routes[0].client_limiter.as_ref().unwrap().limits;
+        assert_eq!(limits.max_connections, Some(10));
+        assert_eq!(limits.ipv6_prefix, 48);
+
+        // Client limiters are kept across reloads, unless their limits changed.
+        let mut new = Config::from_str(&input.replace("rate: 50", "rate: 20")).unwrap();
+        new.inherit_state(&cfg);
+        assert!(!Arc::ptr_eq(
+            new.client_limiter.as_ref().unwrap(),
+            cfg.client_limiter.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            new.routes[0].client_limiter.as_ref().unwrap(),
+            cfg.routes[0].client_limiter.as_ref().unwrap()
+        ));
+
+        assert!(Config::from_str(&input.replace("rate: 50", "rate: 0")).is_err());
+        assert!(Config::from_str(&input.replace("ipv6_prefix: 48", "ipv6_prefix: 256")).is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        let input = "
+routes:
+  - domains:
+      - deny.example.net
+    denied_fingerprints:
+      - 6A75A26F76B4B28B58AC04B868CEBDD7
+    backend:
+      address: 127.0.0.1:443
+  - domains:
+      - allow.example.net
+    allowed_fingerprints:
+      - t13d3611a1_018971650b2c_24611a1bfa3f
+      - t13d1516h2_8daaf6152771_e5627efa2ab1
+    denied_fingerprints:
+      - t13d1516h2_8daaf6152771_e5627efa2ab1
+    backend:
+      address: 127.0.0.1:443
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        let peer = "10.0.0.1:12345".parse().unwrap();
+        let known = Fingerprint {
+            ja3: "6a75a26f76b4b28b58ac04b868cebdd7".to_string(),
+            ja4: "t13d3611a1_018971650b2c_24611a1bfa3f".to_string(),
+        };
+        let other = Fingerprint {
+            ja3: "773906b0efdefa24a7f2b8eb6985bf37".to_string(),
+            ja4: "t13d1516h2_8daaf6152771_e5627efa2ab1".to_string(),
+        };
+
+        // Fingerprints are matched in their lowercase form, either on JA3 or
+        // JA4, denied ones winning.
+        let deny = &cfg.routes[0];
+        assert!(!deny.is_fingerprint_allowed(&known));
+        assert!(deny.is_fingerprint_allowed(&other));
+        let allow = &cfg.routes[1];
+        assert!(allow.is_fingerprint_allowed(&known));
+        assert!(!allow.is_fingerprint_allowed(&other));
+        assert!(!allow.is_fingerprint_allowed(&Fingerprint::default()));
+
+        let hello = ClientHello::default();
+        assert!(cfg
+            .get_backend("deny.example.net", &peer, &hello, &other)
+            .is_ok());
+        let err = cfg
+            .get_backend("deny.example.net", &peer, &hello, &known)
+            .unwrap_err();
+        assert!(matches!(err.downcast(), Ok(Error::AccessDenied)));
+
+        assert!(Config::from_str(&input.replace("24611a1bfa3f", "24611a1bfa3")).is_err());
+    }
+
+    #[test]
+    fn min_tls_version() {
+        let input = "
+routes:
+  - domains:
+      - example.net
+    min_tls_version: 1.2
+    backend:
+      address: 127.0.0.1:443
+  - domains:
+      - example.com
+    backend:
+      address: 127.0.0.1:443
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        assert_eq!(cfg.routes[0].min_tls_version, Some(TlsVersion::Tls12));
+        assert!(cfg.routes[1].min_tls_version.is_none());
+
+        let peer = "10.0.0.1:12345".parse().unwrap();
+        let backend = |hostname: &str, legacy_version: u16, supported_versions: &[u16]| {
+            let hello = ClientHello {
+                legacy_version,
+                supported_versions: supported_versions.to_vec(),
+                ..Default::default()
+            };
+            cfg.get_backend(hostname, &peer, &hello, &Fingerprint::default())
+        };
+
+        // The highest version offered is used.
+        assert!(backend("example.net", 0x303, &[]).is_ok());
+        assert!(backend("example.net", 0x303, &[0x304]).is_ok());
+        assert!(backend("example.net", 0x301, &[0x303, 0x302]).is_ok());
+        let err = backend("example.net", 0x302, &[]).unwrap_err();
+        assert!(matches!(err.downcast(), Ok(Error::ProtocolVersion(0x302))));
+        let err = backend("example.net", 0x303, &[0x302, 0x301]).unwrap_err();
+        assert!(matches!(err.downcast(), Ok(Error::ProtocolVersion(0x302))));
+
+        // Routes accept any version by default.
+        assert!(backend("example.com", 0x301, &[]).is_ok());
+
+        assert!(Config::from_str(&input.replace("1.2", "2.0")).is_err());
+    }
+
+    #[test]
+    fn ech() {
+        let input = "
+routes:
+  - domains:
+      - public.example.net
+    ech_backend:
+      address: 127.0.0.1:443
+    ech_config_ids:
+      - 42
+    alpn_backends:
+      - protocols:
+          - h2
+        backend:
+          address: 127.0.0.2:443
+    backend:
+      address: 127.0.0.3:443
+  - domains:
+      - example.com
+    deny_ech: true
+    ech_config_ids:
+      - 42
+      - 43
+    backend:
+      address: 127.0.0.4:443
+        ";
+        let cfg = Config::from_str(input).unwrap();
+
+        let peer = "10.0.0.1:12345".parse().unwrap();
+        let backend = |hostname: &str, ech: Option<u8>| {
+            let hello = ClientHello {
+                alpn_protocols: vec![b"h2".to_vec()],
+                ech: ech.map(|config_id| Ech {
+                    config_id,
+                    kdf_id: 1,
+                    aead_id: 1,
+                }),
+                ..Default::default()
+            };
+            cfg.get_backend(hostname, &peer, &hello, &Fingerprint::default())
+                .map(|(_, b)| b.address.clone())
+        };
+
+        // ECH connections use the ECH backend, before ALPN ones.
+        assert_eq!(
+            backend("public.example.net", Some(42)).unwrap(),
+            "127.0.0.1:443"
+        );
+        assert_eq!(
+            backend("public.example.net", None).unwrap(),
+            "127.0.0.2:443"
+        );
+
+        // Unless the ECH backend is not available, ECH connections can't be
+        // handled by the other backends.
+        cfg.routes[0]
+            .ech_backend
+            .as_ref()
+            .unwrap()
+            .set_state(BackendState::Draining);
+        let err = backend("public.example.net", Some(42)).unwrap_err();
+        assert!(matches!(err.downcast(), Ok(Error::NoHealthyBackend)));
+        assert_eq!(
+            backend("public.example.net", None).unwrap(),
+            "127.0.0.2:443"
+        );
+
+        // Or are refused.
+        let err = backend("example.com", Some(43)).unwrap_err();
+        assert!(matches!(err.downcast(), Ok(Error::EchDenied)));
+        assert_eq!(backend("example.com", None).unwrap(), "127.0.0.4:443");
+
+        // ECH extensions using other config ids are GREASE, the connections
+        // are handled as non-ECH ones.
+        assert_eq!(
+            backend("public.example.net", Some(0xc3)).unwrap(),
+            "127.0.0.2:443"
+        );
+        assert_eq!(backend("example.com", Some(0xc3)).unwrap(), "127.0.0.4:443");
+
+        // A route can only have an ECH backend, but not deny ECH at the same
+        // time.
+        let cfg = Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    ech_backend:
+      address: 127.0.0.1:443
+    ech_config_ids:
+      - 42
+        ",
+        )
+        .unwrap();
+        let err = cfg
+            .get_backend(
+                "example.net",
+                &peer,
+                &ClientHello::default(),
+                &Fingerprint::default(),
+            )
+            .unwrap_err();
+        assert!(matches!(err.downcast(), Ok(Error::NoBackend)));
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    deny_ech: true
+    ech_backend:
+      address: 127.0.0.1:443
+    ech_config_ids:
+      - 42
+        "
+        )
+        .is_err());
+
+        // ECH config ids are needed to tell ECH connections from GREASE.
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    ech_backend:
+      address: 127.0.0.1:443
+        "
+        )
+        .is_err());
+    }
+    #[test]
+    fn alpn_backends() {
+        let input = "
+routes:
+  - domains:
+      - example.net
+    denied_ranges:
+      - 0.0.0.0/0
+    alpn_challenge_backend:
+      address: 127.0.0.1:443
+    alpn_challenge_bypass_acl: true
+    alpn_backends:
+      - protocols:
+          - h2
+        backend:
+          address: 127.0.0.2:443
+      - protocols:
+          - imap
+          - pop3
+        backend:
+          address: 127.0.0.3:443
+        bypass_acl: true
+    backend:
+      address: 127.0.0.4:443
+        ";
+        let cfg = Config::from_str(input).unwrap();
+        let route = &cfg.routes[0];
+        let protocols: Vec<&Vec<String>> =
+            route.alpn_backends.iter().map(|b| &b.protocols).collect();
+        assert_eq!(
+            protocols,
+            vec![&vec!["acme-tls/1"], &vec!["h2"], &vec!["imap", "pop3"]]
+        );
+
+        let peer = "10.0.0.1:12345".parse().unwrap();
+        let backend = |alpn: &[&str]| {
+            let hello = ClientHello {
+                alpn_protocols: alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+                ..Default::default()
+            };
+            cfg.get_backend("example.net", &peer, &hello, &Fingerprint::default())
+                .map(|(_, b)| b.address.clone())
+        };
+
+        // The first entry matching one of the offered protocols is used, its
+        // ACL bypass setting applying.
+        assert_eq!(backend(&["acme-tls/1"]).unwrap(), "127.0.0.1:443");
+        assert_eq!(backend(&["foo", "pop3"]).unwrap(), "127.0.0.3:443");
+        assert!(backend(&["h2", "http/1.1"]).is_err());
+
+        // acme-tls/1 only matches tls-alpn-01 challenges, offering it along
+        // other protocols does not bypass the ACLs.
+        assert!(backend(&["http/1.1", "acme-tls/1"]).is_err());
+        assert!(backend(&["acme-tls/1", "h2"]).is_err());
+
+        // Unavailable ALPN backends are skipped.
+        route.alpn_backends[2].backend.set_healthy(false);
+        assert!(backend(&["foo", "pop3"]).is_err());
+        route.alpn_backends[2].backend.set_healthy(true);
+        route.alpn_backends[2]
+            .backend
+            .set_state(BackendState::Disabled);
+        assert!(backend(&["foo", "pop3"]).is_err());
+        route.alpn_backends[2]
+            .backend
+            .set_state(BackendState::Enabled);
+
+        // Otherwise the route backends are used.
+        assert!(backend(&[]).is_err());
+        let cfg = Config::from_str(&input.replace("- 0.0.0.0/0", "- 192.0.2.0/24")).unwrap();
+        let hello = ClientHello {
+            alpn_protocols: vec![b"custom".to_vec()],
+            ..Default::default()
+        };
+        let (_, backend) = cfg
+            .get_backend("example.net", &peer, &hello, &Fingerprint::default())
+            .unwrap();
+        assert_eq!(backend.address, "127.0.0.4:443");
+        let hello = ClientHello {
+            alpn_protocols: vec![b"h2".to_vec()],
+            ..Default::default()
+        };
+        cfg.routes[0].alpn_backends[1].backend.set_healthy(false);
+        let (_, backend) = cfg
+            .get_backend("example.net", &peer, &hello, &Fingerprint::default())
+            .unwrap();
+        assert_eq!(backend.address, "127.0.0.4:443");
+
+        // A route can only have ALPN backends, but they must match a protocol.
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    alpn_backends:
+      - protocols:
+          - h2
+        backend:
+          address: 127.0.0.1:443
+        "
+        )
+        .is_ok());
+        assert!(Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    alpn_backends:
+      - protocols: []
+        backend:
+          address: 127.0.0.1:443
+        "
+        )
+        .is_err());
     }
 }
@@ -1,29 +1,122 @@
-use std::{cell::RefCell, future::Future, net::SocketAddr};
+use std::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use tokio::task::futures::TaskLocalFuture;
 
+use crate::{access_log, fingerprint::Fingerprint};
+
 tokio::task_local! {
     pub(crate) static REQ_CONTEXT: RefCell<ReqContext>;
 }
 
+/// Last connection identifier generated, used to keep them monotonic.
+static LAST_ID: Mutex<u128> = Mutex::new(0);
+
+/// Crockford's base32 alphabet, used to display connection identifiers.
+const ID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Connection identifier, unique and sortable by creation time. Follows the
+/// ULID layout: a 48-bit timestamp in milliseconds followed by 80 random bits,
+/// displayed as 26 Crockford's base32 characters.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) struct ConnectionId(u128);
+
+impl ConnectionId {
+    /// Generates a new identifier. Identifiers generated within the same
+    /// millisecond are incremented from the previous one so they still sort
+    /// in creation order.
+    pub(crate) fn new() -> Self {
+        let ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default()
+            & ((1 << 48) - 1);
+
+        let mut last = LAST_ID.lock().unwrap();
+        *last = match *last >> 80 >= ms {
+            true => *last + 1,
+            false => (ms << 80) | (fastrand::u128(..) & ((1 << 80) - 1)),
+        };
+        Self(*last)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 26 characters of 5 bits, the first one only using 3 bits.
+        let mut buf = [0u8; 26];
+        for (i, c) in buf.iter_mut().enumerate() {
+            *c = ID_ALPHABET[((self.0 >> ((25 - i) * 5)) & 0x1f) as usize];
+        }
+        // Cannot fail, all characters come from the ASCII alphabet above.
+        f.write_str(std::str::from_utf8(&buf).unwrap())
+    }
+}
+
+impl FromStr for ConnectionId {
+    type Err = anyhow::Error;
+
+    /// Parses an identifier from its string representation, case
+    /// insensitively.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 26 || s.as_bytes()[0] > b'7' {
+            bail!("Invalid connection identifier: {s}");
+        }
+        let mut id = 0;
+        for c in s.bytes() {
+            match ID_ALPHABET
+                .iter()
+                .position(|a| *a == c.to_ascii_uppercase())
+            {
+                Some(value) => id = (id << 5) | value as u128,
+                None => bail!("Invalid connection identifier: {s}"),
+            }
+        }
+        Ok(Self(id))
+    }
+}
+
 /// Request context, embedding per-request information in tokio tasks.
 pub(crate) struct ReqContext {
+    /// Connection identifier, assigned at accept time.
+    pub(crate) id: ConnectionId,
     /// Local IP & port.
     pub(crate) local: SocketAddr,
     /// Peer IP & port.
     pub(crate) peer: SocketAddr,
     /// Hostname requested. Can be None early in the processing.
     pub(crate) hostname: Option<String>,
+    /// TLS client fingerprints. None until the ClientHello is parsed.
+    pub(crate) fingerprint: Option<Fingerprint>,
+    /// Access log record, filled while the request is processed. Shared with
+    /// the connection registry.
+    pub(crate) access: Arc<Mutex<access_log::Record>>,
 }
 
 impl ReqContext {
     /// Initialize a new request context given local & peer information.
     pub(crate) fn from(local: SocketAddr, peer: SocketAddr) -> RefCell<Self> {
+        let id = ConnectionId::new();
+        let mut access = access_log::Record::default();
+        access.id = id.to_string();
+        access.peer = peer.to_string();
+        access.local = local.to_string();
+
         RefCell::new(Self {
+            id,
             local,
             peer,
             hostname: None,
+            fingerprint: None,
+            access: Arc::new(Mutex::new(access)),
         })
     }
 
@@ -47,6 +140,45 @@ pub(crate) fn set_hostname(hostname: &str) -> Result<()> {
     Ok(())
 }
 
+/// Set the current context TLS client fingerprints. Can fail if not context is
+/// defined.
+pub(crate) fn set_fingerprint(fingerprint: &Fingerprint) -> Result<()> {
+    REQ_CONTEXT.try_with(|context| {
+        let mut context = context.borrow_mut();
+        context.fingerprint = Some(fingerprint.clone());
+
+        let mut access = context.access.lock().unwrap();
+        access.ja3 = Some(fingerprint.ja3.clone());
+        access.ja4 = Some(fingerprint.ja4.clone());
+    })?;
+    Ok(())
+}
+
+/// Set the current context local & peer addresses, eg. when they were provided
+/// by a proxy in front of us. Can fail if not context is defined.
+pub(crate) fn set_addrs(local: SocketAddr, peer: SocketAddr) -> Result<()> {
+    REQ_CONTEXT.try_with(|context| {
+        let mut context = context.borrow_mut();
+        context.local = local;
+        context.peer = peer;
+
+        let mut access = context.access.lock().unwrap();
+        access.local = local.to_string();
+        access.peer = peer.to_string();
+    })?;
+    Ok(())
+}
+
+/// Returns the connection identifier associated with the context.
+pub(crate) fn id() -> Result<ConnectionId> {
+    Ok(REQ_CONTEXT.try_with(|context| context.borrow().id)?)
+}
+
+/// Returns the hostname associated with the context, if any.
+pub(crate) fn hostname() -> Result<Option<String>> {
+    Ok(REQ_CONTEXT.try_with(|context| context.borrow().hostname.clone())?)
+}
+
 /// Returns the local address associated with the context.
 pub(crate) fn local_addr() -> Result<SocketAddr> {
     Ok(REQ_CONTEXT.try_with(|context| -> SocketAddr {
@@ -67,8 +199,8 @@ pub(crate) fn peer_addr() -> Result<SocketAddr> {
 mod tests {
     use super::*;
 
-    #[test]
-    fn context() {
+    #[tokio::test]
+    async fn context() {
         let local = "[1111:1::42]:10443".parse().unwrap();
         let peer = "[1337:42::700]:12345".parse().unwrap();
 
@@ -84,9 +216,74 @@ mod tests {
                 let context = context.borrow();
                 assert_eq!(context.hostname, Some("example.net".to_string()));
             });
-        });
+            assert_eq!(hostname().unwrap(), Some("example.net".to_string()));
+            let fingerprint = Fingerprint {
+                ja3: "6a75a26f76b4b28b58ac04b868cebdd7".to_string(),
+                ja4: "t13d3611a1_018971650b2c_24611a1bfa3f".to_string(),
+            };
+            assert!(set_fingerprint(&fingerprint).is_ok());
+            REQ_CONTEXT.with(|context| {
+                let context = context.borrow();
+                assert_eq!(context.fingerprint, Some(fingerprint.clone()));
+                let access = context.access.lock().unwrap();
+                assert_eq!(access.ja4.as_deref(), Some(fingerprint.ja4.as_str()));
+            });
+            assert!(set_addrs(
+                "172.16.99.1:443".parse().unwrap(),
+                "10.0.42.132:1337".parse().unwrap()
+            )
+            .is_ok());
+            assert_eq!(local_addr().unwrap(), "172.16.99.1:443".parse().unwrap());
+            assert_eq!(peer_addr().unwrap(), "10.0.42.132:1337".parse().unwrap());
+        })
+        .await;
+
+        // Each context gets its own identifier.
+        let first = ReqContext::from(local, peer).borrow().id;
+        assert!(first < ReqContext::from(local, peer).borrow().id);
 
         assert!(REQ_CONTEXT.try_with(|_| {}).is_err());
+        assert!(id().is_err());
         assert!(set_hostname("example.net").is_err());
     }
+
+    #[test]
+    fn connection_id() {
+        assert_eq!(
+            ConnectionId(0x0191_3f1a_2b3c_8a5e_4f7d_0123_4567_89ab).to_string(),
+            "01J4ZHMASWH9F4YZ814D2PF2DB"
+        );
+        assert_eq!(ConnectionId(0).to_string(), "00000000000000000000000000");
+        assert_eq!(ConnectionId(u128::MAX).to_string(), "7ZZZZZZZZZZZZZZZZZZZZZZZZZ");
+
+        // Identifiers can be parsed back.
+        for id in [0, 0x0191_3f1a_2b3c_8a5e_4f7d_0123_4567_89ab, u128::MAX] {
+            let id = ConnectionId(id);
+            assert_eq!(id.to_string().parse::<ConnectionId>().unwrap(), id);
+        }
+        assert_eq!(
+            "01j4zhmaswh9f4yz814d2pf2db"
+                .parse::<ConnectionId>()
+                .unwrap(),
+            ConnectionId(0x0191_3f1a_2b3c_8a5e_4f7d_0123_4567_89ab)
+        );
+        for id in [
+            "",
+            "01J4ZHMASWH9F4YZ814D2PF2D",
+            "01J4ZHMASWH9F4YZ814D2PF2DBB",
+            "81J4ZHMASWH9F4YZ814D2PF2DB",
+            "01J4ZHMASWH9F4YZ814D2PF2DU",
+            "01J4ZHMASWH9F4YZ814D2PF2é",
+        ] {
+            assert!(id.parse::<ConnectionId>().is_err());
+        }
+
+        // Identifiers are monotonic, and their string representation sorts the
+        // same way.
+        let ids: Vec<_> = (0..1000).map(|_| ConnectionId::new()).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert!(ids
+            .windows(2)
+            .all(|w| w[0].to_string() < w[1].to_string()));
+    }
 }
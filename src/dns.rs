@@ -0,0 +1,300 @@
+use std::{fmt, fs, net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    system_conf, TokioAsyncResolver,
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+/// Resolution of backend hostnames. Lookups are cached, including failed ones,
+/// respecting the TTL of the records.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct DnsConfig {
+    /// resolv.conf formatted file to read the nameservers and the resolver
+    /// options from. Defaults to `/etc/resolv.conf`.
+    pub(crate) resolv_conf: Option<PathBuf>,
+    /// Nameservers to use, in the <ip>:<port> form. Replaces the nameservers
+    /// and the resolver options of `resolv_conf`.
+    #[serde(default)]
+    pub(crate) nameservers: Vec<SocketAddr>,
+    /// Address family preference, see `IpPreference`.
+    #[serde(default)]
+    pub(crate) ip_preference: IpPreference,
+    /// Minimum time in seconds failed lookups (non-existent domain or no
+    /// record) are cached for. Defaults to 5s.
+    pub(crate) negative_ttl: Option<u64>,
+    /// Maximum number of records in the cache. Defaults to 1024.
+    pub(crate) cache_size: Option<usize>,
+}
+
+/// Address families to resolve backend hostnames to.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IpPreference {
+    /// Both IPv6 and IPv4 addresses, IPv6 ones first.
+    #[default]
+    Ipv6,
+    /// Both IPv4 and IPv6 addresses, IPv4 ones first.
+    Ipv4,
+    /// Only IPv6 addresses.
+    Ipv6Only,
+    /// Only IPv4 addresses.
+    Ipv4Only,
+}
+
+/// Asynchronous caching resolver, configured using `DnsConfig`.
+#[derive(Default)]
+pub(crate) struct Resolver {
+    pub(crate) config: DnsConfig,
+    /// Inner resolver, built on first use when relying on the system
+    /// configuration, not to require it when no hostname is used.
+    inner: OnceCell<TokioAsyncResolver>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl Resolver {
+    /// Creates a new resolver. Explicitly configured nameservers or
+    /// resolv.conf file are used right away, to report errors early.
+    pub(crate) fn new(config: DnsConfig) -> Result<Self> {
+        let resolver = Self {
+            config,
+            inner: OnceCell::new(),
+        };
+        if resolver.config.resolv_conf.is_some() || !resolver.config.nameservers.is_empty() {
+            resolver.inner()?;
+        }
+        Ok(resolver)
+    }
+
+    /// Resolves an <addr>:<port> address, <addr> being either an IP address
+    /// or a hostname, and returns all the IP:port pairs it resolves to,
+    /// ordered using the address family preference.
+    pub(crate) async fn resolve(&self, address: &str) -> Result<Vec<SocketAddr>> {
+        if let Ok(addr) = address.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>()?),
+            None => bail!("No port found in {address}"),
+        };
+        let lookup = self
+            .inner()?
+            .lookup_ip(host)
+            .await
+            .map_err(|e| anyhow!("Could not resolve {host}: {e}"))?;
+
+        let mut addrs: Vec<SocketAddr> =
+            lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+        if addrs.is_empty() {
+            bail!("Could not convert {address} to an IP:port pair");
+        }
+        // Stable sort, keeping the order of the records within a family.
+        match self.config.ip_preference {
+            IpPreference::Ipv6 => addrs.sort_by_key(|a| a.is_ipv4()),
+            IpPreference::Ipv4 => addrs.sort_by_key(|a| a.is_ipv6()),
+            _ => (),
+        }
+        Ok(addrs)
+    }
+
+    /// Returns the inner resolver, building it if needed.
+    fn inner(&self) -> Result<&TokioAsyncResolver> {
+        self.inner.get_or_try_init(|| {
+            let (config, mut opts) = match (&self.config.resolv_conf, &self.config.nameservers) {
+                (_, nameservers) if !nameservers.is_empty() => {
+                    let mut group = NameServerConfigGroup::new();
+                    for ns in nameservers {
+                        group.merge(NameServerConfigGroup::from_ips_clear(
+                            &[ns.ip()],
+                            ns.port(),
+                            true,
+                        ));
+                    }
+                    (
+                        ResolverConfig::from_parts(None, Vec::new(), group),
+                        ResolverOpts::default(),
+                    )
+                }
+                (Some(path), _) => {
+                    let data = fs::read(path).map_err(|e| {
+                        anyhow!("Could not read resolv.conf file '{}': {e}", path.display())
+                    })?;
+                    system_conf::parse_resolv_conf(data)?
+                }
+                (None, _) => system_conf::read_system_conf()
+                    .map_err(|e| anyhow!("Could not read the system DNS configuration: {e}"))?,
+            };
+
+            opts.ip_strategy = match self.config.ip_preference {
+                IpPreference::Ipv6 | IpPreference::Ipv4 => LookupIpStrategy::Ipv4AndIpv6,
+                IpPreference::Ipv6Only => LookupIpStrategy::Ipv6Only,
+                IpPreference::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            };
+            opts.negative_min_ttl =
+                Some(Duration::from_secs(self.config.negative_ttl.unwrap_or(5)));
+            opts.cache_size = self.config.cache_size.unwrap_or(1024);
+
+            Ok(TokioAsyncResolver::tokio(config, opts))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use hickory_resolver::proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{
+            rdata::{A, AAAA, SOA},
+            Name, RData, Record, RecordType,
+        },
+    };
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// Starts a stub DNS server answering for the `test.` zone and returns its
+    /// address, along with the number of queries it received.
+    async fn stub_server() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        let count = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                count.fetch_add(1, Ordering::Relaxed);
+
+                let query = request.queries()[0].clone();
+                let name = query.name().clone();
+                let rdata = match (name.to_ascii().as_str(), query.query_type()) {
+                    ("backend.test.", RecordType::A) => {
+                        Some(RData::A(A(Ipv4Addr::new(192, 0, 2, 1))))
+                    }
+                    ("backend.test.", RecordType::AAAA) => Some(RData::AAAA(AAAA(Ipv6Addr::new(
+                        0x2001, 0xdb8, 0, 0, 0, 0, 0, 1,
+                    )))),
+                    ("v4.test.", RecordType::A) => Some(RData::A(A(Ipv4Addr::new(192, 0, 2, 2)))),
+                    _ => None,
+                };
+
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_query(query);
+                match rdata {
+                    Some(rdata) => {
+                        response.add_answer(Record::from_rdata(name, 60, rdata));
+                    }
+                    None => {
+                        if !name.to_ascii().starts_with("v4.") {
+                            response.set_response_code(ResponseCode::NXDomain);
+                        }
+                        let zone = Name::from_ascii("test.").unwrap();
+                        let soa = SOA::new(zone.clone(), zone.clone(), 1, 60, 60, 60, 60);
+                        response.add_name_server(Record::from_rdata(zone, 60, RData::SOA(soa)));
+                    }
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (addr, queries)
+    }
+
+    fn resolver(nameserver: SocketAddr, ip_preference: IpPreference) -> Resolver {
+        Resolver::new(DnsConfig {
+            nameservers: vec![nameserver],
+            ip_preference,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let (nameserver, queries) = stub_server().await;
+        let count = || queries.load(Ordering::Relaxed);
+
+        // IP addresses are not resolved.
+        let resolver = resolver(nameserver, IpPreference::Ipv6);
+        assert_eq!(
+            resolver.resolve("192.0.2.42:443").await.unwrap(),
+            vec!["192.0.2.42:443".parse().unwrap()]
+        );
+        assert_eq!(count(), 0);
+
+        // All addresses are returned, the preferred family first, and lookups
+        // are cached.
+        let expected: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:443".parse().unwrap(),
+            "192.0.2.1:443".parse().unwrap(),
+        ];
+        assert_eq!(
+            resolver.resolve("backend.test:443").await.unwrap(),
+            expected
+        );
+        let queried = count();
+        assert_eq!(queried, 2);
+        assert_eq!(
+            resolver.resolve("backend.test:443").await.unwrap(),
+            expected
+        );
+        assert_eq!(count(), queried);
+
+        // Failed lookups are cached too.
+        assert!(resolver.resolve("missing.test:443").await.is_err());
+        let queried = count();
+        assert!(resolver.resolve("missing.test:443").await.is_err());
+        assert_eq!(count(), queried);
+
+        // A single family is enough.
+        assert_eq!(
+            resolver.resolve("v4.test:443").await.unwrap(),
+            vec!["192.0.2.2:443".parse().unwrap()]
+        );
+
+        // Address family preference.
+        let resolver = super::tests::resolver(nameserver, IpPreference::Ipv4);
+        let addrs = resolver.resolve("backend.test:443").await.unwrap();
+        assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6());
+        let resolver = super::tests::resolver(nameserver, IpPreference::Ipv6Only);
+        assert_eq!(
+            resolver.resolve("backend.test:443").await.unwrap(),
+            vec!["[2001:db8::1]:443".parse().unwrap()]
+        );
+        assert!(resolver.resolve("v4.test:443").await.is_err());
+
+        // Invalid configuration.
+        assert!(Resolver::new(DnsConfig {
+            resolv_conf: Some(PathBuf::from("/nonexistent/resolv.conf")),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt::Write as _;
+
+use anyhow::{bail, Result};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+use crate::tls::{self, ClientHello};
+
+/// TLS client fingerprints, computed from the ClientHello. They identify the
+/// TLS stack of a client (browser, library, bot, ...) rather than the client
+/// itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Fingerprint {
+    /// JA3 fingerprint, a MD5 hash.
+    /// https://github.com/salesforce/ja3
+    pub(crate) ja3: String,
+    /// JA4 fingerprint, in its hashed form.
+    /// https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md
+    pub(crate) ja4: String,
+}
+
+impl Fingerprint {
+    pub(crate) fn from(hello: &ClientHello) -> Self {
+        Self {
+            ja3: ja3(hello),
+            ja4: ja4(hello),
+        }
+    }
+
+    /// Does `fingerprint`, either a JA3 or a JA4 one, match ours. Configured
+    /// fingerprints are lowercased while the JA4 ALPN characters are kept
+    /// as sent, so fingerprints are compared case-insensitively.
+    pub(crate) fn matches(&self, fingerprint: &str) -> bool {
+        self.ja3.eq_ignore_ascii_case(fingerprint) || self.ja4.eq_ignore_ascii_case(fingerprint)
+    }
+}
+
+/// Checks `fingerprint` looks like a JA3 or a JA4 fingerprint and returns its
+/// canonical, lowercase, form.
+pub(crate) fn parse(fingerprint: &str) -> Result<String> {
+    let fingerprint = fingerprint.to_ascii_lowercase();
+    let hex = |s: &str, len: usize| s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit());
+
+    let valid = match fingerprint.split('_').collect::<Vec<_>>()[..] {
+        [ja3] => hex(ja3, 32),
+        [a, b, c] => {
+            a.len() == 10
+                && a.chars().all(|c| c.is_ascii_alphanumeric())
+                && hex(b, 12)
+                && hex(c, 12)
+        }
+        _ => false,
+    };
+    if !valid {
+        bail!("Invalid JA3 or JA4 fingerprint ({fingerprint})");
+    }
+    Ok(fingerprint)
+}
+
+/// Joins `values` using `sep`, formatting them with `f`.
+fn join<T: Copy>(values: &[T], sep: char, f: impl Fn(T) -> String) -> String {
+    let mut out = String::new();
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            out.push(sep);
+        }
+        out.push_str(&f(*value));
+    }
+    out
+}
+
+/// Removes the GREASE values from `values`.
+fn no_grease(values: &[u16]) -> Vec<u16> {
+    values
+        .iter()
+        .copied()
+        .filter(|v| !tls::is_grease(*v))
+        .collect()
+}
+
+/// Computes the JA3 fingerprint: the MD5 hash of the version, cipher suites,
+/// extensions, supported groups and EC point formats, GREASE values excluded.
+fn ja3(hello: &ClientHello) -> String {
+    let decimal = |v: u16| v.to_string();
+    let input = format!(
+        "{},{},{},{},{}",
+        hello.legacy_version,
+        join(&no_grease(&hello.cipher_suites), '-', decimal),
+        join(&no_grease(&hello.extensions), '-', decimal),
+        join(&no_grease(&hello.supported_groups), '-', decimal),
+        join(&hello.ec_point_formats, '-', |v| v.to_string()),
+    );
+    to_hex(&Md5::digest(input))
+}
+
+/// Computes the JA4 fingerprint, in the `a_b_c` form:
+/// - a: protocol, highest version offered, SNI presence, number of cipher
+///   suites and extensions, and the first ALPN protocol.
+/// - b: truncated SHA256 hash of the sorted cipher suites.
+/// - c: truncated SHA256 hash of the sorted extensions, SNI and ALPN
+///   excluded, followed by the signature algorithms in their original order.
+///
+/// GREASE values are excluded everywhere.
+fn ja4(hello: &ClientHello) -> String {
+    let ciphers = no_grease(&hello.cipher_suites);
+    let extensions = no_grease(&hello.extensions);
+
+    let version = match hello.max_version() {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0200 => "s2",
+        0x0100 => "s1",
+        _ => "00",
+    };
+    let sni = match extensions.contains(&tls::EXT_SERVER_NAME) {
+        true => 'd',
+        false => 'i',
+    };
+    let alpn = match hello.alpn_protocols.first().map(Vec::as_slice) {
+        Some(&[first, .., last]) | Some(&[first @ last]) => {
+            match first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                true => format!("{}{}", first as char, last as char),
+                // Use the first and last characters of the hex representation
+                // instead.
+                false => format!("{:x}{:x}", first >> 4, last & 0xf),
+            }
+        }
+        _ => "00".to_string(),
+    };
+    let a = format!(
+        "t{version}{sni}{:02}{:02}{alpn}",
+        ciphers.len().min(99),
+        extensions.len().min(99)
+    );
+
+    let hex = |v: u16| format!("{v:04x}");
+    let mut sorted = ciphers;
+    sorted.sort_unstable();
+    let b = join(&sorted, ',', hex);
+
+    let mut sorted: Vec<u16> = extensions
+        .into_iter()
+        .filter(|e| *e != tls::EXT_SERVER_NAME && *e != tls::EXT_ALPN)
+        .collect();
+    sorted.sort_unstable();
+    let mut c = join(&sorted, ',', hex);
+    let algorithms = no_grease(&hello.signature_algorithms);
+    if !algorithms.is_empty() {
+        c.push('_');
+        c.push_str(&join(&algorithms, ',', hex));
+    }
+
+    format!("{a}_{}_{}", ja4_hash(&b), ja4_hash(&c))
+}
+
+/// Truncated SHA256 hash used in JA4 fingerprints, all zeros for no data.
+fn ja4_hash(input: &str) -> String {
+    match input.is_empty() {
+        true => "0".repeat(12),
+        false => to_hex(&Sha256::digest(input)[..6]),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02x}");
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reader::ReaderBuf, tls::tests::RECORD_SNI_ALPN};
+
+    #[tokio::test]
+    async fn fingerprint() {
+        let tls = tls::Tls::from(&mut ReaderBuf::from_bytes(RECORD_SNI_ALPN), 64 * 1024)
+            .await
+            .unwrap();
+        let fingerprint = Fingerprint::from(tls.client_hello());
+        assert_eq!(fingerprint.ja3, "6a75a26f76b4b28b58ac04b868cebdd7");
+        assert_eq!(fingerprint.ja4, "t13d3611a1_018971650b2c_24611a1bfa3f");
+        assert!(fingerprint.matches(&fingerprint.ja3.clone()));
+        assert!(fingerprint.matches(&fingerprint.ja4.clone()));
+        assert!(!fingerprint.matches("t13d1516h2_8daaf6152771_e5627efa2ab1"));
+    }
+
+    #[test]
+    fn ja4() {
+        // Values from the JA4 specification.
+        assert_eq!(
+            ja4_hash("002f,0035,009c,009d,1301,1302,1303,c013,c014,c02b,c02c,c02f,c030,cca8,cca9"),
+            "8daaf6152771"
+        );
+        assert_eq!(
+            ja4_hash(
+                "0005,000a,000b,000d,0012,0015,0017,001b,0023,002b,002d,0033,4469,ff01_0403,0804,\
+                 0401,0503,0805,0501,0806,0601"
+            ),
+            "e5627efa2ab1"
+        );
+        assert_eq!(ja4_hash(""), "000000000000");
+
+        // GREASE values are ignored, the highest supported version is used and
+        // non alphanumeric ALPN values are hex encoded.
+        let hello = ClientHello {
+            legacy_version: 0x303,
+            supported_versions: vec![0x3a3a, 0x304, 0x303],
+            cipher_suites: vec![0x0a0a, 0x1301],
+            extensions: vec![0xfafa, 16],
+            alpn_protocols: vec!["é".as_bytes().to_vec()],
+            ..Default::default()
+        };
+        assert!(super::ja4(&hello).starts_with("t13i0101c9_"));
+        assert!(super::ja4(&hello).ends_with("_000000000000"));
+
+        // ALPN characters are kept as sent, but still match the lowercase
+        // configured fingerprints.
+        let hello = ClientHello {
+            alpn_protocols: vec![b"HTTP/2".to_vec()],
+            ..hello
+        };
+        let fingerprint = Fingerprint::from(&hello);
+        assert!(fingerprint.ja4.starts_with("t13i0101H2_"));
+        let configured = super::parse(&fingerprint.ja4).unwrap();
+        assert!(configured.starts_with("t13i0101h2_"));
+        assert!(fingerprint.matches(&configured));
+    }
+
+    #[test]
+    fn parse() {
+        for fingerprint in [
+            "773906B0EFDEFA24A7F2B8EB6985BF37",
+            "t13d1516h2_8daaf6152771_e5627efa2ab1",
+        ] {
+            assert_eq!(
+                super::parse(fingerprint).unwrap(),
+                fingerprint.to_ascii_lowercase()
+            );
+        }
+        for fingerprint in [
+            "",
+            "773906b0efdefa24a7f2b8eb6985bf3",
+            "773906b0efdefa24a7f2b8eb6985bf3z",
+            "t13d1516h2_8daaf6152771",
+            "t13d1516h2_8daaf6152771_e5627efa2ab",
+            "t13d1516h_8daaf6152771_e5627efa2ab1",
+        ] {
+            assert!(super::parse(fingerprint).is_err());
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+use crate::{
+    config::{Backend, Config, HealthCheck, HealthCheckType},
+    proxy_protocol,
+    reload::SharedConfig,
+};
+
+/// Starts the health checks of the backends, ALPN and ECH ones included, of
+/// all routes defining them, in the current configuration. Checks run as long
+/// as the configuration is the active one.
+pub(crate) fn start(shared: &SharedConfig) {
+    let config = shared.load_full();
+
+    for (r, route) in config.routes.iter().enumerate() {
+        if route.health_check.is_none() {
+            continue;
+        }
+
+        for b in 0..route.all_backends().count() {
+            tokio::spawn(run(Arc::clone(shared), Arc::downgrade(&config), r, b));
+        }
+    }
+}
+
+/// Periodically checks a single backend, identified by its route index in
+/// `config` and its index in the route backends (see `Route::all_backends`),
+/// and updates its health status.
+async fn run(shared: SharedConfig, config: Weak<Config>, route: usize, backend: usize) {
+    let mut status = Status::default();
+
+    loop {
+        let interval = {
+            // Stop checking once the configuration was replaced.
+            let config = match config.upgrade() {
+                Some(config) if Arc::ptr_eq(&shared.load(), &config) => config,
+                _ => return,
+            };
+            let route = &config.routes[route];
+            let (backend, check) = match (route.all_backends().nth(backend), &route.health_check) {
+                (Some(backend), Some(check)) => (backend, check),
+                _ => return,
+            };
+
+            let result = self::check(&config, backend, check).await;
+            if let Err(e) = &result {
+                debug!("Health check of backend {} failed: {e}", backend.address);
+            }
+            match status.update(backend, check, &result) {
+                Some(true) => info!("Backend {} is now healthy", backend.address),
+                Some(false) => warn!(
+                    "Backend {} is now unhealthy: {}",
+                    backend.address,
+                    result.unwrap_err()
+                ),
+                None => (),
+            }
+
+            Duration::from_secs(check.interval)
+        };
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Runs a single health check on a backend of `config`.
+async fn check(config: &Config, backend: &Backend, check: &HealthCheck) -> Result<()> {
+    timeout(Duration::from_secs(check.timeout), async {
+        let addrs = backend.to_socket_addrs(&config.resolver).await?;
+        let mut stream = TcpStream::connect(&addrs[..]).await?;
+
+        if let (true, Some(version)) = (check.proxy_protocol, backend.proxy_protocol) {
+            let mut buf = Vec::new();
+            proxy_protocol::write_local_header(&mut buf, version)?;
+            stream.write_all(&buf).await?;
+        }
+
+        if check.r#type == HealthCheckType::Tls {
+            let sni = ServerName::try_from(check.sni.clone().unwrap_or_default())?;
+            let mut stream = TLS_CONNECTOR.connect(sni, stream).await?;
+            let _ = stream.shutdown().await;
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|_| anyhow!("timed out"))?
+}
+
+/// Consecutive health check results of a backend.
+#[derive(Default)]
+struct Status {
+    successes: u32,
+    failures: u32,
+}
+
+impl Status {
+    /// Accounts for a new check result and updates the backend health status
+    /// if the rise or fall threshold was reached. Returns the new health
+    /// status if it changed.
+    fn update(
+        &mut self,
+        backend: &Backend,
+        check: &HealthCheck,
+        result: &Result<()>,
+    ) -> Option<bool> {
+        match result {
+            Ok(_) => {
+                self.failures = 0;
+                self.successes = self.successes.saturating_add(1);
+                if !backend.is_healthy() && self.successes >= check.rise {
+                    backend.set_healthy(true);
+                    return Some(true);
+                }
+            }
+            Err(_) => {
+                self.successes = 0;
+                self.failures = self.failures.saturating_add(1);
+                if backend.is_healthy() && self.failures >= check.fall {
+                    backend.set_healthy(false);
+                    return Some(false);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// TLS connector used for health checks. Health checks only care about the
+/// backend being able to complete a TLS handshake, certificates are not
+/// verified.
+static TLS_CONNECTOR: Lazy<TlsConnector> = Lazy::new(|| {
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoVerification))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
+
+/// Certificate verifier accepting all certificates.
+#[derive(Debug)]
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{pem::PemObject, PrivateKeyDer},
+            ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    use super::*;
+
+    fn config(address: &str, check: &str) -> Config {
+        Config::from_str(&format!(
+            "
+routes:
+  - domains:
+      - example.net
+    backends:
+      - address: {address}
+    health_check:
+{check}
+            "
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn status() {
+        let cfg = config("127.0.0.1:443", "      rise: 2\n      fall: 3");
+        let route = cfg.get_route("example.net").unwrap();
+        let (backend, check) = (&route.backends[0], route.health_check.as_ref().unwrap());
+        let mut status = Status::default();
+
+        assert!(backend.is_healthy());
+        assert_eq!(status.update(backend, check, &Ok(())), None);
+        assert_eq!(status.update(backend, check, &Err(anyhow!(""))), None);
+        assert_eq!(status.update(backend, check, &Err(anyhow!(""))), None);
+        assert!(backend.is_healthy());
+        assert_eq!(
+            status.update(backend, check, &Err(anyhow!(""))),
+            Some(false)
+        );
+        assert!(!backend.is_healthy());
+        assert!(route.select_backend().is_none());
+
+        assert_eq!(status.update(backend, check, &Err(anyhow!(""))), None);
+        assert_eq!(status.update(backend, check, &Ok(())), None);
+        assert_eq!(status.update(backend, check, &Err(anyhow!(""))), None);
+        assert_eq!(status.update(backend, check, &Ok(())), None);
+        assert!(!backend.is_healthy());
+        assert_eq!(status.update(backend, check, &Ok(())), Some(true));
+        assert!(backend.is_healthy());
+        assert!(route.select_backend().is_some());
+    }
+
+    #[tokio::test]
+    async fn tcp_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let cfg = config(&address, "      type: tcp");
+        let route = cfg.get_route("example.net").unwrap();
+        let (backend, check) = (&route.backends[0], route.health_check.as_ref().unwrap());
+        assert!(super::check(&cfg, backend, check).await.is_ok());
+
+        drop(listener);
+        assert!(super::check(&cfg, backend, check).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let cert = CertificateDer::from_pem_file("test_data/example.net.crt").unwrap();
+        let key = PrivateKeyDer::from_pem_file("test_data/example.net.key").unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert], key)
+                .unwrap(),
+        ));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+
+        let cfg = config(&address, "      type: tls\n      sni: example.net");
+        let route = cfg.get_route("example.net").unwrap();
+        let (backend, check) = (&route.backends[0], route.health_check.as_ref().unwrap());
+        assert!(super::check(&cfg, backend, check).await.is_ok());
+
+        // A plain TCP server can't complete the handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.write_all(b"HTTP/1.0 400 Bad Request\r\n\r\n").await;
+            }
+        });
+
+        let cfg = config(&address, "      type: tls\n      sni: example.net");
+        let route = cfg.get_route("example.net").unwrap();
+        let (backend, check) = (&route.backends[0], route.health_check.as_ref().unwrap());
+        assert!(super::check(&cfg, backend, check).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let cfg = Config::from_str(&format!(
+            "
+routes:
+  - domains:
+      - example.net
+    backends:
+      - address: {address}
+        proxy_protocol: 2
+    health_check:
+      proxy_protocol: true
+            "
+        ))
+        .unwrap();
+        let route = cfg.get_route("example.net").unwrap();
+        let (backend, check) = (&route.backends[0], route.health_check.as_ref().unwrap());
+        assert!(super::check(&cfg, backend, check).await.is_ok());
+
+        // The backend got a 'LOCAL' header.
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            proxy_protocol::read_header(&mut stream, &mut Vec::new())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
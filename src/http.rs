@@ -1,17 +1,14 @@
-use std::{
-    io::{Read, Write},
-    net::SocketAddr,
-    str,
-};
+use std::{net::SocketAddr, str};
 
 use anyhow::{bail, Result};
 use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::{config::Config, reader::ReaderBuf};
+use crate::{access_log, config::Config, metrics, reader::ReaderBuf};
 
 /// Check if the provided buffer looks like an HTTP request. This does not guarantee the request is
 /// a genuine one, but should be enough to at least try handling it.
-pub(crate) fn is_http<R: Read>(rb: &ReaderBuf<R>) -> bool {
+pub(crate) fn is_http<R: AsyncRead + Unpin>(rb: &ReaderBuf<R>) -> bool {
     // The buffer comes from previous reads, we might have failed to read up to 5 bytes. No reason
     // try try reading it again. In all other cases 5 bytes is the maximal length we can match all
     // HTTP methods on. This is a shortcut but as we don't try to be 100% correct here, this is
@@ -30,44 +27,54 @@ pub(crate) fn is_http<R: Read>(rb: &ReaderBuf<R>) -> bool {
 }
 
 /// Redirect an HTTP request with a 308.
-pub(crate) fn try_redirect<R: Read + Write>(
+pub(crate) async fn try_redirect<R: AsyncRead + AsyncWrite + Unpin>(
     config: &Config,
     client: &SocketAddr,
     rb: &mut ReaderBuf<R>,
 ) -> Result<()> {
-    let host = match get_host(rb)? {
+    let host = match get_host(rb).await? {
         Some(host) => host,
         None => return Ok(()), // Not much we can do, not really an error.
     };
     #[cfg(not(test))]
     crate::context::set_hostname(host)?;
 
-    match config.get_route(host) {
+    let route = match config.get_route(host) {
         Some(route) => {
             if !route.http_redirect {
                 bail!("Denied HTTP redirect to HTTPS (disabled)");
             }
 
             if !route.is_allowed(client) {
+                metrics::rejected(Some(route.name()), metrics::Rejection::AclDenied);
                 bail!("Denied HTTP redirect to HTTPS (ACLs)");
             }
+            route
         }
-        None => bail!("Unknown hostname ({host})"), // Not much we can do, not really an error.
-    }
+        None => {
+            metrics::rejected(None, metrics::Rejection::NoRoute);
+            bail!("Unknown hostname ({host})") // Not much we can do, not really an error.
+        }
+    };
 
     info!("Redirecting HTTP request to HTTPS");
+    metrics::http_redirect(route.name());
+    access_log::update(|r| {
+        r.route = Some(route.name().to_string());
+        r.termination = Some("redirect");
+    });
     let response = format!(
         "HTTP/1.0 308 Unknown\r\nLocation: https://{host}:{}\r\n\r\n",
         config.bind_https.port()
     );
-    Ok(rb.get_mut().write_all(response.as_bytes())?)
+    Ok(rb.get_mut().write_all(response.as_bytes()).await?)
 }
 
 /// Try parsing an HTTP request host.
-fn get_host<R: Read>(rb: &mut ReaderBuf<R>) -> Result<Option<&str>> {
+async fn get_host<R: AsyncRead + Unpin>(rb: &mut ReaderBuf<R>) -> Result<Option<&str>> {
     // Try to read the remaining of the HTTP headers. 8KB is the limit size on
     // many web servers.
-    rb.read(8192)?;
+    rb.read(8192).await?;
     let headers = str::from_utf8(rb.buf())?;
 
     // Skip the request line and loop over the HTTP headers to find the Host
@@ -91,43 +98,53 @@ fn get_host<R: Read>(rb: &mut ReaderBuf<R>) -> Result<Option<&str>> {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use std::io::Cursor;
 
     use crate::{config::Config, reader::ReaderBuf as B, tls::tests::RECORD_SNI_ALPN};
 
-    #[test]
-    fn is_http() {
+    #[tokio::test]
+    async fn is_http() {
         let valid: &[&str] = &[
             "GET /", "HEAD ", "POST ", "PUT /", "DELET", "CONNE", "OPTIO", "TRACE", "PATCH",
         ];
         for s in valid.iter() {
-            let mut rb = &mut B::from_bytes(s.as_bytes());
-            rb.read(5).unwrap();
-            assert!(super::is_http(&mut rb));
+            let rb = &mut B::from_bytes(s.as_bytes());
+            rb.read(5).await.unwrap();
+            assert!(super::is_http(rb));
         }
 
         let mut rb = B::from_bytes(&[]);
-        rb.read(5).unwrap();
-        assert_eq!(super::is_http(&mut rb), false);
+        rb.read(5).await.unwrap();
+        assert_eq!(super::is_http(&rb), false);
 
         let mut rb = B::from_bytes(&[0; 256]);
-        rb.read(5).unwrap();
-        assert_eq!(super::is_http(&mut rb), false);
+        rb.read(5).await.unwrap();
+        assert_eq!(super::is_http(&rb), false);
     }
 
-    #[test]
-    fn get_host() {
-        assert_eq!(super::get_host(&mut B::from_bytes(&[])).unwrap(), None);
-        assert_eq!(super::get_host(&mut B::from_bytes(b"GET /")).unwrap(), None);
+    #[tokio::test]
+    async fn get_host() {
+        assert_eq!(
+            super::get_host(&mut B::from_bytes(&[])).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            super::get_host(&mut B::from_bytes(b"GET /")).await.unwrap(),
+            None
+        );
         assert_eq!(
-            super::get_host(&mut B::from_bytes(b"GET /\r\nHost: example.net\r\n")).unwrap(),
+            super::get_host(&mut B::from_bytes(b"GET /\r\nHost: example.net\r\n"))
+                .await
+                .unwrap(),
             Some("example.net")
         );
         assert_eq!(
             super::get_host(&mut B::from_bytes(
                 b"GET /\r\nHost: foo.example.net:8080\r\n"
             ))
+            .await
             .unwrap(),
             Some("foo.example.net")
         );
@@ -135,16 +152,19 @@ mod tests {
             super::get_host(&mut B::from_bytes(
                 b"GET /\r\nScheme: https\r\nHost: example.net\r\nFilename: foo\r\n"
             ))
+            .await
             .unwrap(),
             Some("example.net")
         );
 
         // Invalid UTF-8 sequence.
-        assert!(super::get_host(&mut B::from_bytes(RECORD_SNI_ALPN)).is_err());
+        assert!(super::get_host(&mut B::from_bytes(RECORD_SNI_ALPN))
+            .await
+            .is_err());
     }
 
-    #[test]
-    fn try_redirect() {
+    #[tokio::test]
+    async fn try_redirect() {
         let config = Config::from_str(
             "
 routes:
@@ -171,7 +191,11 @@ routes:
 
         let req = "GET /\r\nHost: example.net\r\n".as_bytes();
         let mut rb = B::new(Cursor::new(req.to_vec()));
-        assert!(super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb).is_ok());
+        assert!(
+            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb)
+                .await
+                .is_ok()
+        );
 
         let buf = rb.into_inner().into_inner();
         assert_eq!(
@@ -181,28 +205,42 @@ routes:
 
         let req = "GET /\r\n".as_bytes();
         let mut rb = B::new(Cursor::new(req.to_vec()));
-        assert!(super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb).is_ok());
+        assert!(
+            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb)
+                .await
+                .is_ok()
+        );
 
         let req = "GET /\r\nHost: denied.example.net\r\n".as_bytes();
         let mut rb = B::new(Cursor::new(req.to_vec()));
         assert!(
-            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb).is_err()
+            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb)
+                .await
+                .is_err()
         );
 
         let req = "GET /\r\nHost: foo.example.net\r\n".as_bytes();
         let mut rb = B::new(Cursor::new(req.to_vec()));
         assert!(
-            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb).is_err()
+            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb)
+                .await
+                .is_err()
         );
 
         let req = "GET /\r\nHost: acls.example.net\r\n".as_bytes();
         let mut rb = B::new(Cursor::new(req.to_vec()));
         assert!(
-            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb).is_err()
+            super::try_redirect(&config, &"127.0.0.1:10000".parse().unwrap(), &mut rb)
+                .await
+                .is_err()
         );
 
         let req = "GET /\r\nHost: acls.example.net\r\n".as_bytes();
         let mut rb = B::new(Cursor::new(req.to_vec()));
-        assert!(super::try_redirect(&config, &"10.0.42.1:10000".parse().unwrap(), &mut rb).is_ok());
+        assert!(
+            super::try_redirect(&config, &"10.0.42.1:10000".parse().unwrap(), &mut rb)
+                .await
+                .is_ok()
+        );
     }
 }
@@ -0,0 +1,357 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+use crate::{
+    config::{Backend, Config, Route},
+    metrics,
+    tls::AlertDescription,
+};
+
+/// Behavior when a connection limit is reached.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct ConnectionLimit {
+    /// Action taken on new connections.
+    #[serde(default)]
+    pub(crate) action: LimitAction,
+    /// TLS alert sent to rejected clients. Defaults to `internal_error`.
+    #[serde(default = "default_alert")]
+    pub(crate) alert: AlertDescription,
+    /// Time in seconds a queued connection waits for an active one to close
+    /// before being rejected. Defaults to 5s.
+    #[serde(default = "default_queue_timeout")]
+    pub(crate) queue_timeout: u64,
+}
+
+impl Default for ConnectionLimit {
+    fn default() -> Self {
+        Self {
+            action: LimitAction::default(),
+            alert: default_alert(),
+            queue_timeout: default_queue_timeout(),
+        }
+    }
+}
+
+/// Actions taken on new connections when a connection limit is reached.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LimitAction {
+    /// The connection is rejected right away.
+    #[default]
+    Reject,
+    /// The connection waits for an active one to close, up to
+    /// `queue_timeout`, before being rejected.
+    Queue,
+}
+
+/// Caps the number of connections active at the same time.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    max: usize,
+    semaphore: Arc<Semaphore>,
+}
+
+impl Limiter {
+    pub(crate) fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            semaphore: Arc::new(Semaphore::new(max)),
+        })
+    }
+
+    /// Maximum number of connections.
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Can a new connection be accepted right now.
+    pub(crate) fn has_capacity(&self) -> bool {
+        self.semaphore.available_permits() > 0
+    }
+
+    fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.semaphore).try_acquire_owned().ok()
+    }
+
+    async fn acquire(&self, deadline: Instant) -> Option<OwnedSemaphorePermit> {
+        tokio::time::timeout_at(deadline, Arc::clone(&self.semaphore).acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+/// Connection limits scopes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Scope {
+    Global,
+    Route,
+    Backend,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Global => "global",
+            Scope::Route => "route",
+            Scope::Backend => "backend",
+        })
+    }
+}
+
+/// Connection slots taken in the limiters, released when dropped.
+pub(crate) struct Permits {
+    permits: Vec<OwnedSemaphorePermit>,
+    backend: Option<OwnedSemaphorePermit>,
+}
+
+/// Takes a connection slot in the backend, route and global limiters. When a
+/// limit is reached the connection is either rejected or queued, depending on
+/// the route `ConnectionLimit`. Queued connections wait holding no slot, so
+/// they don't take one other routes or backends could use. Returns the scope
+/// of the limit reached on failure.
+pub(crate) async fn acquire(
+    config: &Config,
+    route: &Route,
+    backend: &Backend,
+) -> Result<Permits, Scope> {
+    // Most specific limiters first, they are the most likely to be full.
+    let limiters: Vec<(Scope, &Limiter)> = [
+        (Scope::Backend, &backend.limiter),
+        (Scope::Route, &route.limiter),
+        (Scope::Global, &config.limiter),
+    ]
+    .into_iter()
+    .filter_map(|(scope, limiter)| Some((scope, limiter.as_deref()?)))
+    .collect();
+
+    let mut deadline = None;
+    // Slot we waited for, and the index of its limiter.
+    let mut queued = None;
+    let taken = loop {
+        let mut taken = Vec::with_capacity(limiters.len());
+        let mut full = None;
+        for (i, (_, limiter)) in limiters.iter().enumerate() {
+            let permit = match queued.take() {
+                Some((j, permit)) if j == i => Some(permit),
+                other => {
+                    queued = other;
+                    limiter.try_acquire()
+                }
+            };
+            match permit {
+                Some(permit) => taken.push(permit),
+                None => {
+                    full = Some(i);
+                    break;
+                }
+            }
+        }
+        let i = match full {
+            Some(i) => i,
+            None => break taken,
+        };
+
+        // Release the slots taken so far before waiting.
+        drop((taken, queued.take()));
+
+        let (scope, limiter) = limiters[i];
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => *deadline.insert(limit_reached(config, route, scope, limiter)?),
+        };
+        queued = Some((i, limiter.acquire(deadline).await.ok_or(scope)?));
+    };
+
+    let mut permits = Permits {
+        permits: Vec::new(),
+        backend: None,
+    };
+    for ((scope, _), permit) in limiters.into_iter().zip(taken) {
+        match scope {
+            Scope::Backend => permits.backend = Some(permit),
+            _ => permits.permits.push(permit),
+        }
+    }
+    Ok(permits)
+}
+
+/// Handles a connection limit being reached. Returns until when the
+/// connection can be queued.
+fn limit_reached(
+    config: &Config,
+    route: &Route,
+    scope: Scope,
+    limiter: &Limiter,
+) -> Result<Instant, Scope> {
+    metrics::limit_hit(route.name(), &scope.to_string());
+
+    let limit = config.connection_limit(route);
+    match limit.action {
+        LimitAction::Reject => {
+            warn!(
+                "Reached the {scope} limit of {} connections, rejecting",
+                limiter.max()
+            );
+            Err(scope)
+        }
+        LimitAction::Queue => {
+            warn!(
+                "Reached the {scope} limit of {} connections, queueing",
+                limiter.max()
+            );
+            Ok(Instant::now() + Duration::from_secs(limit.queue_timeout))
+        }
+    }
+}
+
+impl Permits {
+    /// Moves the backend slot to `backend`, eg. when connecting to the
+    /// selected backend failed and another one was used. Connections are
+    /// never queued at this point.
+    pub(crate) fn switch_backend(&mut self, backend: &Backend) -> Result<(), Scope> {
+        self.backend = None;
+        if let Some(limiter) = &backend.limiter {
+            self.backend = Some(limiter.try_acquire().ok_or(Scope::Backend)?);
+        }
+        Ok(())
+    }
+}
+
+// Default values.
+fn default_alert() -> AlertDescription {
+    AlertDescription::InternalError
+}
+fn default_queue_timeout() -> u64 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn limits() {
+        let cfg = Config::from_str(
+            "
+max_connections: 3
+routes:
+  - domains:
+      - reject.example.net
+    max_connections: 2
+    backends:
+      - address: 127.0.0.1:443
+        max_connections: 1
+      - address: 127.0.0.2:443
+  - domains:
+      - queue.example.net
+    connection_limit:
+      action: queue
+      queue_timeout: 1
+    backends:
+      - address: 127.0.0.1:443
+        ",
+        )
+        .unwrap();
+        let cfg = Arc::new(cfg);
+        let reject = &cfg.routes[0];
+        let queue = &cfg.routes[1];
+
+        // Backend limit.
+        let p0 = acquire(&cfg, reject, &reject.backends[0]).await.unwrap();
+        assert!(!reject.backends[0].has_capacity());
+        assert_eq!(
+            acquire(&cfg, reject, &reject.backends[0]).await.err(),
+            Some(Scope::Backend)
+        );
+
+        // Route limit.
+        let mut p1 = acquire(&cfg, reject, &reject.backends[1]).await.unwrap();
+        assert_eq!(
+            acquire(&cfg, reject, &reject.backends[1]).await.err(),
+            Some(Scope::Route)
+        );
+
+        // Moving a slot to a full backend fails.
+        assert_eq!(p1.switch_backend(&reject.backends[0]), Err(Scope::Backend));
+        drop(p0);
+        assert!(p1.switch_backend(&reject.backends[0]).is_ok());
+        assert!(!reject.backends[0].has_capacity());
+
+        // Global limit, reached while queueing.
+        let _p2 = acquire(&cfg, queue, &queue.backends[0]).await.unwrap();
+        let _p3 = acquire(&cfg, queue, &queue.backends[0]).await.unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            acquire(&cfg, queue, &queue.backends[0]).await.err(),
+            Some(Scope::Global)
+        );
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        // Queued connections get a slot once one is released, before their
+        // deadline.
+        let queued = tokio::spawn({
+            let cfg = Arc::clone(&cfg);
+            async move {
+                let route = &cfg.routes[1];
+                acquire(&cfg, route, &route.backends[0]).await.is_ok()
+            }
+        });
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(!queued.is_finished());
+        drop(p1);
+        assert!(queued.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queue_holding_nothing() {
+        let cfg = Config::from_str(
+            "
+max_connections: 2
+routes:
+  - domains:
+      - queue.example.net
+    max_connections: 1
+    connection_limit:
+      action: queue
+      queue_timeout: 5
+    backend:
+      address: 127.0.0.1:443
+  - domains:
+      - reject.example.net
+    backend:
+      address: 127.0.0.2:443
+        ",
+        )
+        .unwrap();
+        let cfg = Arc::new(cfg);
+        let (queue, reject) = (&cfg.routes[0], &cfg.routes[1]);
+
+        // A connection queued on its route limit does not hold a global slot.
+        let p0 = acquire(&cfg, queue, &queue.backends[0]).await.unwrap();
+        let queued = tokio::spawn({
+            let cfg = Arc::clone(&cfg);
+            async move {
+                let route = &cfg.routes[0];
+                acquire(&cfg, route, &route.backends[0]).await.is_ok()
+            }
+        });
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!queued.is_finished());
+        assert!(cfg.limiter.as_ref().unwrap().has_capacity());
+        let p1 = acquire(&cfg, reject, &reject.backends[0]).await.unwrap();
+        assert!(!cfg.limiter.as_ref().unwrap().has_capacity());
+
+        // Once a global slot and its route one are released, the queued
+        // connection gets them.
+        drop(p1);
+        drop(p0);
+        assert!(queued.await.unwrap());
+    }
+}
@@ -1,28 +1,193 @@
-use std::{io::Write, sync::Mutex};
+use std::{
+    ffi::CStr,
+    io::Write,
+    net::SocketAddr,
+    os::unix::net::UnixDatagram,
+    str::FromStr,
+    sync::Mutex,
+};
 
-use anyhow::Result;
-use log::{LevelFilter, Metadata, Record};
+use anyhow::{anyhow, bail, Result};
+use log::{Level, LevelFilter, Metadata, Record};
 use termcolor::{BufferedStandardStream, Color, ColorChoice, ColorSpec, WriteColor};
-use time::{macros::format_description, OffsetDateTime};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
 
-use crate::context::REQ_CONTEXT;
+use crate::{
+    context::{ConnectionId, REQ_CONTEXT},
+    fingerprint::Fingerprint,
+};
+
+/// Syslog socket path.
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Journald native protocol socket path.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// Identifier used in syslog and journald records.
+const IDENTIFIER: &str = "sniproxy";
+
+/// Log levels, globally and per module.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Filter {
+    /// Level used for modules not listed in `modules`.
+    default: LevelFilter,
+    /// Per-module levels. Applies to the module and its sub-modules.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of directives, either a level (eg.
+    /// `info`) or a module and its level (eg. `sniproxy::zc=trace`).
+    fn from_str(s: &str) -> Result<Self> {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parse = |level: &str| {
+                LevelFilter::from_str(level).map_err(|_| anyhow!("Invalid log level: {level}"))
+            };
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    if module.is_empty() {
+                        bail!("Invalid log directive: {directive}");
+                    }
+                    filter.modules.push((module.to_string(), parse(level)?));
+                }
+                None => filter.default = parse(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    /// Returns the level to use for a given target (module path). The most
+    /// specific module directive wins.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Returns the most verbose level used.
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// Formats of the console output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    /// Human readable text, with colors when possible.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Log outputs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Target {
+    /// Standard output and error.
+    Console,
+    /// Local syslog daemon, using RFC 5424 messages.
+    Syslog,
+    /// Journald, using its native protocol.
+    Journald,
+}
+
+/// Output in use, and its state.
+enum Output {
+    Console {
+        /// We can output messages to stdout and stderr depending on their
+        /// severity.
+        stdout: Mutex<BufferedStandardStream>,
+        stderr: Mutex<BufferedStandardStream>,
+    },
+    Syslog {
+        socket: UnixDatagram,
+        hostname: String,
+    },
+    Journald(UnixDatagram),
+}
 
 /// Our own simple logger.
 pub(crate) struct Logger {
-    /// Max level the logger will output.
-    max_level: LevelFilter,
-    /// We can output messages to stdout and stderr depending on their severity.
-    stdout: Mutex<BufferedStandardStream>,
-    stderr: Mutex<BufferedStandardStream>,
+    /// Levels the logger will output.
+    filter: Filter,
+    /// Format of the console output.
+    format: Format,
+    output: Output,
+}
+
+/// Request context fields, as seen by the logger.
+struct Context {
+    id: ConnectionId,
+    peer: SocketAddr,
+    local: SocketAddr,
+    hostname: Option<String>,
+    fingerprint: Option<Fingerprint>,
+}
+
+impl Context {
+    /// Returns the current request context, if any. The context is not
+    /// mandatory.
+    fn current() -> Option<Self> {
+        REQ_CONTEXT
+            .try_with(|context| {
+                let context = context.borrow();
+                Self {
+                    id: context.id,
+                    peer: context.peer,
+                    local: context.local,
+                    hostname: context.hostname.clone(),
+                    fingerprint: context.fingerprint.clone(),
+                }
+            })
+            .ok()
+    }
 }
 
 impl Logger {
-    pub(crate) fn init(max_level: LevelFilter) -> Result<()> {
-        log::set_max_level(max_level);
+    pub(crate) fn init(filter: Filter, format: Format, target: Target) -> Result<()> {
+        let connect = |path: &str| -> Result<UnixDatagram> {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(path)
+                .map_err(|e| anyhow!("Could not connect to {path}: {e}"))?;
+            // Never block the caller, drop messages instead.
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        };
+
+        let output = match target {
+            Target::Console => Output::Console {
+                stdout: Mutex::new(BufferedStandardStream::stdout(ColorChoice::Auto)),
+                stderr: Mutex::new(BufferedStandardStream::stderr(ColorChoice::Auto)),
+            },
+            Target::Syslog => Output::Syslog {
+                socket: connect(SYSLOG_SOCKET)?,
+                hostname: hostname(),
+            },
+            Target::Journald => Output::Journald(connect(JOURNALD_SOCKET)?),
+        };
+
+        log::set_max_level(filter.max_level());
         log::set_boxed_logger(Box::new(Self {
-            max_level,
-            stdout: Mutex::new(BufferedStandardStream::stdout(ColorChoice::Auto)),
-            stderr: Mutex::new(BufferedStandardStream::stderr(ColorChoice::Auto)),
+            filter,
+            format,
+            output,
         }))?;
         Ok(())
     }
@@ -36,32 +201,28 @@ impl Logger {
             Some(Color::Cyan),   // Debug.
             Some(Color::White),  // Trace.
         ];
+        let max_level = self.filter.max_level();
 
         // If the log level allows debug! and or trace! messages, show time
         // time.
-        if self.max_level >= LevelFilter::Debug {
+        if max_level >= LevelFilter::Debug {
             OffsetDateTime::now_utc().format_into(
                 out,
                 format_description!("[hour]:[minute]:[second]:[subsecond digits:6] "),
             )?;
         }
 
-        // If we have a request context, use it. Silence access errors, the
-        // context is not mandatory.
-        if let Ok(ret) = REQ_CONTEXT.try_with(|context| -> Result<()> {
-            let context = context.borrow();
-            write!(out, "{}>{} ", context.peer, context.local)?;
+        // If we have a request context, use it.
+        if let Some(context) = Context::current() {
+            write!(out, "{} {}>{} ", context.id, context.peer, context.local)?;
             if let Some(hostname) = &context.hostname {
                 write!(out, "({hostname}) ")?;
             }
-            Ok(())
-        }) {
-            ret?;
         }
 
         // Show the level for error! and warn! messages, or if the max level
         // includes debug!.
-        if record.level() <= LevelFilter::Warn || self.max_level >= LevelFilter::Debug {
+        if record.level() <= LevelFilter::Warn || max_level >= LevelFilter::Debug {
             out.set_color(ColorSpec::new().set_fg(LEVEL_COLORS[record.level() as usize]))?;
             write!(out, "{:5} ", record.level())?;
             out.reset()?;
@@ -75,9 +236,138 @@ impl Logger {
     }
 }
 
+/// Formats a record as a JSON object.
+fn format_json(record: &Record, context: Option<&Context>) -> String {
+    let mut json = serde_json::Map::new();
+    json.insert(
+        "time".to_string(),
+        OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default()
+            .into(),
+    );
+    json.insert("level".to_string(), record.level().as_str().into());
+    json.insert("target".to_string(), record.target().into());
+    if let Some(context) = context {
+        json.insert("id".to_string(), context.id.to_string().into());
+        json.insert("peer".to_string(), context.peer.to_string().into());
+        json.insert("local".to_string(), context.local.to_string().into());
+        if let Some(hostname) = &context.hostname {
+            json.insert("hostname".to_string(), hostname.as_str().into());
+        }
+        if let Some(fingerprint) = &context.fingerprint {
+            json.insert("ja3".to_string(), fingerprint.ja3.as_str().into());
+            json.insert("ja4".to_string(), fingerprint.ja4.as_str().into());
+        }
+    }
+    json.insert("message".to_string(), record.args().to_string().into());
+    serde_json::Value::Object(json).to_string()
+}
+
+/// Syslog severity of a level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Formats a record as an RFC 5424 syslog message, using the daemon facility.
+/// The request context is reported as structured data.
+fn format_syslog(record: &Record, context: Option<&Context>, hostname: &str, time: &str) -> String {
+    // Daemon facility (3).
+    let priority = 3 * 8 + severity(record.level());
+
+    // Escape structured data parameter values.
+    let escape = |value: &str| {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace(']', "\\]")
+    };
+    let data = match context {
+        Some(context) => {
+            let mut data = format!(
+                "[context@32473 id=\"{}\" peer=\"{}\" local=\"{}\"",
+                context.id, context.peer, context.local
+            );
+            if let Some(hostname) = &context.hostname {
+                data.push_str(&format!(" hostname=\"{}\"", escape(hostname)));
+            }
+            if let Some(fingerprint) = &context.fingerprint {
+                data.push_str(&format!(
+                    " ja3=\"{}\" ja4=\"{}\"",
+                    fingerprint.ja3, fingerprint.ja4
+                ));
+            }
+            data.push(']');
+            data
+        }
+        None => "-".to_string(),
+    };
+
+    format!(
+        "<{priority}>1 {time} {hostname} {IDENTIFIER} {} - {data} {}",
+        std::process::id(),
+        record.args()
+    )
+}
+
+/// Formats a record using the journald native protocol.
+fn format_journald(record: &Record, context: Option<&Context>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut field = |key: &str, value: &str| {
+        // Values containing new lines use a binary format: the key, a new
+        // line, the value length as a 64-bit little endian integer and the
+        // value.
+        if value.contains('\n') {
+            out.extend_from_slice(key.as_bytes());
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            out.extend_from_slice(value.as_bytes());
+        } else {
+            out.extend_from_slice(format!("{key}={value}").as_bytes());
+        }
+        out.push(b'\n');
+    };
+
+    field("MESSAGE", &record.args().to_string());
+    field("PRIORITY", &severity(record.level()).to_string());
+    field("SYSLOG_IDENTIFIER", IDENTIFIER);
+    field("TARGET", record.target());
+    if let Some(context) = context {
+        field("CONNECTION_ID", &context.id.to_string());
+        field("PEER", &context.peer.to_string());
+        field("LOCAL", &context.local.to_string());
+        if let Some(hostname) = &context.hostname {
+            field("HOSTNAME", hostname);
+        }
+        if let Some(fingerprint) = &context.fingerprint {
+            field("JA3", &fingerprint.ja3);
+            field("JA4", &fingerprint.ja4);
+        }
+    }
+    out
+}
+
+/// Returns the host name of the system, or "-" (syslog nil value) if unknown.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // Safety: the buffer is valid for its whole length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return "-".to_string();
+    }
+    match CStr::from_bytes_until_nul(&buf) {
+        Ok(name) if !name.is_empty() => name.to_string_lossy().into_owned(),
+        _ => "-".to_string(),
+    }
+}
+
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
@@ -85,19 +375,173 @@ impl log::Log for Logger {
             return;
         }
 
-        // Select the output based on the log level.
-        let mut out = match record.level() {
-            level if level == LevelFilter::Error => self.stderr.lock().unwrap(),
-            _ => self.stdout.lock().unwrap(),
-        };
+        // Not much we can do to report the errors below.
+        match &self.output {
+            Output::Console { stdout, stderr } => {
+                // Select the output based on the log level.
+                let mut out = match record.level() {
+                    level if level == LevelFilter::Error => stderr.lock().unwrap(),
+                    _ => stdout.lock().unwrap(),
+                };
 
-        // Not much we can do to report the error.
-        let _ = self.try_log(&mut out, record);
+                let _ = match self.format {
+                    Format::Text => self.try_log(&mut out, record),
+                    Format::Json => {
+                        let json = format_json(record, Context::current().as_ref());
+                        writeln!(out, "{json}")
+                            .and_then(|_| out.flush())
+                            .map_err(|e| e.into())
+                    }
+                };
+            }
+            Output::Syslog { socket, hostname } => {
+                let time = OffsetDateTime::now_utc()
+                    .format(&Rfc3339)
+                    .unwrap_or_default();
+                let msg = format_syslog(record, Context::current().as_ref(), hostname, &time);
+                let _ = socket.send(msg.as_bytes());
+            }
+            Output::Journald(socket) => {
+                let _ = socket.send(&format_journald(record, Context::current().as_ref()));
+            }
+        }
     }
 
     fn flush(&self) {
         // Not much we can do to report the errors.
-        let _ = self.stdout.lock().unwrap().flush();
-        let _ = self.stderr.lock().unwrap().flush();
+        if let Output::Console { stdout, stderr } = &self.output {
+            let _ = stdout.lock().unwrap().flush();
+            let _ = stderr.lock().unwrap().flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        Context {
+            id: ConnectionId::new(),
+            peer: "10.0.42.132:1337".parse().unwrap(),
+            local: "172.16.99.1:443".parse().unwrap(),
+            hostname: Some("example.net".to_string()),
+            fingerprint: Some(Fingerprint {
+                ja3: "6a75a26f76b4b28b58ac04b868cebdd7".to_string(),
+                ja4: "t13d3611a1_018971650b2c_24611a1bfa3f".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter = Filter::from_str("warn").unwrap();
+        assert_eq!(filter.level("sniproxy"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Warn);
+
+        let filter = Filter::from_str("sniproxy::zc=trace, info,sniproxy::tcp=error").unwrap();
+        assert_eq!(filter.level("sniproxy"), LevelFilter::Info);
+        assert_eq!(filter.level("sniproxy::zc"), LevelFilter::Trace);
+        assert_eq!(filter.level("sniproxy::zcfoo"), LevelFilter::Info);
+        assert_eq!(filter.level("sniproxy::tcp::tls"), LevelFilter::Error);
+        assert_eq!(filter.level("rustls::client"), LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        // The most specific module wins.
+        let filter = Filter::from_str("sniproxy=debug,sniproxy::tcp=off").unwrap();
+        assert_eq!(filter.level("sniproxy::reload"), LevelFilter::Debug);
+        assert_eq!(filter.level("sniproxy::tcp::tls"), LevelFilter::Off);
+
+        // Defaults to info.
+        assert_eq!(
+            Filter::from_str("sniproxy::zc=debug").unwrap().default,
+            LevelFilter::Info
+        );
+
+        assert!(Filter::from_str("foo").is_err());
+        assert!(Filter::from_str("sniproxy=foo").is_err());
+        assert!(Filter::from_str("=info").is_err());
+    }
+
+    #[test]
+    fn json() {
+        let record = Record::builder()
+            .args(format_args!("Hello \"world\""))
+            .level(Level::Warn)
+            .target("sniproxy::tcp")
+            .build();
+
+        let context = context();
+        let json: serde_json::Value =
+            serde_json::from_str(&format_json(&record, Some(&context))).unwrap();
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["id"], context.id.to_string());
+        assert_eq!(json["target"], "sniproxy::tcp");
+        assert_eq!(json["peer"], "10.0.42.132:1337");
+        assert_eq!(json["local"], "172.16.99.1:443");
+        assert_eq!(json["hostname"], "example.net");
+        assert_eq!(json["ja4"], "t13d3611a1_018971650b2c_24611a1bfa3f");
+        assert_eq!(json["message"], "Hello \"world\"");
+        assert!(json["time"].is_string());
+
+        let json: serde_json::Value = serde_json::from_str(&format_json(&record, None)).unwrap();
+        assert!(json.get("peer").is_none());
+    }
+
+    #[test]
+    fn syslog() {
+        let record = Record::builder()
+            .args(format_args!("Connection shut down"))
+            .level(Level::Info)
+            .target("sniproxy::tcp")
+            .build();
+        let time = "2024-05-04T13:37:42.5Z";
+        let pid = std::process::id();
+        let context = context();
+        let id = context.id;
+
+        assert_eq!(
+            format_syslog(&record, Some(&context), "host", time),
+            format!(
+                "<30>1 {time} host sniproxy {pid} - [context@32473 id=\"{id}\" \
+                 peer=\"10.0.42.132:1337\" local=\"172.16.99.1:443\" hostname=\"example.net\" \
+                 ja3=\"6a75a26f76b4b28b58ac04b868cebdd7\" \
+                 ja4=\"t13d3611a1_018971650b2c_24611a1bfa3f\"] Connection shut down"
+            )
+        );
+
+        let record = Record::builder()
+            .args(format_args!("Oops"))
+            .level(Level::Error)
+            .build();
+        assert_eq!(
+            format_syslog(&record, None, "host", time),
+            format!("<27>1 {time} host sniproxy {pid} - - Oops")
+        );
+    }
+
+    #[test]
+    fn journald() {
+        let record = Record::builder()
+            .args(format_args!("Multi\nline"))
+            .level(Level::Debug)
+            .target("sniproxy::zc")
+            .build();
+
+        let context = context();
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&10u64.to_le_bytes());
+        expected.extend_from_slice(b"Multi\nline\n");
+        expected.extend_from_slice(
+            format!(
+                "PRIORITY=7\nSYSLOG_IDENTIFIER=sniproxy\nTARGET=sniproxy::zc\n\
+                 CONNECTION_ID={}\nPEER=10.0.42.132:1337\nLOCAL=172.16.99.1:443\n\
+                 HOSTNAME=example.net\nJA3=6a75a26f76b4b28b58ac04b868cebdd7\n\
+                 JA4=t13d3611a1_018971650b2c_24611a1bfa3f\n",
+                context.id
+            )
+            .as_bytes(),
+        );
+        assert_eq!(format_journald(&record, Some(&context)), expected);
     }
 }
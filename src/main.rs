@@ -1,33 +1,62 @@
-use std::{path::PathBuf, sync::Arc, thread};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::{bail, Result};
+use arc_swap::ArcSwap;
 use clap::{builder::PossibleValuesParser, Parser};
-use log::{error, LevelFilter};
+use log::{error, info};
 use once_cell::sync::OnceCell;
 use tokio::runtime;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
+mod access_log;
+mod admin;
+mod client_limits;
 mod config;
 mod context;
+mod dns;
+mod fingerprint;
+mod health;
 mod http;
+mod limits;
 mod logger;
+mod metrics;
 mod proxy_protocol;
 mod reader;
+mod registry;
+mod reload;
+mod shutdown;
 mod tcp;
 mod tls;
 mod zc;
 
-use crate::{config::Config, logger::Logger};
+use crate::{
+    config::{Config, Listener},
+    logger::Logger,
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(
         long,
-        value_parser=PossibleValuesParser::new(["error", "warn", "info", "debug", "trace"]),
         default_value = "info",
-        help = "Log level",
+        help = "Log level (error, warn, info, debug, trace or off), optionally per module (eg. info,sniproxy::zc=trace)",
     )]
     pub(crate) log_level: String,
+    #[arg(
+        long,
+        value_parser=PossibleValuesParser::new(["text", "json"]),
+        default_value = "text",
+        help = "Log format, for the console target",
+    )]
+    pub(crate) log_format: String,
+    #[arg(
+        long,
+        value_parser=PossibleValuesParser::new(["console", "syslog", "journald"]),
+        default_value = "console",
+        help = "Log target",
+    )]
+    pub(crate) log_target: String,
     #[arg(
         short,
         long,
@@ -35,62 +64,139 @@ struct Args {
         help = "Path to the configuration file"
     )]
     config: PathBuf,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "Reload the configuration file when it is modified, checking every SECONDS"
+    )]
+    watch_config: Option<u64>,
 }
 
 fn main() -> Result<()> {
     // Start by parsing the cli arguments.
     let args = Args::parse();
 
-    // Set the log level.
-    let log_level = match args.log_level.as_str() {
-        "error" => LevelFilter::Error,
-        "warn" => LevelFilter::Warn,
-        "info" => LevelFilter::Info,
-        "debug" => LevelFilter::Debug,
-        "trace" => LevelFilter::Trace,
-        x => bail!("Invalid log_level: {}", x),
+    // Set up the logger.
+    let log_format = match args.log_format.as_str() {
+        "text" => logger::Format::Text,
+        "json" => logger::Format::Json,
+        x => bail!("Invalid log_format: {}", x),
+    };
+    let log_target = match args.log_target.as_str() {
+        "console" => logger::Target::Console,
+        "syslog" => logger::Target::Syslog,
+        "journald" => logger::Target::Journald,
+        x => bail!("Invalid log_target: {}", x),
     };
-    Logger::init(log_level)?;
+    Logger::init(args.log_level.parse()?, log_format, log_target)?;
 
     // Parse the configuration file.
-    let config = Arc::new(Config::from_file(args.config)?);
-
-    let mut threads = Vec::new();
-
-    // Start the TLS listener and handle incoming connections.
-    let _config = Arc::clone(&config);
-    let bind = config.bind_https;
-    threads.push((
-        "HTTPS",
-        thread::spawn(move || {
-            if let Err(e) = tcp::listen_and_proxy(_config, bind, tcp::tls::handle_stream) {
-                error!("HTTPS listener returned: {e}");
-            }
-        }),
-    ));
-
-    // Start the HTTP listener and handle incoming connections, if needed.
-    if config.need_http() {
-        let _config = Arc::clone(&config);
-        let bind = config.bind_http;
-        threads.push((
-            "HTTP",
-            thread::spawn(move || {
-                if let Err(e) = tcp::listen_and_proxy(_config, bind, tcp::http::handle_stream) {
-                    error!("HTTP listener returned: {e}");
-                }
-            }),
-        ));
+    let config = Arc::new(ArcSwap::from_pointee(Config::from_file(
+        args.config.clone(),
+    )?));
+
+    // Enable the access log, if configured.
+    if let Some(access_log) = &config.load().access_log {
+        access_log::init(access_log)?;
     }
 
-    // Wait for threads to join.
-    threads.drain(..).for_each(|(name, t)| {
-        if t.join().is_err() {
-            error!("{name} server thread returned unexpectedly");
+    runtime!()?.block_on(async {
+        // Start the backends health checks.
+        health::start(&config);
+
+        // Reload the configuration on SIGHUP and, if requested, when the
+        // configuration file is modified.
+        reload::watch_sighup(args.config.clone(), Arc::clone(&config))?;
+        if let Some(interval) = args.watch_config {
+            tokio::spawn(reload::watch_file(
+                args.config.clone(),
+                Arc::clone(&config),
+                Duration::from_secs(interval.max(1)),
+            ));
+        }
+
+        // Handle termination signals from now on.
+        let mut signals = shutdown::Signals::new()?;
+        let shutdown = CancellationToken::new();
+        let tracker = TaskTracker::new();
+
+        let mut listeners = Vec::new();
+
+        // Start the TLS listener and handle incoming connections.
+        listeners.push((
+            "HTTPS",
+            tokio::spawn(tcp::listen_and_proxy(
+                Arc::clone(&config),
+                Listener::Https,
+                config.load().bind_https,
+                tcp::tls::handle_stream,
+                shutdown.clone(),
+                tracker.clone(),
+            )),
+        ));
+
+        // Start the HTTP listener and handle incoming connections, if needed.
+        if config.load().need_http() {
+            listeners.push((
+                "HTTP",
+                tokio::spawn(tcp::listen_and_proxy(
+                    Arc::clone(&config),
+                    Listener::Http,
+                    config.load().bind_http,
+                    tcp::http::handle_stream,
+                    shutdown.clone(),
+                    tracker.clone(),
+                )),
+            ));
+        }
+
+        // Serve metrics, if enabled.
+        if let Some(bind) = config.load().bind_metrics {
+            tokio::spawn({
+                let shutdown = shutdown.clone();
+                async move {
+                    if let Err(e) = metrics::serve(bind, shutdown).await {
+                        error!("Metrics listener returned: {e}");
+                    }
+                }
+            });
+        }
+
+        // Serve the admin interface, if enabled.
+        if let Some(bind) = config.load().bind_admin.clone() {
+            tokio::spawn({
+                let (config, path) = (Arc::clone(&config), args.config.clone());
+                let shutdown = shutdown.clone();
+                async move {
+                    if let Err(e) = admin::serve(bind, config, path, shutdown).await {
+                        error!("Admin listener returned: {e}");
+                    }
+                }
+            });
+        }
+
+        // Wait for a termination signal or for all listeners to return.
+        let listeners = async {
+            for (name, listener) in listeners.drain(..) {
+                match listener.await {
+                    Ok(Err(e)) => error!("{name} listener returned: {e}"),
+                    Err(_) => error!("{name} server task returned unexpectedly"),
+                    _ => (),
+                }
+            }
+        };
+        tokio::select! {
+            _ = listeners => (),
+            signal = signals.recv() => info!("Received {signal}, shutting down"),
         }
-    });
 
-    Ok(())
+        // Let running connections finish before exiting.
+        let timeout = Duration::from_secs(config.load().drain_timeout);
+        shutdown::drain(&shutdown, &tracker, &mut signals, timeout).await;
+        access_log::flush(Duration::from_secs(1));
+
+        Ok(())
+    })
 }
 
 static RUNTIME: OnceCell<runtime::Runtime> = OnceCell::new();
@@ -98,6 +204,11 @@ static RUNTIME: OnceCell<runtime::Runtime> = OnceCell::new();
 #[macro_export]
 macro_rules! runtime {
     () => {
-        RUNTIME.get_or_try_init(|| runtime::Builder::new_multi_thread().enable_io().build())
+        RUNTIME.get_or_try_init(|| {
+            runtime::Builder::new_multi_thread()
+                .enable_io()
+                .enable_time()
+                .build()
+        })
     };
 }
@@ -0,0 +1,475 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{debug, error};
+use once_cell::sync::Lazy;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::access_log;
+
+/// Global metrics, exposed in the Prometheus text format.
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Buckets of the latency histograms, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Reasons for rejecting a client connection.
+#[derive(Clone, Copy)]
+pub(crate) enum Rejection {
+    /// The requested hostname does not match any route.
+    NoRoute,
+    /// The client is not allowed by the route ACLs.
+    AclDenied,
+    /// The route has no (healthy) backend.
+    NoBackend,
+    /// No backend connection could be established.
+    ConnectError,
+    /// The client request could not be parsed.
+    ParseError,
+    /// A connection limit was reached.
+    LimitReached,
+    /// The client opened new connections too fast.
+    RateLimited,
+    /// The client has too many connections opened.
+    ClientLimit,
+    /// The client does not offer the minimum TLS version of the route.
+    ProtocolVersion,
+    /// The client uses Encrypted Client Hello, which the route refuses.
+    EchDenied,
+}
+
+impl Rejection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::NoRoute => "no_route",
+            Rejection::AclDenied => "acl_denied",
+            Rejection::NoBackend => "no_backend",
+            Rejection::ConnectError => "connect_error",
+            Rejection::ParseError => "parse_error",
+            Rejection::LimitReached => "limit_reached",
+            Rejection::RateLimited => "rate_limited",
+            Rejection::ClientLimit => "client_limit",
+            Rejection::ProtocolVersion => "protocol_version",
+            Rejection::EchDenied => "ech_denied",
+        }
+    }
+}
+
+/// Counts a connection accepted on a listener.
+pub(crate) fn accepted(listener: &str) {
+    METRICS.accepted.with(&[listener], |v| *v += 1);
+}
+
+/// Counts a rejected connection. The route is unknown for some rejection
+/// reasons.
+pub(crate) fn rejected(route: Option<&str>, reason: Rejection) {
+    // This is also the termination reason of the connection.
+    access_log::update(|r| r.termination = Some(reason.as_str()));
+
+    METRICS
+        .rejected
+        .with(&[route.unwrap_or(""), reason.as_str()], |v| *v += 1);
+}
+
+/// Counts a connection hitting a connection limit on a route. `scope` is the
+/// limit that was hit: global, route or backend.
+pub(crate) fn limit_hit(route: &str, scope: &str) {
+    METRICS.limit_hits.with(&[route, scope], |v| *v += 1);
+}
+
+/// Counts a connection proxied to a backend of a route.
+pub(crate) fn proxied(route: &str) {
+    METRICS.proxied.with(&[route], |v| *v += 1);
+}
+
+/// Accounts for an active connection on a route, until the returned value is
+/// dropped.
+pub(crate) fn active(route: &str) -> ActiveConnection {
+    METRICS.active.with(&[route], |v| *v += 1);
+    ActiveConnection(route.to_string())
+}
+
+/// Keeps track of an active connection, for as long as it lives.
+pub(crate) struct ActiveConnection(String);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        METRICS.active.with(&[&self.0], |v| *v -= 1);
+    }
+}
+
+/// Counts bytes proxied on a route, in both directions.
+pub(crate) fn bytes(route: &str, to_backend: usize, to_client: usize) {
+    METRICS
+        .bytes
+        .with(&[route, "to_backend"], |v| *v += to_backend as u64);
+    METRICS
+        .bytes
+        .with(&[route, "to_client"], |v| *v += to_client as u64);
+}
+
+/// Observes the time spent reading and parsing a client handshake, since
+/// `start`.
+pub(crate) fn handshake_parsed(start: Instant) {
+    let elapsed = start.elapsed();
+    METRICS.handshake_duration.with(&[], |h| h.observe(elapsed));
+}
+
+/// Observes the time spent connecting to a backend of a route, since `start`.
+pub(crate) fn backend_connected(route: &str, start: Instant) {
+    let elapsed = start.elapsed();
+    METRICS
+        .connect_duration
+        .with(&[route], |h| h.observe(elapsed));
+}
+
+/// Counts an HTTP to HTTPS redirect served for a route.
+pub(crate) fn http_redirect(route: &str) {
+    METRICS.http_redirects.with(&[route], |v| *v += 1);
+}
+
+/// Starts an HTTP server on `bind` serving the metrics on `/metrics`, until
+/// `shutdown` is cancelled.
+pub(crate) async fn serve(bind: SocketAddr, shutdown: CancellationToken) -> Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => {
+                debug!("Closing listener on {bind}");
+                return Ok(());
+            }
+            conn = listener.accept() => match conn {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Connection error: {e}");
+                    continue;
+                }
+            },
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream).await {
+                debug!("Could not serve metrics: {e}");
+            }
+        });
+    }
+}
+
+/// Handles a single HTTP request to the metrics server.
+async fn handle_request(mut stream: TcpStream) -> Result<()> {
+    // We only care about the request line, which fits in the first read.
+    let mut buf = [0; 1024];
+    let len = timeout(Duration::from_secs(3), stream.read(&mut buf)).await??;
+
+    let response = match buf[..len].starts_with(b"GET /metrics ") {
+        true => {
+            let body = METRICS.render();
+            format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        false => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+/// All the metrics we expose.
+struct Metrics {
+    accepted: Family<u64>,
+    rejected: Family<u64>,
+    proxied: Family<u64>,
+    active: Family<i64>,
+    bytes: Family<u64>,
+    handshake_duration: Family<Histogram>,
+    connect_duration: Family<Histogram>,
+    http_redirects: Family<u64>,
+    limit_hits: Family<u64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            accepted: Family::new(
+                "sniproxy_connections_accepted_total",
+                "Connections accepted per listener.",
+                "counter",
+                &["listener"],
+            ),
+            rejected: Family::new(
+                "sniproxy_connections_rejected_total",
+                "Connections rejected per route and reason.",
+                "counter",
+                &["route", "reason"],
+            ),
+            proxied: Family::new(
+                "sniproxy_connections_proxied_total",
+                "Connections proxied to a backend per route.",
+                "counter",
+                &["route"],
+            ),
+            active: Family::new(
+                "sniproxy_connections_active",
+                "Connections currently proxied per route.",
+                "gauge",
+                &["route"],
+            ),
+            bytes: Family::new(
+                "sniproxy_proxied_bytes_total",
+                "Bytes proxied per route and direction.",
+                "counter",
+                &["route", "direction"],
+            ),
+            handshake_duration: Family::new(
+                "sniproxy_handshake_parse_duration_seconds",
+                "Time spent reading and parsing client handshakes.",
+                "histogram",
+                &[],
+            ),
+            connect_duration: Family::new(
+                "sniproxy_backend_connect_duration_seconds",
+                "Time spent connecting to backends per route.",
+                "histogram",
+                &["route"],
+            ),
+            http_redirects: Family::new(
+                "sniproxy_http_redirects_total",
+                "HTTP to HTTPS redirects served per route.",
+                "counter",
+                &["route"],
+            ),
+            limit_hits: Family::new(
+                "sniproxy_connection_limit_hits_total",
+                "Connections hitting a connection limit per route and limit scope.",
+                "counter",
+                &["route", "scope"],
+            ),
+        }
+    }
+}
+
+impl Metrics {
+    /// Renders all the metrics in the Prometheus text format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        self.accepted.render(&mut out);
+        self.rejected.render(&mut out);
+        self.proxied.render(&mut out);
+        self.active.render(&mut out);
+        self.bytes.render(&mut out);
+        self.handshake_duration.render(&mut out);
+        self.connect_duration.render(&mut out);
+        self.http_redirects.render(&mut out);
+        self.limit_hits.render(&mut out);
+        out
+    }
+}
+
+/// A metric and its values, one per set of label values.
+struct Family<T> {
+    name: &'static str,
+    help: &'static str,
+    r#type: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, T>>,
+}
+
+impl<T: Default + Value> Family<T> {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        r#type: &'static str,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            r#type,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Updates the value matching the given label values.
+    fn with<F: FnOnce(&mut T)>(&self, labels: &[&str], f: F) {
+        let labels = labels.iter().map(|l| l.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        f(values.entry(labels).or_default());
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.r#type);
+
+        let values = self.values.lock().unwrap();
+        for (labels, value) in values.iter() {
+            let labels: Vec<String> = self
+                .labels
+                .iter()
+                .zip(labels)
+                .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+                .collect();
+            value.render(out, self.name, &labels);
+        }
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Formats a set of labels, eg. `{a="1",b="2"}`.
+fn format_labels(labels: &[String]) -> String {
+    match labels.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", labels.join(",")),
+    }
+}
+
+/// Metric values which can be rendered.
+trait Value {
+    fn render(&self, out: &mut String, name: &str, labels: &[String]);
+}
+
+impl Value for u64 {
+    fn render(&self, out: &mut String, name: &str, labels: &[String]) {
+        let _ = writeln!(out, "{name}{} {self}", format_labels(labels));
+    }
+}
+
+impl Value for i64 {
+    fn render(&self, out: &mut String, name: &str, labels: &[String]) {
+        let _ = writeln!(out, "{name}{} {self}", format_labels(labels));
+    }
+}
+
+/// Histogram using the `LATENCY_BUCKETS` buckets.
+#[derive(Default)]
+struct Histogram {
+    /// Number of observations per bucket (non-cumulative).
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: Duration) {
+        let value = value.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| value <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Value for Histogram {
+    fn render(&self, out: &mut String, name: &str, labels: &[String]) {
+        let mut cumulative = 0;
+        for (bucket, count) in LATENCY_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let mut labels = labels.to_vec();
+            labels.push(format!("le=\"{bucket}\""));
+            let _ = writeln!(out, "{name}_bucket{} {cumulative}", format_labels(&labels));
+        }
+        let mut inf = labels.to_vec();
+        inf.push("le=\"+Inf\"".to_string());
+        let _ = writeln!(out, "{name}_bucket{} {}", format_labels(&inf), self.count);
+
+        let labels = format_labels(labels);
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.accepted.with(&["HTTPS"], |v| *v += 2);
+        metrics
+            .rejected
+            .with(&["", Rejection::NoRoute.as_str()], |v| *v += 1);
+        metrics.active.with(&["a\"b"], |v| *v += 1);
+        metrics
+            .handshake_duration
+            .with(&[], |h| h.observe(Duration::from_millis(3)));
+        metrics
+            .handshake_duration
+            .with(&[], |h| h.observe(Duration::from_secs(10)));
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE sniproxy_connections_accepted_total counter\n"));
+        assert!(out.contains("sniproxy_connections_accepted_total{listener=\"HTTPS\"} 2\n"));
+        assert!(
+            out.contains("sniproxy_connections_rejected_total{route=\"\",reason=\"no_route\"} 1\n")
+        );
+        assert!(out.contains("sniproxy_connections_active{route=\"a\\\"b\"} 1\n"));
+        assert!(out.contains("sniproxy_handshake_parse_duration_seconds_bucket{le=\"0.0025\"} 0\n"));
+        assert!(out.contains("sniproxy_handshake_parse_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("sniproxy_handshake_parse_duration_seconds_bucket{le=\"2.5\"} 1\n"));
+        assert!(out.contains("sniproxy_handshake_parse_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("sniproxy_handshake_parse_duration_seconds_count 2\n"));
+        assert!(out.contains("# TYPE sniproxy_backend_connect_duration_seconds histogram\n"));
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bind = listener.local_addr().unwrap();
+        drop(listener);
+
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(super::serve(bind, shutdown.clone()));
+        accepted("test");
+
+        let get = |path: &'static str| async move {
+            // The server might not be listening yet.
+            let mut stream = loop {
+                match TcpStream::connect(bind).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            stream
+                .write_all(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains("sniproxy_connections_accepted_total{listener=\"test\"} 1\n"));
+        assert!(get("/").await.starts_with("HTTP/1.0 404 Not Found\r\n"));
+
+        shutdown.cancel();
+        assert!(server.await.unwrap().is_ok());
+    }
+}
@@ -1,40 +1,140 @@
 use std::{
+    cmp,
     io::Write,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
 };
 
 use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-/// Writes a `version` HAProxy protocol header.
+/// HAProxy protocol version 2 signature.
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+/// Maximum length of an HAProxy protocol header version 1, including the
+/// trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Length of the reads issued when reading an HAProxy protocol header, to get
+/// it in a single read most of the time.
+const READ_LEN: usize = 512;
+
+/// Maximum length of the PP2_TYPE_UNIQUE_ID TLV value.
+const UNIQUE_ID_MAX_LEN: usize = 128;
+
+/// HAProxy protocol version 2 TLVs, see
+/// https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt (2.2).
+pub(crate) enum Tlv<'a> {
+    /// PP2_TYPE_ALPN, the application protocol.
+    Alpn(&'a [u8]),
+    /// PP2_TYPE_AUTHORITY, the host name requested by the client.
+    Authority(&'a str),
+    /// PP2_TYPE_CRC32C, a checksum of the whole header. Computed when writing
+    /// the header.
+    Crc32c,
+    /// PP2_TYPE_UNIQUE_ID, an opaque connection identifier.
+    UniqueId(&'a [u8]),
+}
+
+impl Tlv<'_> {
+    /// TLV type.
+    fn r#type(&self) -> u8 {
+        match self {
+            Tlv::Alpn(_) => 0x01,
+            Tlv::Authority(_) => 0x02,
+            Tlv::Crc32c => 0x03,
+            Tlv::UniqueId(_) => 0x05,
+        }
+    }
+
+    /// TLV value. The checksum value is zeroed, as it is computed last.
+    fn value(&self) -> &[u8] {
+        match self {
+            Tlv::Alpn(value) | Tlv::UniqueId(value) => value,
+            Tlv::Authority(value) => value.as_bytes(),
+            Tlv::Crc32c => &[0; 4],
+        }
+    }
+}
+
+/// Writes a `version` HAProxy protocol header. TLVs are only supported by the
+/// version 2 of the protocol and are ignored otherwise.
 pub(crate) fn write_header<W>(
     w: W,
     version: u8,
     local: &SocketAddr,
     peer: &SocketAddr,
+    tlvs: &[Tlv],
 ) -> Result<()>
 where
     W: Write,
 {
     match version {
         1 => write_header_v1(w, local, peer),
-        2 => write_header_v2(w, local, peer),
+        2 => write_header_v2(w, local, peer, tlvs),
         x => bail!("Invalid HAProxy protocol header version ({x})"),
     }
 }
 
+/// Writes a `version` HAProxy protocol header for a connection initiated by
+/// ourselves (eg. health checks), not on behalf of a client: a 'LOCAL' command
+/// for version 2 and an 'UNKNOWN' protocol for version 1.
+pub(crate) fn write_local_header<W>(mut w: W, version: u8) -> Result<()>
+where
+    W: Write,
+{
+    match version {
+        1 => w.write_all(b"PROXY UNKNOWN\r\n")?,
+        2 => {
+            w.write_all(&V2_SIGNATURE)?;
+            // Version 2, 'local' command; AF_UNSPEC and no data.
+            w.write_all(&[0x20, 0x00, 0x00, 0x00])?;
+        }
+        x => bail!("Invalid HAProxy protocol header version ({x})"),
+    }
+    Ok(())
+}
+
+/// Converts IPv4-mapped IPv6 addresses to IPv4 ones, eg. when an IPv4 client
+/// reaches a dual-stack listener, so both addresses use the same family when
+/// possible. Returns None if the addresses do not have a common family.
+fn normalize(local: &SocketAddr, peer: &SocketAddr) -> Option<(SocketAddr, SocketAddr)> {
+    let to_ipv4 = |addr: &SocketAddr| match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()),
+            None => *addr,
+        },
+        IpAddr::V4(_) => *addr,
+    };
+
+    match (to_ipv4(local), to_ipv4(peer)) {
+        (local @ SocketAddr::V4(_), peer @ SocketAddr::V4(_)) => Some((local, peer)),
+        // Keep the original addresses if both are IPv6 ones.
+        (SocketAddr::V6(_), SocketAddr::V6(_)) => Some((*local, *peer)),
+        (SocketAddr::V6(_), SocketAddr::V4(_)) | (SocketAddr::V4(_), SocketAddr::V6(_)) => None,
+    }
+}
+
 /// Write an HAProxy protocol header version 1 in a writer.
 /// See https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
 fn write_header_v1<W>(mut w: W, local: &SocketAddr, peer: &SocketAddr) -> Result<()>
 where
     W: Write,
 {
+    // Use the 'UNKNOWN' protocol if the addresses have no common family.
+    let (local, peer) = match normalize(local, peer) {
+        Some(addrs) => addrs,
+        None => return Ok(w.write_all(b"PROXY UNKNOWN\r\n")?),
+    };
+
     Ok(write!(
         w,
         "PROXY {} {} {} {} {}\r\n",
-        match (local, peer) {
-            (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
-            (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
-            _ => bail!("Invalid address types (mixing IPv4 and IPv6)"),
+        match local {
+            SocketAddr::V4(_) => "TCP4",
+            SocketAddr::V6(_) => "TCP6",
         },
         peer.ip(),
         local.ip(),
@@ -45,52 +145,256 @@ where
 
 /// Write an HAProxy protocol header version 2 in a writer.
 /// See https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
-fn write_header_v2<W>(mut w: W, local: &SocketAddr, peer: &SocketAddr) -> Result<()>
+fn write_header_v2<W>(mut w: W, local: &SocketAddr, peer: &SocketAddr, tlvs: &[Tlv]) -> Result<()>
 where
     W: Write,
 {
+    // The header is built in a local buffer as its checksum, if any, can only
+    // be computed once it is complete.
+    let mut buf = Vec::with_capacity(64);
+
     // Protocol signature and the command (\x2 followed by \x0 for 'local' or
     // \x1 for 'proxy').
-    w.write_all(&[
-        0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21,
-    ])?;
+    buf.write_all(&V2_SIGNATURE)?;
+    buf.write_all(&[0x21])?;
+
+    // Validate the TLVs and compute their total length (type: u8, length:
+    // u16 and value).
+    let mut tlvs_len = 0;
+    for tlv in tlvs {
+        let len = tlv.value().len();
+        if let Tlv::UniqueId(_) = tlv {
+            if len > UNIQUE_ID_MAX_LEN {
+                bail!("Unique ID TLV is too long ({len} > {UNIQUE_ID_MAX_LEN})");
+            }
+        }
+        tlvs_len += 3 + len;
+    }
+    // The address block is at most 216 bytes long.
+    let tlvs_len = match u16::try_from(tlvs_len) {
+        Ok(len) if len <= u16::MAX - 216 => len,
+        _ => bail!("TLVs are too long ({tlvs_len} bytes)"),
+    };
 
     // Transport protocol and address family. The highest 4 bits represent
-    // the address family (\x1: AF_INET, \x2: AF_INET6) and the lowest 4
-    // bits the protocol (\x1: SOCK_STREAM).
+    // the address family (\x0: AF_UNSPEC, \x1: AF_INET, \x2: AF_INET6) and
+    // the lowest 4 bits the protocol (\x0: UNSPEC, \x1: SOCK_STREAM).
     //
-    // Followed by the address length. 12 for IPv4 and 36 for IPv6.
-    match (local, peer) {
-        (SocketAddr::V4(_), SocketAddr::V4(_)) => {
-            w.write_all(&[0x11])?;
-            w.write_all(&u16::to_be_bytes(12))?;
+    // Followed by the length of the addresses (0 for AF_UNSPEC, 12 for IPv4
+    // and 36 for IPv6) and of the TLVs.
+    let addrs = normalize(local, peer);
+    match addrs {
+        Some((SocketAddr::V4(_), _)) => {
+            buf.write_all(&[0x11])?;
+            buf.write_all(&u16::to_be_bytes(12 + tlvs_len))?;
+        }
+        Some((SocketAddr::V6(_), _)) => {
+            buf.write_all(&[0x21])?;
+            buf.write_all(&u16::to_be_bytes(36 + tlvs_len))?;
         }
-        (SocketAddr::V6(_), SocketAddr::V6(_)) => {
-            w.write_all(&[0x21])?;
-            w.write_all(&u16::to_be_bytes(36))?;
+        // The addresses have no common family.
+        None => {
+            buf.write_all(&[0x00])?;
+            buf.write_all(&u16::to_be_bytes(tlvs_len))?;
         }
-        _ => bail!("Invalid address types (mixing IPv4 and IPv6)"),
     }
 
     // Now write addresses & ports information.
-    match peer.ip() {
-        IpAddr::V4(addr) => w.write_all(&addr.octets())?,
-        IpAddr::V6(addr) => w.write_all(&addr.octets())?,
+    if let Some((local, peer)) = addrs {
+        match peer.ip() {
+            IpAddr::V4(addr) => buf.write_all(&addr.octets())?,
+            IpAddr::V6(addr) => buf.write_all(&addr.octets())?,
+        }
+        match local.ip() {
+            IpAddr::V4(addr) => buf.write_all(&addr.octets())?,
+            IpAddr::V6(addr) => buf.write_all(&addr.octets())?,
+        }
+        buf.write_all(&u16::to_be_bytes(peer.port()))?;
+        buf.write_all(&u16::to_be_bytes(local.port()))?;
+    }
+
+    // Then the TLVs.
+    let mut crc_offset = None;
+    for tlv in tlvs {
+        let value = tlv.value();
+        buf.write_all(&[tlv.r#type()])?;
+        buf.write_all(&u16::to_be_bytes(value.len() as u16))?;
+        if let Tlv::Crc32c = tlv {
+            crc_offset = Some(buf.len());
+        }
+        buf.write_all(value)?;
     }
-    match local.ip() {
-        IpAddr::V4(addr) => w.write_all(&addr.octets())?,
-        IpAddr::V6(addr) => w.write_all(&addr.octets())?,
+
+    // The checksum is computed over the whole header, with its own value
+    // zeroed.
+    if let Some(offset) = crc_offset {
+        let crc = crc32c(&buf);
+        buf[offset..(offset + 4)].copy_from_slice(&crc.to_be_bytes());
     }
-    w.write_all(&u16::to_be_bytes(peer.port()))?;
-    w.write_all(&u16::to_be_bytes(local.port()))?;
 
+    w.write_all(&buf)?;
     Ok(())
 }
 
+/// Computes the CRC32c (Castagnoli) checksum of `data`.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82f63b78,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+/// Reads an HAProxy protocol header, version 1 or 2, from a reader. Data is
+/// read in chunks, not to issue a read per byte, and the data read past the
+/// header is left in `buf` to be processed as if read from `r`. Returns the
+/// source and destination addresses conveyed by the header, or None if the
+/// header does not carry them (eg. v1 'UNKNOWN' or v2 'LOCAL' headers).
+pub(crate) async fn read_header<R>(
+    r: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin,
+{
+    // The smallest valid header is "PROXY UNKNOWN\r\n" (15 bytes), version 2
+    // headers being at least 16 bytes long.
+    fill(r, buf, 15).await?;
+
+    if buf[..12] == V2_SIGNATURE {
+        read_header_v2(r, buf).await
+    } else if buf.starts_with(b"PROXY ") {
+        read_header_v1(r, buf).await
+    } else {
+        bail!("Invalid HAProxy protocol header (unknown signature)");
+    }
+}
+
+/// Reads from `r` to `buf` until it holds at least `len` bytes. More data can
+/// be read.
+async fn fill<R>(r: &mut R, buf: &mut Vec<u8>, len: usize) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    while buf.len() < len {
+        buf.reserve(cmp::max(len - buf.len(), READ_LEN));
+        if r.read_buf(buf).await? == 0 {
+            bail!("Could not read enough data for the HAProxy protocol header");
+        }
+    }
+    Ok(())
+}
+
+/// Reads the remaining of an HAProxy protocol header version 1, `buf` holding
+/// its beginning.
+async fn read_header_v1<R>(r: &mut R, buf: &mut Vec<u8>) -> Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin,
+{
+    // Read until the end of the header.
+    let len = loop {
+        let end = cmp::min(buf.len(), V1_MAX_LEN);
+        if let Some(pos) = buf[..end].windows(2).position(|w| w == b"\r\n") {
+            break pos + 2;
+        }
+        if buf.len() >= V1_MAX_LEN {
+            bail!("Invalid HAProxy protocol v1 header (too long)");
+        }
+        fill(r, buf, buf.len() + 1).await?;
+    };
+    let header: Vec<u8> = buf.drain(..len).collect();
+
+    let header = str::from_utf8(&header[..len - 2])?;
+    let fields: Vec<&str> = header.split(' ').collect();
+    match fields[..] {
+        [_, "UNKNOWN", ..] => Ok(None),
+        [_, proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let src: IpAddr = src.parse()?;
+            let dst: IpAddr = dst.parse()?;
+            match (proto, src, dst) {
+                ("TCP4", IpAddr::V4(_), IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_), IpAddr::V6(_)) => {
+                }
+                _ => bail!("Invalid HAProxy protocol v1 header (address family mismatch)"),
+            }
+            Ok(Some((
+                SocketAddr::new(src, sport.parse()?),
+                SocketAddr::new(dst, dport.parse()?),
+            )))
+        }
+        _ => bail!("Invalid HAProxy protocol v1 header ({header})"),
+    }
+}
+
+/// Reads the remaining of an HAProxy protocol header version 2, `buf` holding
+/// its beginning.
+async fn read_header_v2<R>(r: &mut R, buf: &mut Vec<u8>) -> Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin,
+{
+    // Get the full fixed-size part of the header.
+    fill(r, buf, 16).await?;
+
+    let (version, command) = (buf[12] >> 4, buf[12] & 0xf);
+    if version != 2 {
+        bail!("Invalid HAProxy protocol v2 header (version {version})");
+    }
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    // Always consume the addresses and TLVs, even if unused.
+    fill(r, buf, 16 + len).await?;
+    let data: Vec<u8> = buf.drain(..16 + len).skip(16).collect();
+
+    match command {
+        // Local, the connection was established by the proxy itself.
+        0x0 => return Ok(None),
+        // Proxy.
+        0x1 => (),
+        x => bail!("Invalid HAProxy protocol v2 header (command {x})"),
+    }
+
+    // Only TCP over IPv4 and IPv6 carry addresses we can use. Others (eg.
+    // AF_UNSPEC or AF_UNIX) are handled as if no address was provided.
+    match family {
+        0x11 if len >= 12 => {
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&data[0..4])?);
+            let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&data[4..8])?);
+            let sport = u16::from_be_bytes([data[8], data[9]]);
+            let dport = u16::from_be_bytes([data[10], data[11]]);
+            Ok(Some((
+                SocketAddr::new(src.into(), sport),
+                SocketAddr::new(dst.into(), dport),
+            )))
+        }
+        0x21 if len >= 36 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&data[0..16])?);
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&data[16..32])?);
+            let sport = u16::from_be_bytes([data[32], data[33]]);
+            let dport = u16::from_be_bytes([data[34], data[35]]);
+            Ok(Some((
+                SocketAddr::new(src.into(), sport),
+                SocketAddr::new(dst.into(), dport),
+            )))
+        }
+        0x11 | 0x21 => bail!("Invalid HAProxy protocol v2 header (addresses too short)"),
+        _ => Ok(None),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str;
 
+    use tokio::io::AsyncReadExt;
+
+    use super::{read_header, Tlv};
+
     #[test]
     fn header_v1() {
         let local = "172.16.99.1:443".parse().unwrap();
@@ -111,15 +415,37 @@ mod tests {
             "PROXY TCP6 1337:42::700 1111:1::42 12345 10443\r\n"
         );
 
+        // IPv4-mapped addresses are converted to IPv4 ones.
+        let local = "[::ffff:172.16.99.1]:443".parse().unwrap();
+        let peer = "[::ffff:10.0.42.132]:1337".parse().unwrap();
+        let mut w = Vec::new();
+        assert!(super::write_header_v1(&mut w, &local, &peer).is_ok());
+        assert_eq!(
+            str::from_utf8(&w).unwrap(),
+            "PROXY TCP4 10.0.42.132 172.16.99.1 1337 443\r\n"
+        );
+
+        let local = "[::ffff:172.16.99.1]:443".parse().unwrap();
+        let peer = "10.0.42.132:1337".parse().unwrap();
+        let mut w = Vec::new();
+        assert!(super::write_header_v1(&mut w, &local, &peer).is_ok());
+        assert_eq!(
+            str::from_utf8(&w).unwrap(),
+            "PROXY TCP4 10.0.42.132 172.16.99.1 1337 443\r\n"
+        );
+
+        // No common family.
         let local = "172.16.99.1:443".parse().unwrap();
         let peer = "[1337:42::700]:12345".parse().unwrap();
         let mut w = Vec::new();
-        assert!(super::write_header_v1(&mut w, &local, &peer).is_err());
+        assert!(super::write_header_v1(&mut w, &local, &peer).is_ok());
+        assert_eq!(str::from_utf8(&w).unwrap(), "PROXY UNKNOWN\r\n");
 
         let local = "[1111:1::42]:10443".parse().unwrap();
         let peer = "10.0.42.132:1337".parse().unwrap();
         let mut w = Vec::new();
-        assert!(super::write_header_v1(&mut w, &local, &peer).is_err());
+        assert!(super::write_header_v1(&mut w, &local, &peer).is_ok());
+        assert_eq!(str::from_utf8(&w).unwrap(), "PROXY UNKNOWN\r\n");
     }
 
     #[test]
@@ -127,7 +453,7 @@ mod tests {
         let local = "172.16.99.1:443".parse().unwrap();
         let peer = "10.0.42.132:1337".parse().unwrap();
         let mut w = Vec::new();
-        assert!(super::write_header_v2(&mut w, &local, &peer).is_ok());
+        assert!(super::write_header_v2(&mut w, &local, &peer, &[]).is_ok());
         assert_eq!(
             &w,
             &[
@@ -139,7 +465,7 @@ mod tests {
         let local = "[1111:1::42]:10443".parse().unwrap();
         let peer = "[1337:42::700]:12345".parse().unwrap();
         let mut w = Vec::new();
-        assert!(super::write_header_v2(&mut w, &local, &peer).is_ok());
+        assert!(super::write_header_v2(&mut w, &local, &peer, &[]).is_ok());
         assert_eq!(
             &w,
             &[
@@ -150,14 +476,259 @@ mod tests {
             ]
         );
 
+        // IPv4-mapped addresses are converted to IPv4 ones.
+        let local = "[::ffff:172.16.99.1]:443".parse().unwrap();
+        let peer = "10.0.42.132:1337".parse().unwrap();
+        let mut w = Vec::new();
+        assert!(super::write_header_v2(&mut w, &local, &peer, &[]).is_ok());
+        assert_eq!(
+            &w,
+            &[
+                0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21, 0x11,
+                0x00, 0x0c, 0x0a, 0x00, 0x2a, 0x84, 0xac, 0x10, 0x63, 0x01, 0x05, 0x39, 0x01, 0xbb
+            ]
+        );
+
+        // No common family, TLVs are still sent.
         let local = "172.16.99.1:443".parse().unwrap();
         let peer = "[1337:42::700]:12345".parse().unwrap();
         let mut w = Vec::new();
-        assert!(super::write_header_v2(&mut w, &local, &peer).is_err());
+        assert!(super::write_header_v2(&mut w, &local, &peer, &[]).is_ok());
+        assert_eq!(
+            &w,
+            &[
+                0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21, 0x00,
+                0x00, 0x00,
+            ]
+        );
 
         let local = "[1111:1::42]:10443".parse().unwrap();
         let peer = "10.0.42.132:1337".parse().unwrap();
         let mut w = Vec::new();
-        assert!(super::write_header_v2(&mut w, &local, &peer).is_err());
+        assert!(super::write_header_v2(&mut w, &local, &peer, &[Tlv::UniqueId(b"42")]).is_ok());
+        assert_eq!(
+            &w,
+            &[
+                0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21, 0x00,
+                0x00, 0x05, 0x05, 0x00, 0x02, 0x34, 0x32,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_header() {
+        let mut w = Vec::new();
+        assert!(super::write_local_header(&mut w, 1).is_ok());
+        assert_eq!(&w, b"PROXY UNKNOWN\r\n");
+        let mut r: &[u8] = &w;
+        assert_eq!(read_header(&mut r, &mut Vec::new()).await.unwrap(), None);
+
+        let mut w = Vec::new();
+        assert!(super::write_local_header(&mut w, 2).is_ok());
+        assert_eq!(
+            &w,
+            &[
+                0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x20, 0x00,
+                0x00, 0x00,
+            ]
+        );
+        let mut r: &[u8] = &w;
+        assert_eq!(read_header(&mut r, &mut Vec::new()).await.unwrap(), None);
+
+        assert!(super::write_local_header(&mut Vec::new(), 3).is_err());
+    }
+
+    #[test]
+    fn crc32c() {
+        assert_eq!(super::crc32c(b""), 0);
+        assert_eq!(super::crc32c(b"123456789"), 0xe3069283);
+    }
+
+    #[test]
+    fn header_v2_tlvs() {
+        let local = "172.16.99.1:443".parse().unwrap();
+        let peer = "10.0.42.132:1337".parse().unwrap();
+        let mut w = Vec::new();
+        assert!(super::write_header_v2(
+            &mut w,
+            &local,
+            &peer,
+            &[
+                Tlv::Authority("example.net"),
+                Tlv::Alpn(b"h2"),
+                Tlv::UniqueId(b"42"),
+            ]
+        )
+        .is_ok());
+        #[rustfmt::skip]
+        assert_eq!(
+            &w,
+            &[
+                0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21, 0x11,
+                0x00, 0x24, 0x0a, 0x00, 0x2a, 0x84, 0xac, 0x10, 0x63, 0x01, 0x05, 0x39, 0x01, 0xbb,
+                // Authority.
+                0x02, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6e, 0x65, 0x74,
+                // ALPN.
+                0x01, 0x00, 0x02, 0x68, 0x32,
+                // Unique ID.
+                0x05, 0x00, 0x02, 0x34, 0x32,
+            ]
+        );
+
+        // The checksum covers the whole header, with its value zeroed.
+        let mut w = Vec::new();
+        assert!(super::write_header_v2(
+            &mut w,
+            &local,
+            &peer,
+            &[Tlv::Authority("example.net"), Tlv::Crc32c]
+        )
+        .is_ok());
+        assert_eq!(w.len(), 16 + 12 + 14 + 7);
+        assert_eq!(&w[14..16], &[0x00, 0x21]);
+        assert_eq!(&w[42..45], &[0x03, 0x00, 0x04]);
+        let crc = u32::from_be_bytes(w[45..49].try_into().unwrap());
+        w[45..49].copy_from_slice(&[0; 4]);
+        assert_eq!(crc, super::crc32c(&w));
+
+        // Unique IDs are limited to 128 bytes.
+        let mut w = Vec::new();
+        assert!(
+            super::write_header_v2(&mut w, &local, &peer, &[Tlv::UniqueId(&[b'0'; 129])]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn read_v1() {
+        let mut r: &[u8] = b"PROXY TCP4 10.0.42.132 172.16.99.1 1337 443\r\n\x16\x03";
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_header(&mut r, &mut buf).await.unwrap(),
+            Some((
+                "10.0.42.132:1337".parse().unwrap(),
+                "172.16.99.1:443".parse().unwrap()
+            ))
+        );
+        // Data read past the header is kept.
+        assert_eq!(buf, b"\x16\x03");
+
+        // Headers can be split across reads.
+        let mut r = (&b"PROXY TCP4 10.0.42.132 "[..])
+            .chain(&b"172.16.99.1 1337 443\r"[..])
+            .chain(&b"\n\x16\x03"[..]);
+        let mut buf = Vec::new();
+        assert!(read_header(&mut r, &mut buf).await.unwrap().is_some());
+        assert_eq!(buf, b"\x16\x03");
+
+        let mut r: &[u8] = b"PROXY TCP6 1337:42::700 1111:1::42 12345 10443\r\n";
+        assert_eq!(
+            read_header(&mut r, &mut Vec::new()).await.unwrap(),
+            Some((
+                "[1337:42::700]:12345".parse().unwrap(),
+                "[1111:1::42]:10443".parse().unwrap()
+            ))
+        );
+
+        let mut r: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut r, &mut Vec::new()).await.unwrap(), None);
+        let mut r: &[u8] = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(read_header(&mut r, &mut Vec::new()).await.unwrap(), None);
+
+        for header in [
+            &b"PROXY TCP4 10.0.42.132 1111:1::42 1337 443\r\n"[..],
+            b"PROXY TCP6 10.0.42.132 172.16.99.1 1337 443\r\n",
+            b"PROXY TCP4 10.0.42.132 172.16.99.1 1337\r\n",
+            b"PROXY TCP4 10.0.42.132 172.16.99.1 1337 99999\r\n",
+            b"PROXY UDP4 10.0.42.132 172.16.99.1 1337 443\r\n",
+            b"PROXY TCP4 10.0.42.132 172.16.99.1 1337 443",
+            &[b'0'; 200],
+            b"\x16\x03\x01\x00\xc8\x01\x00\x00\xc4\x03\x03\x00\x00\x00\x00\x00",
+        ] {
+            let mut r: &[u8] = header;
+            assert!(read_header(&mut r, &mut Vec::new()).await.is_err());
+        }
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.extend_from_slice(&[b'1'; 200]);
+        let mut r: &[u8] = &long;
+        assert!(read_header(&mut r, &mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v2() {
+        for (local, peer) in [
+            ("172.16.99.1:443", "10.0.42.132:1337"),
+            ("[1111:1::42]:10443", "[1337:42::700]:12345"),
+        ] {
+            let local = local.parse().unwrap();
+            let peer = peer.parse().unwrap();
+            let mut w = Vec::new();
+            super::write_header_v2(&mut w, &local, &peer, &[]).unwrap();
+            w.extend_from_slice(b"\x16\x03");
+
+            let mut r: &[u8] = &w;
+            let mut buf = Vec::new();
+            assert_eq!(
+                read_header(&mut r, &mut buf).await.unwrap(),
+                Some((peer, local))
+            );
+            assert_eq!(buf, b"\x16\x03");
+        }
+
+        // Headers can be split across reads.
+        let mut w = Vec::new();
+        let (local, peer) = (
+            "172.16.99.1:443".parse().unwrap(),
+            "10.0.42.132:1337".parse().unwrap(),
+        );
+        super::write_header_v2(&mut w, &local, &peer, &[]).unwrap();
+        let mut r = (&w[..14]).chain(&w[14..20]).chain(&w[20..]);
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_header(&mut r, &mut buf).await.unwrap(),
+            Some((peer, local))
+        );
+        assert!(buf.is_empty());
+
+        // TLVs are skipped.
+        let mut header = super::V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[
+            0x21, 0x11, 0x00, 0x10, 0x0a, 0x00, 0x2a, 0x84, 0xac, 0x10, 0x63, 0x01, 0x05, 0x39,
+            0x01, 0xbb, 0x04, 0x00, 0x01, 0x00, 0x16,
+        ]);
+        let mut r: &[u8] = &header;
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_header(&mut r, &mut buf).await.unwrap(),
+            Some((
+                "10.0.42.132:1337".parse().unwrap(),
+                "172.16.99.1:443".parse().unwrap()
+            ))
+        );
+        assert_eq!(buf, b"\x16");
+
+        // Local command and unspecified family.
+        let mut header = super::V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00, 0x16]);
+        let mut r: &[u8] = &header;
+        let mut buf = Vec::new();
+        assert_eq!(read_header(&mut r, &mut buf).await.unwrap(), None);
+        assert_eq!(buf, b"\x16");
+        let mut header = super::V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x00, 0x00, 0x00]);
+        let mut r: &[u8] = &header;
+        assert_eq!(read_header(&mut r, &mut Vec::new()).await.unwrap(), None);
+
+        // Invalid version, command and address length.
+        for bytes in [
+            [0x11, 0x11, 0x00, 0x00],
+            [0x22, 0x11, 0x00, 0x00],
+            [0x21, 0x11, 0x00, 0x04],
+        ] {
+            let mut header = super::V2_SIGNATURE.to_vec();
+            header.extend_from_slice(&bytes);
+            header.extend_from_slice(&[0; 4]);
+            let mut r: &[u8] = &header;
+            assert!(read_header(&mut r, &mut Vec::new()).await.is_err());
+        }
     }
 }
@@ -1,12 +1,16 @@
-use std::{cmp, io::Read, mem};
+use std::{cmp, mem};
 
 use anyhow::{bail, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::Instant,
+};
 
 /// Fast buffer reader never removing read data from its internal buffer. It
-/// does not offer traditional accessors from io::Read and instead returns
+/// does not offer traditional accessors from AsyncRead and instead returns
 /// structured data references to its inner buffer, saving a copy.
-pub(crate) struct ReaderBuf<R: Read> {
-    /// Inner reader, implementing io::Read.
+pub(crate) struct ReaderBuf<R: AsyncRead + Unpin> {
+    /// Inner reader, implementing AsyncRead.
     inner: R,
     /// Inner buffer, holding the data (both already read + buffered).
     buffer: Vec<u8>,
@@ -16,9 +20,37 @@ pub(crate) struct ReaderBuf<R: Read> {
     /// buffer. Low values might impact performances when the data is not
     /// already mapped into memory.
     min_read: usize,
+    /// Time after which reads from the inner reader stop waiting for data.
+    /// None means reads can wait forever.
+    read_deadline: Option<Instant>,
+    /// Frames the data is read from, if any. See `set_framing`.
+    frames: Option<Frames>,
 }
 
-impl<R: Read> ReaderBuf<R> {
+/// Description of the frames data is split in, eg. records.
+#[derive(Clone)]
+pub(crate) struct Framing {
+    /// Length of the frame headers.
+    pub(crate) header_len: usize,
+    /// Parses a frame header and returns the length of the frame payload.
+    pub(crate) parse_header: fn(&[u8]) -> Result<usize>,
+    /// Maximum length of the frames read, headers included.
+    pub(crate) max_len: usize,
+}
+
+/// State of the frames being read.
+#[derive(Clone)]
+struct Frames {
+    framing: Framing,
+    /// Data as read from the inner reader, frame headers included.
+    raw: Vec<u8>,
+    /// Position in `raw` of the data not yet handled.
+    pos: usize,
+    /// Payload length left in the current frame.
+    left: usize,
+}
+
+impl<R: AsyncRead + Unpin> ReaderBuf<R> {
     /// Create a new ReaderBuf with default values.
     ///
     /// Warning: min_read is initialized to 0.
@@ -29,6 +61,8 @@ impl<R: Read> ReaderBuf<R> {
             buffer: Vec::new(),
             cursor: 0,
             min_read: 0,
+            read_deadline: None,
+            frames: None,
         }
     }
 
@@ -43,6 +77,24 @@ impl<R: Read> ReaderBuf<R> {
             buffer: Vec::with_capacity(capacity),
             cursor: 0,
             min_read: 0,
+            read_deadline: None,
+            frames: None,
+        }
+    }
+
+    /// Create a new ReaderBuf with an explicit inner buffer capacity, its
+    /// buffer holding `data` already read from the inner reader.
+    ///
+    /// Warning: min_read is initialized to 0.
+    pub(crate) fn with_data(capacity: usize, mut data: Vec<u8>, inner: R) -> Self {
+        data.reserve(capacity.saturating_sub(data.len()));
+        Self {
+            inner,
+            buffer: data,
+            cursor: 0,
+            min_read: 0,
+            read_deadline: None,
+            frames: None,
         }
     }
 
@@ -68,19 +120,53 @@ impl<R: Read> ReaderBuf<R> {
         self.min_read = len;
     }
 
-    /// Returns a reference to the start of the inner buffer.
+    /// Set the time after which reads from the inner reader stop waiting for
+    /// data, for all reads combined. When a read times out, it is handled as if
+    /// no data was available.
+    pub(crate) fn set_read_deadline(&mut self, deadline: Option<Instant>) {
+        self.read_deadline = deadline;
+    }
+
+    /// Read the data from the payload of frames described by `framing`, eg.
+    /// to reassemble a message split across records. Frame headers are
+    /// handled as the data is read, and only when more data is needed, so
+    /// what follows the data read is never looked at. Reading frames longer
+    /// than `framing.max_len` in total is an error.
+    ///
+    /// Must be called before reading any data.
+    pub(crate) fn set_framing(&mut self, framing: Framing) {
+        self.frames = Some(Frames {
+            framing,
+            raw: mem::take(&mut self.buffer),
+            pos: 0,
+            left: 0,
+        });
+        self.cursor = 0;
+    }
+
+    /// Returns a reference to the start of the inner buffer. When reading
+    /// frames, this is the data as read from the inner reader, frame headers
+    /// included.
     pub(crate) fn buf(&self) -> &[u8] {
-        &self.buffer
+        match &self.frames {
+            Some(frames) => &frames.raw,
+            None => &self.buffer,
+        }
+    }
+
+    /// Length of the data already read, frame headers excluded.
+    pub(crate) fn pos(&self) -> usize {
+        self.cursor
     }
 
     /// Read at most `len` bytes (advancing the inner cursor and filling the
     /// inner buffer if needed) and returns a pointer to a byte array to access
     /// the data read).
-    pub(crate) fn read(&mut self, len: usize) -> Result<&[u8]> {
+    pub(crate) async fn read(&mut self, len: usize) -> Result<&[u8]> {
         let diff = len.saturating_sub(self.headlen());
         let len = match diff {
             x if x > 0 => {
-                let read = self.fill_buffer(diff)?;
+                let read = self.fill_buffer(diff).await?;
                 // read could be > diff, if diff < self.min_read.
                 len - diff.saturating_sub(read)
             }
@@ -99,10 +185,10 @@ impl<R: Read> ReaderBuf<R> {
     /// data read.
     ///
     /// Returns an error if not enough data could be read.
-    pub(crate) fn read_exact(&mut self, len: usize) -> Result<&[u8]> {
+    pub(crate) async fn read_exact(&mut self, len: usize) -> Result<&[u8]> {
         let diff = len.saturating_sub(self.headlen());
         if diff > 0 {
-            self.fill_buffer_exact(diff)?;
+            self.fill_buffer_exact(diff).await?;
         }
 
         let ptr = &self.buffer[self.cursor..(self.cursor + len)];
@@ -116,10 +202,10 @@ impl<R: Read> ReaderBuf<R> {
     /// `T` to access the data read.
     ///
     /// Returns an error if not enough data could be read.
-    pub(crate) fn read_as<T>(&mut self) -> Result<&T> {
+    pub(crate) async fn read_as<T: 'static>(&mut self) -> Result<&T> {
         let diff = mem::size_of::<T>().saturating_sub(self.headlen());
         if diff > 0 {
-            self.fill_buffer_exact(diff)?;
+            self.fill_buffer_exact(diff).await?;
         }
 
         let ptr: &T = unsafe { mem::transmute(&self.buffer[self.cursor]) };
@@ -130,43 +216,83 @@ impl<R: Read> ReaderBuf<R> {
 
     /// Length of the inner buffer, aka. read + unread data.
     pub(crate) fn len(&self) -> usize {
-        self.buffer.len()
+        self.buf().len()
     }
 
     /// Length of the head in the inner buffer, aka. unread data.
     fn headlen(&self) -> usize {
-        self.len() - self.cursor
+        self.buffer.len() - self.cursor
     }
 
     /// Read at least `requested_len` bytes to the internal buffer an return how
     /// many bytes were read.
-    fn fill_buffer(&mut self, requested_len: usize) -> Result<usize> {
+    async fn fill_buffer(&mut self, requested_len: usize) -> Result<usize> {
+        if self.frames.is_some() {
+            return self.fill_buffer_from_frames(requested_len).await;
+        }
+
         // Compute how much we'd like to read.
         let len = cmp::max(requested_len, self.min_read);
-        let end = self.len();
+        read_to(&mut self.inner, &mut self.buffer, len, self.read_deadline).await
+    }
 
-        // Resize the internal buffer to accept the additional data.
-        self.buffer.resize(self.buffer.len() + len, 0);
+    /// Read at least `requested_len` bytes of frame payloads to the internal
+    /// buffer, as long as they are available, and return how many bytes were
+    /// read. Frames are only read up to the one holding the requested data.
+    async fn fill_buffer_from_frames(&mut self, requested_len: usize) -> Result<usize> {
+        let Self {
+            inner,
+            buffer,
+            min_read,
+            read_deadline,
+            frames,
+            ..
+        } = self;
+        let frames = frames.as_mut().unwrap();
+        let header_len = frames.framing.header_len;
+
+        let mut read = 0;
+        while read < requested_len {
+            let available = frames.raw.len() - frames.pos;
+
+            // Start of a new frame, parse its header.
+            if frames.left == 0 {
+                if available < header_len {
+                    let len = cmp::max(header_len - available, *min_read);
+                    if read_to(inner, &mut frames.raw, len, *read_deadline).await? == 0 {
+                        break;
+                    }
+                    continue;
+                }
 
-        // Try to read `len` bytes.
-        let read = match self.inner.read(&mut self.buffer[end..]) {
-            Ok(read) => read,
-            Err(e) => {
-                match e.kind() {
-                    // Special case if the read would block. This means we can't
-                    // read data right now, so just report that.
-                    std::io::ErrorKind::WouldBlock => return Ok(0),
-                    _ => return Err(e.into()),
+                let header = &frames.raw[frames.pos..(frames.pos + header_len)];
+                frames.left = (frames.framing.parse_header)(header)?;
+                frames.pos += header_len;
+
+                let len = frames.pos + frames.left;
+                if len > frames.framing.max_len {
+                    bail!(
+                        "Frames exceed the maximum length ({len} > {})",
+                        frames.framing.max_len
+                    );
                 }
+                continue;
             }
-        };
 
-        // If we read less than requested, truncate the internal buffer. This is
-        // mandatory as the previous `resize()` call also modified the buffer
-        // length.
-        let diff = len.saturating_sub(read);
-        if diff > 0 {
-            self.buffer.truncate(self.buffer.len() - diff);
+            if available == 0 {
+                let len = cmp::max(cmp::min(frames.left, requested_len - read), *min_read);
+                if read_to(inner, &mut frames.raw, len, *read_deadline).await? == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            // Move what we have of the current frame payload to the buffer.
+            let len = cmp::min(available, frames.left);
+            buffer.extend_from_slice(&frames.raw[frames.pos..(frames.pos + len)]);
+            frames.pos += len;
+            frames.left -= len;
+            read += len;
         }
 
         Ok(read)
@@ -174,22 +300,75 @@ impl<R: Read> ReaderBuf<R> {
 
     /// Read `requested_len` bytes to the internal buffer an return an error if
     /// the underlying reader couldn't provide the requested read lenght.
-    fn fill_buffer_exact(&mut self, len: usize) -> Result<()> {
-        let read = self.fill_buffer(len)?;
-        if read < len {
-            bail!("Could not read enough data from the inner reader");
+    ///
+    /// Data can be split across multiple reads, keep on reading until we get
+    /// enough of it or the inner reader can't provide more.
+    async fn fill_buffer_exact(&mut self, len: usize) -> Result<()> {
+        let mut left = len;
+        while left > 0 {
+            let read = self.fill_buffer(left).await?;
+            if read == 0 {
+                bail!("Could not read enough data from the inner reader");
+            }
+            left = left.saturating_sub(read);
         }
         Ok(())
     }
 }
 
-impl<R: Read + Clone> Clone for ReaderBuf<R> {
+/// Read at most `len` bytes from `inner` to the end of `buffer` and return how
+/// many bytes were read.
+async fn read_to<R: AsyncRead + Unpin>(
+    inner: &mut R,
+    buffer: &mut Vec<u8>,
+    len: usize,
+    deadline: Option<Instant>,
+) -> Result<usize> {
+    let end = buffer.len();
+
+    // Resize the buffer to accept the additional data.
+    buffer.resize(end + len, 0);
+
+    // Try to read `len` bytes.
+    let read = inner.read(&mut buffer[end..]);
+    let read = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, read).await {
+            Ok(read) => read,
+            // Handle timeouts as if no data could be read.
+            Err(_) => Ok(0),
+        },
+        None => read.await,
+    };
+    let read = match read {
+        Ok(read) => read,
+        Err(e) => {
+            // Do not leave the buffer resized on errors.
+            buffer.truncate(end);
+            match e.kind() {
+                // Special case if the read would block. This means we can't
+                // read data right now, so just report that.
+                std::io::ErrorKind::WouldBlock => return Ok(0),
+                _ => return Err(e.into()),
+            }
+        }
+    };
+
+    // If we read less than requested, truncate the buffer. This is mandatory
+    // as the previous `resize()` call also modified the buffer length.
+    buffer.truncate(end + read);
+
+    Ok(read)
+}
+
+impl<R: AsyncRead + Unpin + Clone> Clone for ReaderBuf<R> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
             buffer: self.buffer.clone(),
             cursor: self.cursor,
             min_read: self.min_read,
+            read_deadline: self.read_deadline,
+            frames: self.frames.clone(),
         }
     }
 }
@@ -203,6 +382,8 @@ impl<'a> ReaderBuf<&'a [u8]> {
             buffer: Vec::new(),
             cursor: 0,
             min_read: 0,
+            read_deadline: None,
+            frames: None,
         }
     }
 }
@@ -211,8 +392,8 @@ impl<'a> ReaderBuf<&'a [u8]> {
 mod tests {
     use super::ReaderBuf as B;
 
-    #[test]
-    fn reader() {
+    #[tokio::test]
+    async fn reader() {
         let data: Vec<u8> = (1..=30).collect();
         let mut rb = B::from_bytes(&data);
 
@@ -220,42 +401,100 @@ mod tests {
         assert_eq!(rb.buf(), &[] as &[u8]);
 
         // Reading 3 bytes using read_exact.
-        assert_eq!(rb.read_exact(3).unwrap(), &(1..=3).collect::<Vec<u8>>());
+        assert_eq!(
+            rb.read_exact(3).await.unwrap(),
+            &(1..=3).collect::<Vec<u8>>()
+        );
         assert_eq!(rb.buf(), &(1..=3).collect::<Vec<u8>>());
 
         // Reading 7 bytes using read.
-        assert_eq!(rb.read(7).unwrap(), &(4..=10).collect::<Vec<u8>>());
+        assert_eq!(rb.read(7).await.unwrap(), &(4..=10).collect::<Vec<u8>>());
         assert_eq!(rb.buf(), &(1..=10).collect::<Vec<u8>>());
 
         // Setting min read.
         rb.set_min_read(12);
-        assert_eq!(rb.read(5).unwrap(), &(11..=15).collect::<Vec<u8>>());
+        assert_eq!(rb.read(5).await.unwrap(), &(11..=15).collect::<Vec<u8>>());
         assert_eq!(rb.buf(), &(1..=22).collect::<Vec<u8>>());
 
         // Read should still be within the buffered data.
-        assert_eq!(rb.read(7).unwrap(), &(16..=22).collect::<Vec<u8>>());
+        assert_eq!(rb.read(7).await.unwrap(), &(16..=22).collect::<Vec<u8>>());
         assert_eq!(rb.buf(), &(1..=22).collect::<Vec<u8>>());
 
         // Trying to read more than the available data (7 bytes left).
-        assert_eq!(rb.read(10).unwrap(), &(23..=30).collect::<Vec<u8>>());
+        assert_eq!(rb.read(10).await.unwrap(), &(23..=30).collect::<Vec<u8>>());
         assert_eq!(rb.buf(), &(1..=30).collect::<Vec<u8>>());
 
         // No data left.
-        assert_eq!(rb.read(1).unwrap(), &[] as &[u8]);
-        assert!(rb.read_exact(1).is_err());
+        assert_eq!(rb.read(1).await.unwrap(), &[] as &[u8]);
+        assert!(rb.read_exact(1).await.is_err());
     }
 
-    #[test]
-    fn exact_read_and_min_sz() {
+    #[tokio::test]
+    async fn exact_read_and_min_sz() {
         let data: Vec<u8> = (1..=5).collect();
         let mut rb = B::from_bytes(&data);
         rb.set_min_read(12);
 
         // Even though the min read size is > 5, we only want an error if the
         // exact read size cannot be read.
-        assert_eq!(rb.read_exact(5).unwrap(), &(1..=5).collect::<Vec<u8>>());
+        assert_eq!(
+            rb.read_exact(5).await.unwrap(),
+            &(1..=5).collect::<Vec<u8>>()
+        );
 
         // Now it can fail.
-        assert!(rb.read_exact(1).is_err());
+        assert!(rb.read_exact(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_deadline() {
+        use std::time::Duration;
+
+        use tokio::{io::AsyncWriteExt, time::Instant};
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut rb = B::new(server);
+        rb.set_read_deadline(Some(Instant::now() + Duration::from_millis(100)));
+
+        // Data sent before the deadline can be read.
+        client.write_all(&[1, 2, 3]).await.unwrap();
+        assert_eq!(rb.read_exact(3).await.unwrap(), &[1, 2, 3]);
+
+        // Reads do not wait past the deadline, even though the peer is still
+        // connected.
+        assert_eq!(rb.read(1).await.unwrap(), &[] as &[u8]);
+        assert!(Instant::now() >= rb.read_deadline.unwrap());
+        assert!(rb.read_exact(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn framing() {
+        use super::Framing;
+
+        // Frames with a 1 byte header holding the payload length.
+        let framing = Framing {
+            header_len: 1,
+            parse_header: |header| Ok(header[0] as usize),
+            max_len: 10,
+        };
+        let data = [2, 1, 2, 0, 3, 3, 4, 5, 0xff];
+        let mut rb = B::from_bytes(&data);
+        rb.set_framing(framing.clone());
+
+        // Payloads are read across frames, empty ones included, and the
+        // following frame headers are only parsed when needed.
+        assert_eq!(rb.read_exact(3).await.unwrap(), &[1, 2, 3]);
+        assert_eq!(rb.pos(), 3);
+        assert_eq!(rb.read(2).await.unwrap(), &[4, 5]);
+        assert_eq!(rb.buf(), &data[..8]);
+        assert_eq!(rb.len(), 8);
+
+        // The next frame goes past the maximum length.
+        assert!(rb.read(1).await.is_err());
+
+        // Truncated frames.
+        let mut rb = B::from_bytes(&data[..6]);
+        rb.set_framing(framing);
+        assert!(rb.read_exact(4).await.is_err());
     }
 }
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    access_log,
+    context::{ConnectionId, ReqContext},
+};
+
+/// Connections currently handled, by identifier.
+static REGISTRY: Lazy<Mutex<HashMap<ConnectionId, Entry>>> = Lazy::new(Default::default);
+
+/// Registry entry of a single connection.
+struct Entry {
+    /// Access log record of the connection, holding what we know about it.
+    record: Arc<Mutex<access_log::Record>>,
+    /// Cancelled to close the connection.
+    kill: CancellationToken,
+}
+
+/// Keeps a connection in the registry, for as long as it lives.
+pub(crate) struct Registration(ConnectionId);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Adds a connection to the registry. The connection is expected to close
+/// once `kill` is cancelled. It stays registered until the returned value is
+/// dropped.
+pub(crate) fn register(context: &ReqContext, kill: CancellationToken) -> Registration {
+    REGISTRY.lock().unwrap().insert(
+        context.id,
+        Entry {
+            record: Arc::clone(&context.access),
+            kill,
+        },
+    );
+    Registration(context.id)
+}
+
+/// Snapshot of a registered connection.
+#[derive(Debug, Serialize)]
+pub(crate) struct Connection {
+    pub(crate) id: String,
+    pub(crate) peer: String,
+    pub(crate) local: String,
+    pub(crate) sni: Option<String>,
+    pub(crate) route: Option<String>,
+    pub(crate) backend: Option<String>,
+    /// Time since the connection was accepted, in seconds.
+    pub(crate) age: f64,
+    pub(crate) bytes_to_backend: u64,
+    pub(crate) bytes_to_client: u64,
+}
+
+/// Lists the registered connections, oldest first.
+pub(crate) fn list() -> Vec<Connection> {
+    let registry = REGISTRY.lock().unwrap();
+    let mut ids: Vec<&ConnectionId> = registry.keys().collect();
+    ids.sort();
+
+    ids.into_iter()
+        .map(|id| {
+            let record = registry[id].record.lock().unwrap();
+            Connection {
+                id: id.to_string(),
+                peer: record.peer.clone(),
+                local: record.local.clone(),
+                sni: record.sni.clone(),
+                route: record.route.clone(),
+                backend: record.backend.clone(),
+                age: record.elapsed().as_secs_f64(),
+                bytes_to_backend: record.bytes_to_backend,
+                bytes_to_client: record.bytes_to_client,
+            }
+        })
+        .collect()
+}
+
+/// Closes a connection given its identifier. Returns false if no such
+/// connection is registered.
+pub(crate) fn kill(id: &str) -> bool {
+    let id = match id.parse::<ConnectionId>() {
+        Ok(id) => id,
+        Err(_) => return false,
+    };
+    match REGISTRY.lock().unwrap().get(&id) {
+        Some(entry) => {
+            entry.kill.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Closes all the connections proxied to a backend of a route. Returns the
+/// number of connections closed.
+pub(crate) fn kill_backend(route: &str, backend: &str) -> usize {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|entry| {
+            let record = entry.record.lock().unwrap();
+            record.route.as_deref() == Some(route) && record.backend.as_deref() == Some(backend)
+        })
+        .map(|entry| entry.kill.cancel())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry() {
+        let local = "127.0.0.1:443".parse().unwrap();
+        let a = ReqContext::from(local, "10.0.42.1:1337".parse().unwrap());
+        let b = ReqContext::from(local, "10.0.42.2:1337".parse().unwrap());
+        let (a, b) = (a.borrow(), b.borrow());
+        let (kill_a, kill_b) = (CancellationToken::new(), CancellationToken::new());
+
+        let registration_a = register(&a, kill_a.clone());
+        let registration_b = register(&b, kill_b.clone());
+        {
+            let mut record = b.access.lock().unwrap();
+            record.route = Some("example.net".to_string());
+            record.backend = Some("127.0.0.1:8443".to_string());
+            record.bytes_to_client = 42;
+        }
+
+        // Other tests can register connections too.
+        let list: Vec<_> = list()
+            .into_iter()
+            .filter(|c| c.id == a.id.to_string() || c.id == b.id.to_string())
+            .collect();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].peer, "10.0.42.1:1337");
+        assert_eq!(list[1].peer, "10.0.42.2:1337");
+        assert_eq!(list[1].route.as_deref(), Some("example.net"));
+        assert_eq!(list[1].bytes_to_client, 42);
+
+        assert_eq!(kill_backend("example.net", "127.0.0.1:8443"), 1);
+        assert!(!kill_a.is_cancelled());
+        assert!(kill_b.is_cancelled());
+
+        assert!(!kill("not-an-id"));
+        assert!(kill(&a.id.to_string()));
+        assert!(kill_a.is_cancelled());
+
+        // Connections are unregistered once closed.
+        drop(registration_a);
+        assert!(!kill(&a.id.to_string()));
+        drop(registration_b);
+        assert!(!kill(&b.id.to_string()));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use log::{debug, error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{config::Config, health};
+
+/// Configuration shared between listeners. New connections use the latest
+/// configuration, while in-flight ones keep a reference to the configuration
+/// that was active when they were accepted.
+pub(crate) type SharedConfig = Arc<ArcSwap<Config>>;
+
+/// Re-parses and validates the configuration file, and if valid swaps it in
+/// for new connections. On error the current configuration stays active.
+pub(crate) fn reload(path: &Path, config: &SharedConfig) -> Result<()> {
+    let mut new = Config::from_file(path.to_path_buf())?;
+    let current = config.load();
+
+    // Listeners are not restarted on reloads.
+    if new.bind_https != current.bind_https
+        || new.bind_http != current.bind_http
+        || new.bind_metrics != current.bind_metrics
+        || new.need_http() != current.need_http()
+    {
+        warn!("Changes to the listeners configuration require a restart to be applied");
+    }
+    if new.access_log != current.access_log {
+        warn!("Changes to the access log configuration require a restart to be applied");
+    }
+    if new.bind_admin != current.bind_admin {
+        warn!("Changes to the admin interface configuration require a restart to be applied");
+    }
+
+    // Backends keep their administrative state across reloads, and limiters
+    // their active connections.
+    new.inherit_state(&current);
+
+    config.store(Arc::new(new));
+    health::start(config);
+    Ok(())
+}
+
+/// Reloads the configuration each time a SIGHUP is received. The signal
+/// handler is installed before returning, the reloads themselves are handled
+/// in a background task.
+pub(crate) fn watch_sighup(path: PathBuf, config: SharedConfig) -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading the configuration");
+            match reload(&path, &config) {
+                Ok(_) => info!("Configuration reloaded"),
+                Err(e) => {
+                    error!("Could not reload the configuration, keeping the current one: {e}")
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Reloads the configuration each time the configuration file is modified,
+/// checking for modifications every `interval`.
+pub(crate) async fn watch_file(path: PathBuf, config: SharedConfig, interval: Duration) {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    let mut last = modified(&path);
+
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        let current = modified(&path);
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+
+        debug!("Configuration file was modified, reloading it");
+        match reload(&path, &config) {
+            Ok(_) => info!("Configuration reloaded"),
+            Err(e) => error!("Could not reload the configuration, keeping the current one: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn reload() {
+        let path =
+            std::env::temp_dir().join(format!("sniproxy-reload-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "
+routes:
+  - domains:
+      - example.net
+    backend:
+      address: 127.0.0.1:443
+        ",
+        )
+        .unwrap();
+
+        let config: SharedConfig = Arc::new(ArcSwap::from_pointee(
+            Config::from_file(path.clone()).unwrap(),
+        ));
+        let old = config.load_full();
+        assert!(config.load().get_route("example.net").is_some());
+        assert!(config.load().get_route("example.com").is_none());
+
+        // Valid configuration, it should be swapped in.
+        fs::write(
+            &path,
+            "
+routes:
+  - domains:
+      - example.com
+    backend:
+      address: 127.0.0.1:443
+        ",
+        )
+        .unwrap();
+        assert!(super::reload(&path, &config).is_ok());
+        assert!(config.load().get_route("example.net").is_none());
+        assert!(config.load().get_route("example.com").is_some());
+
+        // The previous configuration is still usable by its holders.
+        assert!(old.get_route("example.net").is_some());
+
+        // Invalid configuration, the current one should be kept.
+        fs::write(
+            &path,
+            "
+routes:
+  - domains:
+      - example.org
+        ",
+        )
+        .unwrap();
+        assert!(super::reload(&path, &config).is_err());
+        assert!(config.load().get_route("example.org").is_none());
+        assert!(config.load().get_route("example.com").is_some());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
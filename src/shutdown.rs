@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Termination signals handler.
+pub(crate) struct Signals {
+    sigterm: Signal,
+    sigint: Signal,
+}
+
+impl Signals {
+    /// Installs the SIGTERM and SIGINT handlers. From now on those signals are
+    /// not terminating the process anymore and must be handled using
+    /// `Signals::recv`.
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Waits for a termination signal and returns its name.
+    pub(crate) async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigterm.recv() => "SIGTERM",
+            _ = self.sigint.recv() => "SIGINT",
+        }
+    }
+}
+
+/// Gracefully shuts down the proxy: listeners are notified using `shutdown`
+/// and stop accepting new connections, then connections tracked by `tracker`
+/// are given up to `timeout` to finish. A second termination signal ends the
+/// drain early.
+///
+/// Connections still running when returning are force-closed when the process
+/// exits.
+pub(crate) async fn drain(
+    shutdown: &CancellationToken,
+    tracker: &TaskTracker,
+    signals: &mut Signals,
+    timeout: Duration,
+) {
+    shutdown.cancel();
+    tracker.close();
+    info!(
+        "Stopped accepting new connections, {} connection(s) still active",
+        tracker.len()
+    );
+
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let mut progress = tokio::time::interval(Duration::from_secs(5));
+    progress.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = tracker.wait() => {
+                info!("All connections were drained");
+                return;
+            }
+            _ = &mut deadline => {
+                warn!("Drain deadline reached, force-closing {} connection(s)", tracker.len());
+                return;
+            }
+            signal = signals.recv() => {
+                warn!("Received {signal} while draining, force-closing {} connection(s)", tracker.len());
+                return;
+            }
+            _ = progress.tick() => {
+                info!("Draining, {} connection(s) still active", tracker.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arc_swap::ArcSwap;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        config::{Config, Listener},
+        tcp,
+    };
+
+    #[tokio::test]
+    async fn drained() {
+        let (shutdown, tracker) = (CancellationToken::new(), TaskTracker::new());
+        tracker.spawn(tokio::time::sleep(Duration::from_millis(50)));
+
+        // Returns as soon as the connections are done.
+        let start = Instant::now();
+        drain(
+            &shutdown,
+            &tracker,
+            &mut Signals::new().unwrap(),
+            Duration::from_secs(10),
+        )
+        .await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(shutdown.is_cancelled());
+        assert!(tracker.is_empty());
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        let (shutdown, tracker) = (CancellationToken::new(), TaskTracker::new());
+        tracker.spawn(tokio::time::sleep(Duration::from_secs(60)));
+
+        // Gives up once the deadline is reached.
+        let start = Instant::now();
+        let timeout = Duration::from_millis(100);
+        drain(&shutdown, &tracker, &mut Signals::new().unwrap(), timeout).await;
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(tracker.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stop_accepting() {
+        let bind = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let config = Config::from_str(
+            "
+routes:
+  - domains:
+      - example.net
+    backend:
+      address: 127.0.0.1:443
+            ",
+        )
+        .unwrap();
+
+        let (shutdown, tracker) = (CancellationToken::new(), TaskTracker::new());
+        let listener = tokio::spawn(tcp::listen_and_proxy(
+            Arc::new(ArcSwap::from_pointee(config)),
+            Listener::Https,
+            bind,
+            tcp::tls::handle_stream,
+            shutdown.clone(),
+            tracker.clone(),
+        ));
+
+        // Wait for the listener to accept connections.
+        let mut accepted = false;
+        for _ in 0..100 {
+            if TcpStream::connect(bind).await.is_ok() {
+                accepted = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(accepted);
+
+        // The listener is closed once shutting down.
+        drain(
+            &shutdown,
+            &tracker,
+            &mut Signals::new().unwrap(),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(listener.await.unwrap().is_ok());
+        assert!(TcpStream::connect(bind).await.is_err());
+    }
+}
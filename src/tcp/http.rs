@@ -1,35 +1,41 @@
-use std::{
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream},
-    sync::Arc,
-};
+use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::{bail, Result};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::Instant,
+};
 
-use crate::{config::Config, context, http, reader::ReaderBuf};
+use crate::{config::Config, context, http, metrics, reader::ReaderBuf};
 
 /// Handle TCP/HTTP connections.
-pub(crate) async fn handle_stream(config: Arc<Config>, stream: TcpStream) -> Result<()> {
+pub(crate) async fn handle_stream(
+    config: Arc<Config>,
+    stream: TcpStream,
+    data: Vec<u8>,
+    deadline: Instant,
+) -> Result<()> {
     // 8KB is the limit size on many web servers.
-    try_redirect(
-        &config,
-        &context::peer_addr()?,
-        ReaderBuf::with_capacity(8192, stream),
-    )
+    let mut rb = ReaderBuf::with_data(8192, data, stream);
+    rb.set_read_deadline(Some(deadline));
+
+    try_redirect(&config, &context::peer_addr()?, rb).await
 }
 
 #[inline(always)]
-fn try_redirect<R>(config: &Config, client: &SocketAddr, mut rb: ReaderBuf<R>) -> Result<()>
+async fn try_redirect<R>(config: &Config, client: &SocketAddr, mut rb: ReaderBuf<R>) -> Result<()>
 where
-    R: Read + Write,
+    R: AsyncRead + AsyncWrite + Unpin,
 {
     // First check if the connection looks like an HTTP one. We only need 5
     // bytes for `http::is_http`.
-    rb.read(5)?;
+    rb.read(5).await?;
     if !http::is_http(&rb) {
+        metrics::rejected(None, metrics::Rejection::ParseError);
         bail!("Not an HTTP request");
     }
 
     // Looks like an HTTP request, try redirecting it.
-    http::try_redirect(config, client, &mut rb)
+    http::try_redirect(config, client, &mut rb).await
 }
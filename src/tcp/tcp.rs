@@ -1,50 +1,69 @@
-use std::{
-    future::Future,
-    io,
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::Arc,
-    time::Duration,
-};
+use std::{collections::VecDeque, future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
 
-use anyhow::Result;
-use log::{debug, error};
+use anyhow::{anyhow, bail, Result};
+use log::{debug, error, info, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+    time::{timeout, timeout_at, Instant},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    config::Config,
+    access_log, client_limits,
+    config::{Backend, Config, Listener, Route, Timeouts},
     context::*,
-    runtime,
-    tls::{self, alert},
-    zc, RUNTIME,
+    metrics, proxy_protocol, registry,
+    reload::SharedConfig,
+    zc,
 };
 
 /// Starts a TCP server on `bind` and use the given `handle_stream` function to
-/// process incoming connections.
-pub(crate) fn listen_and_proxy<Fut>(
-    config: Arc<Config>,
+/// process incoming connections, along with the data already read from them
+/// (following an HAProxy protocol header). The client handshake must be
+/// received before the deadline given to `handle_stream`. Connections from
+/// clients over their global `client_limits` are refused before being handled,
+/// see `admit`. Connections are tracked in `tracker` and in the connection
+/// registry, and the server stops accepting new ones (closing the listener)
+/// once `shutdown` is cancelled.
+pub(crate) async fn listen_and_proxy<Fut>(
+    config: SharedConfig,
+    listener_type: Listener,
     bind: SocketAddr,
-    handle_stream: fn(Arc<Config>, TcpStream) -> Fut,
+    handle_stream: fn(Arc<Config>, TcpStream, Vec<u8>, Instant) -> Fut,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
 ) -> Result<()>
 where
     Fut: Future<Output = Result<()>> + Send + 'static,
 {
-    let runtime = runtime!()?;
-    let listener = TcpListener::bind(bind)?;
+    let listener = TcpListener::bind(bind).await?;
 
     // Do not return an error starting from here, this would close the whole
     // listener.
 
-    for stream in listener.incoming() {
+    loop {
+        let conn = tokio::select! {
+            _ = shutdown.cancelled() => {
+                debug!("Closing listener on {bind}");
+                return Ok(());
+            }
+            conn = listener.accept() => conn,
+        };
+
         // Do not fail on stream errors.
-        let mut stream = match stream {
-            Ok(stream) => stream,
+        let (mut stream, peer) = match conn {
+            Ok(conn) => conn,
             Err(e) => {
                 error!("Connection error: {e}");
                 continue;
             }
         };
 
-        // Extract peer & local addresses w/o failing the whole listener in case
-        // of errors.
+        metrics::accepted(&listener_type.to_string());
+
+        // Extract the local address w/o failing the whole listener in case of
+        // errors.
         let local = match stream.local_addr() {
             Ok(local) => local,
             Err(e) => {
@@ -58,68 +77,421 @@ where
                 }
             }
         };
-        let peer = match stream.peer_addr() {
-            Ok(peer) => peer,
-            Err(e) => {
-                match e.kind() {
-                    // Even in this small window the client could close the connection.
-                    io::ErrorKind::NotConnected => continue,
-                    _ => {
-                        error!("Could not get peer address: {e}");
-                        continue;
-                    }
-                }
-            }
+
+        // Handle the connection async, using the latest configuration. The
+        // connection keeps it for its whole lifetime, even if a new one is
+        // loaded in the meantime.
+        let config = config.load_full();
+
+        // Refuse connections from clients over their limits before allocating
+        // anything for them. Clients behind a proxy are only known once its
+        // HAProxy protocol header was read.
+        let proxied = config.accepts_proxy_protocol(listener_type, &peer);
+        let client = match proxied {
+            true => None,
+            false => match admit(&config, &peer) {
+                Ok(slot) => slot,
+                Err(_) => continue,
+            },
         };
 
-        // Handle the connection async.
-        let config = Arc::clone(&config);
-        runtime.spawn(with_req_context(
-            ReqContext::from(local, peer),
-            async move {
-                debug!("New connection from client");
-
-                // Set read & write timeouts for processing the message.
-                if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(3))) {
-                    let _ = alert(&mut stream, tls::AlertDescription::InternalError);
-                    error!("Could not set a read timeout on TCP stream: {e}");
-                    return;
+        let deadline = Instant::now() + config.timeouts.client_hello();
+        let context = ReqContext::from(local, peer);
+        let kill = CancellationToken::new();
+        let registration = registry::register(&context.borrow(), kill.clone());
+        tracker.spawn(with_req_context(context, async move {
+            let _registration = registration;
+            debug!("New connection from client");
+
+            let handle = async {
+                let mut data = Vec::new();
+                let mut client = client;
+                if proxied {
+                    accept_proxy_protocol(&mut stream, &mut data, deadline).await?;
+                    client = admit(&config, &peer_addr()?)?;
                 }
-                if let Err(e) = stream.set_write_timeout(Some(Duration::from_secs(3))) {
-                    let _ = alert(&mut stream, tls::AlertDescription::InternalError);
-                    error!("Could not set a write timeout on TCP stream: {e}");
+
+                let _client = client;
+                handle_stream(config, stream, data, deadline).await
+            };
+            let result = tokio::select! {
+                result = handle => result,
+                _ = kill.cancelled() => {
+                    info!("Connection killed from the admin interface");
+                    access_log::update(|r| r.termination = Some("killed"));
+                    Ok(())
+                }
+            };
+
+            if let Err(e) = &result {
+                // Refusals were already reported.
+                if e.is::<client_limits::Refusal>() {
                     return;
                 }
+                error!("{e}");
+            }
+            access_log::write(&result);
+        }));
+    }
+}
+
+/// Accounts for a new connection from `peer` in the global client limits, if
+/// any. Refused connections are not handled further, nor logged in the access
+/// log: those can come in floods, they are only reported in the metrics and
+/// at the debug level.
+fn admit(
+    config: &Config,
+    peer: &SocketAddr,
+) -> Result<Option<client_limits::ClientSlot>, client_limits::Refusal> {
+    client_limits::admit(config.client_limiter.as_ref(), peer).inspect_err(|refusal| {
+        metrics::rejected(None, (*refusal).into());
+        debug!("Refusing connection from {peer}: {refusal}");
+    })
+}
+
+/// Reads an HAProxy protocol header sent by a trusted proxy in front of us,
+/// before `deadline`, and updates the request context with the addresses it
+/// conveys. The data read past the header is left in `data`.
+async fn accept_proxy_protocol(
+    stream: &mut TcpStream,
+    data: &mut Vec<u8>,
+    deadline: Instant,
+) -> Result<()> {
+    let addrs = match timeout_at(deadline, proxy_protocol::read_header(stream, data)).await {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(e)) => bail!("Could not read HAProxy protocol header: {e}"),
+        Err(_) => bail!("Could not read HAProxy protocol header: timed out"),
+    };
+
+    if let Some((peer, local)) = addrs {
+        debug!("Connection proxied for {peer} (to {local})");
+        set_addrs(local, peer)?;
+    }
+    Ok(())
+}
+
+/// Time to wait for a connection attempt to an address of a backend before
+/// starting one to the next address, see RFC 8305 section 5.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to `backend`. All the addresses the backend resolves to are tried
+/// in a single attempt, using Happy Eyeballs (see `happy_eyeballs`). On
+/// failure the route backup backends are tried, for a total of at most
+/// `route.retries + 1` attempts. Returns the backend we connected to along the
+/// connection. Connections to each address are bounded by the backend connect
+/// timeout, falling back to the route and then the global ones. Backend
+/// hostnames are resolved using the `config` resolver.
+///
+/// Nothing was sent to the backend at this point, so retrying is always safe.
+pub(super) async fn connect<'a>(
+    config: &Config,
+    route: &'a Route,
+    backend: &'a Backend,
+) -> Result<(&'a Backend, TcpStream)> {
+    // Backup backends are only used when the selected backend is one of the
+    // route backends (eg. not the ALPN challenge one), and if they are below
+    // their connection limit.
+    let failover = route.backends.iter().any(|b| std::ptr::eq(b, backend));
+    let backups = route
+        .backup_backends()
+        .filter(|b| failover && !std::ptr::eq(*b, backend) && b.has_capacity());
 
-                if let Err(e) = handle_stream(config, stream).await {
-                    error!("{e}");
+    let mut attempts = route.retries.saturating_add(1);
+    for backend in std::iter::once(backend).chain(backups) {
+        let addrs = match backend.to_socket_addrs(&config.resolver).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("Could not resolve backend '{}': {e}", backend.address);
+                attempts -= 1;
+                if attempts == 0 {
+                    break;
                 }
+                continue;
+            }
+        };
+
+        let connect_timeout = route.timeouts(backend, &config.timeouts).connect();
+        let connect = move |addr| async move {
+            match timeout(connect_timeout, TcpStream::connect(addr)).await {
+                Ok(conn) => Ok(conn?),
+                Err(_) => bail!("timed out"),
+            }
+        };
+        // Failures are logged for each address.
+        if let Ok(conn) =
+            happy_eyeballs(&backend.address, addrs, CONNECTION_ATTEMPT_DELAY, connect).await
+        {
+            return Ok((backend, conn));
+        }
+
+        attempts -= 1;
+        if attempts == 0 {
+            break;
+        }
+    }
+
+    bail!("Could not connect to any backend")
+}
+
+/// Connects to one of `addrs`, the addresses of backend `name`, following
+/// RFC 8305 (Happy Eyeballs v2): addresses are tried alternating between
+/// families, starting with the family of the first one, and a new connection
+/// attempt is started each time one fails or every `delay`, without stopping
+/// the ones in progress. The first established connection is returned and
+/// the other attempts are cancelled.
+async fn happy_eyeballs<F, Fut, T>(
+    name: &str,
+    addrs: Vec<SocketAddr>,
+    delay: Duration,
+    connect: F,
+) -> Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut addrs = interleave(addrs).into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut error = None;
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            debug!("Connecting to backend '{name}' ({addr})");
+            let attempt = connect(addr);
+            attempts.spawn(async move { (addr, attempt.await) });
+        }
+        if attempts.is_empty() {
+            break;
+        }
+
+        let more = addrs.peek().is_some();
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result {
+                // Dropping the other attempts cancels them.
+                Ok((_, Ok(conn))) => return Ok(conn),
+                Ok((addr, Err(e))) => {
+                    warn!("Could not connect to backend '{name}' ({addr}): {e}");
+                    error = Some(e);
+                }
+                Err(e) => error = Some(e.into()),
             },
-        ));
+            _ = tokio::time::sleep(delay), if more => (),
+        }
     }
 
-    Ok(())
+    Err(error.unwrap_or_else(|| anyhow!("No address to connect to")))
+}
+
+/// Orders addresses alternating between the IPv6 and IPv4 families, starting
+/// with the family of the first address, see RFC 8305 section 4. The order of
+/// the addresses within a family is kept.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|a| a.is_ipv6() == first_is_ipv6);
+
+    let mut addrs = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        addrs.extend(first.pop_front());
+        addrs.extend(second.pop_front());
+    }
+    addrs
 }
 
+/// Proxies data between the client and the backend until both connections
+/// are closed, or until the idle timeout or the maximum lifetime is reached.
+/// Returns the number of bytes sent to the backend and to the client;
+/// `progress` is called with the same information while proxying.
 #[inline(always)]
-pub(super) async fn proxy(client: TcpStream, backend: TcpStream) -> Result<()> {
+pub(super) async fn proxy<F>(
+    mut client: TcpStream,
+    mut backend: TcpStream,
+    timeouts: &Timeouts,
+    mut progress: F,
+) -> Result<(usize, usize)>
+where
+    F: FnMut(usize, usize),
+{
     // Send keepalive to both the client and the backend.
-    let keep_alive = socket2::TcpKeepalive::new()
-        .with_time(Duration::from_secs(60))
-        .with_interval(Duration::from_secs(60));
-    socket2::SockRef::from(&client).set_tcp_keepalive(&keep_alive)?;
-    socket2::SockRef::from(&backend).set_tcp_keepalive(&keep_alive)?;
-
-    // All good, proxy the rest of the connection.
-    client.set_nonblocking(true)?;
-    backend.set_nonblocking(true)?;
-    let mut client = tokio::net::TcpStream::from_std(client)?;
-    let mut backend = tokio::net::TcpStream::from_std(backend)?;
+    if let Some(keep_alive) = timeouts.keepalive() {
+        socket2::SockRef::from(&client).set_tcp_keepalive(&keep_alive)?;
+        socket2::SockRef::from(&backend).set_tcp_keepalive(&keep_alive)?;
+    }
+
+    // Keep track of the data moved so far, in case the copy is interrupted.
+    let mut processed = (0, 0);
+    let copy = zc::copy_bidirectional(
+        &mut backend,
+        &mut client,
+        timeouts.idle(),
+        |to_client, to_backend| {
+            processed = (to_backend, to_client);
+            progress(to_backend, to_client)
+        },
+    );
 
     // Move data between backend & client until connections are closed.
     debug!("Starting proxying the connection");
-    let _ = zc::copy_bidirectional(&mut backend, &mut client).await;
+    let result = match timeouts.max_lifetime() {
+        Some(max_lifetime) => match timeout(max_lifetime, copy).await {
+            Ok(result) => result,
+            Err(_) => {
+                debug!("Connection reached its maximum lifetime, closing it");
+                access_log::update(|r| r.termination = Some("max_lifetime"));
+                return Ok(processed);
+            }
+        },
+        None => copy.await,
+    };
+    let (to_client, to_backend) = match result {
+        Ok(processed) => processed,
+        Err(e) => match e.downcast_ref::<zc::Error>() {
+            Some(zc::Error::IdleTimeout) => {
+                debug!("Connection was idle for too long, closing it");
+                access_log::update(|r| r.termination = Some("idle_timeout"));
+                return Ok(processed);
+            }
+            None => {
+                debug!("Connection was interrupted: {e}");
+                access_log::update(|r| r.termination = Some("error"));
+                return Ok(processed);
+            }
+        },
+    };
     debug!("Connection shut down");
 
-    Ok(())
+    Ok((to_backend, to_client))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use anyhow::bail;
+    use tokio::{net::TcpListener, time::Instant};
+
+    use super::{connect, happy_eyeballs, interleave};
+    use crate::config::Config;
+
+    /// Returns an address nothing listens on.
+    async fn closed_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = listener.local_addr().unwrap().to_string();
+        let down = closed_addr().await;
+
+        let config = |retries| {
+            Config::from_str(&format!(
+                "
+routes:
+  - domains:
+      - example.net
+    retries: {retries}
+    backends:
+      - address: {down}
+      - address: {down}
+        backup: true
+      - address: {up}
+        backup: true
+            "
+            ))
+            .unwrap()
+        };
+
+        // Without retries, the first failure is final.
+        let cfg = config(0);
+        let route = cfg.get_route("example.net").unwrap();
+        assert!(connect(&cfg, route, &route.backends[0]).await.is_err());
+
+        // Not enough retries to reach the working backup.
+        let cfg = config(1);
+        let route = cfg.get_route("example.net").unwrap();
+        assert!(connect(&cfg, route, &route.backends[0]).await.is_err());
+
+        // Backups are tried in order until one works.
+        let cfg = config(2);
+        let route = cfg.get_route("example.net").unwrap();
+        let (backend, _) = connect(&cfg, route, &route.backends[0]).await.unwrap();
+        assert_eq!(backend.address, up);
+
+        // Unhealthy backups are skipped.
+        route.backends[1].set_healthy(false);
+        let (backend, _) = connect(&cfg, route, &route.backends[0]).await.unwrap();
+        assert_eq!(backend.address, up);
+    }
+
+    #[test]
+    fn interleave_families() {
+        let addrs = |addrs: &[&str]| -> Vec<SocketAddr> {
+            addrs.iter().map(|a| a.parse().unwrap()).collect()
+        };
+
+        assert_eq!(
+            interleave(addrs(&[
+                "[::1]:1",
+                "[::2]:1",
+                "[::3]:1",
+                "10.0.0.1:1",
+                "10.0.0.2:1"
+            ])),
+            addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"])
+        );
+        assert_eq!(
+            interleave(addrs(&["10.0.0.1:1", "10.0.0.2:1", "[::1]:1"])),
+            addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"])
+        );
+        assert_eq!(interleave(Vec::new()), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_race() {
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let broken: SocketAddr = "192.0.2.2:443".parse().unwrap();
+
+        // Fake connections: IPv6 hangs, the broken address fails right away
+        // and others connect quickly.
+        let connect = |addr: SocketAddr| async move {
+            match addr {
+                a if a.is_ipv6() => tokio::time::sleep(Duration::from_secs(10)).await,
+                a if a == broken => bail!("connection refused"),
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+            Ok(addr)
+        };
+
+        // The next address is tried once the delay expired, while the first
+        // attempt is still in progress.
+        let start = Instant::now();
+        let delay = Duration::from_millis(100);
+        let addr = happy_eyeballs("backend", vec![v6, v4], delay, connect)
+            .await
+            .unwrap();
+        assert_eq!(addr, v4);
+        assert!(start.elapsed() >= delay);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The next address is tried right away on failures.
+        let start = Instant::now();
+        let delay = Duration::from_secs(5);
+        let addr = happy_eyeballs("backend", vec![broken, v4], delay, connect)
+            .await
+            .unwrap();
+        assert_eq!(addr, v4);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // All attempts failed.
+        assert!(happy_eyeballs("backend", vec![broken], delay, connect)
+            .await
+            .is_err());
+        assert!(happy_eyeballs("backend", Vec::new(), delay, connect)
+            .await
+            .is_err());
+    }
 }
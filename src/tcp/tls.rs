@@ -1,16 +1,15 @@
-use std::{
-    io::{BufWriter, Write},
-    net::{SocketAddr, TcpStream},
-    sync::Arc,
-    time::Duration,
-};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 
 use anyhow::{bail, Result};
 use log::debug;
+use tokio::{io::AsyncWriteExt, net::TcpStream, time};
 
 use crate::{
-    config::{self, Config},
-    context, http, proxy_protocol,
+    access_log, client_limits,
+    config::{self, Config, ProxyProtocolTlv},
+    context,
+    fingerprint::Fingerprint,
+    http, limits, metrics, proxy_protocol,
     reader::ReaderBuf,
     tls::{self, Tls},
 };
@@ -29,21 +28,31 @@ fn extract_ip_string(addr: SocketAddr) -> String {
 }
 
 /// Handle TCP/TLS connections.
-pub(crate) async fn handle_stream(config: Arc<Config>, stream: TcpStream) -> Result<()> {
-    let mut rb = ReaderBuf::with_capacity(tls::RECORD_MAX_LEN, stream);
+pub(crate) async fn handle_stream(
+    config: Arc<Config>,
+    stream: TcpStream,
+    data: Vec<u8>,
+    deadline: time::Instant,
+) -> Result<()> {
+    let mut rb = ReaderBuf::with_data(tls::RECORD_MAX_LEN, data, stream);
+    rb.set_read_deadline(Some(deadline));
 
     // Start by checking we got a valid TLS message, and if true parse it.
-    let tls = match Tls::from(&mut rb) {
+    let start = Instant::now();
+    let tls = Tls::from(&mut rb, config.max_client_hello_size).await;
+    metrics::handshake_parsed(start);
+    let tls = match tls {
         Ok(tls) => tls,
         Err(e) => {
             // If this looks like an HTTP request, try to redirect it.
             // Luckily http::is_http needs 5 bytes in the buffer and the
             // minimal TLS parsing reads 5 bytes.
             if http::is_http(&rb) {
-                return http::try_redirect(&config, &context::peer_addr()?, &mut rb);
+                return http::try_redirect(&config, &context::peer_addr()?, &mut rb).await;
             }
 
-            tls::alert(rb.get_mut(), tls::AlertDescription::InternalError)?;
+            metrics::rejected(None, metrics::Rejection::ParseError);
+            tls::alert(rb.get_mut(), tls::AlertDescription::InternalError).await?;
             bail!("Could not parse TLS message: {e}");
         }
     };
@@ -63,68 +72,242 @@ pub(crate) async fn handle_stream(config: Arc<Config>, stream: TcpStream) -> Res
     };
     context::set_hostname(hostname)?;
 
+    let fingerprint = Fingerprint::from(tls.client_hello());
+    debug!(
+        "Client fingerprints: JA3 {}, JA4 {}",
+        fingerprint.ja3, fingerprint.ja4
+    );
+    context::set_fingerprint(&fingerprint)?;
+
+    let ech = tls.client_hello().ech;
+    if let Some(ech) = ech {
+        debug!(
+            "Found Encrypted Client Hello (config id {}, KDF {:#06x}, AEAD {:#06x}), \
+             {hostname} is its public name unless it is GREASE",
+            ech.config_id, ech.kdf_id, ech.aead_id
+        );
+    }
+
     let peer = &context::peer_addr()?;
-    let backend = config
-        .get_backend(hostname, peer, tls.is_challenge())
-        .or_else(|e| match e.downcast() {
+    let route_name = || config.get_route(hostname).map(|r| r.name());
+    access_log::update(|r| {
+        r.sni = tls.hostname().cloned();
+        if !tls.alpn_protocols().is_empty() {
+            let protocols: Vec<_> = tls
+                .alpn_protocols()
+                .iter()
+                .map(|p| String::from_utf8_lossy(p))
+                .collect();
+            r.alpn = Some(protocols.join(","));
+        }
+        r.challenge = tls.is_challenge();
+        r.ech_config_id = ech.map(|ech| ech.config_id);
+        r.route = route_name().map(str::to_string);
+    });
+    let hello = tls.client_hello();
+    let (route, backend) = match config.get_backend(hostname, peer, hello, &fingerprint) {
+        Ok(route) => route,
+        Err(e) => match e.downcast() {
             Ok(e) => match e {
                 config::Error::HostnameNotFound => {
-                    tls::alert(rb.get_mut(), tls::AlertDescription::UnrecognizedName)?;
+                    metrics::rejected(None, metrics::Rejection::NoRoute);
+                    tls::alert(rb.get_mut(), tls::AlertDescription::UnrecognizedName).await?;
                     bail!("No route found for '{hostname}'")
                 }
                 config::Error::NoBackend => {
-                    tls::alert(rb.get_mut(), tls::AlertDescription::AccessDenied)?;
+                    metrics::rejected(route_name(), metrics::Rejection::NoBackend);
+                    tls::alert(rb.get_mut(), tls::AlertDescription::AccessDenied).await?;
                     bail!("No backend defined for '{hostname}'")
                 }
+                config::Error::NoHealthyBackend => {
+                    metrics::rejected(route_name(), metrics::Rejection::NoBackend);
+                    tls::alert(rb.get_mut(), tls::AlertDescription::InternalError).await?;
+                    bail!("No healthy backend for '{hostname}'")
+                }
                 config::Error::AccessDenied => {
-                    tls::alert(rb.get_mut(), tls::AlertDescription::AccessDenied)?;
+                    metrics::rejected(route_name(), metrics::Rejection::AclDenied);
+                    tls::alert(rb.get_mut(), tls::AlertDescription::AccessDenied).await?;
                     bail!("Request from {peer} for '{hostname}' was denied by ACLs")
                 }
+                config::Error::ProtocolVersion(version) => {
+                    metrics::rejected(route_name(), metrics::Rejection::ProtocolVersion);
+                    tls::alert(rb.get_mut(), tls::AlertDescription::ProtocolVersion).await?;
+                    bail!(
+                        "Request from {peer} for '{hostname}' was refused, its highest TLS \
+                         version ({version:#x}) is below the route minimum"
+                    )
+                }
+                config::Error::EchDenied => {
+                    metrics::rejected(route_name(), metrics::Rejection::EchDenied);
+                    tls::alert(rb.get_mut(), tls::AlertDescription::AccessDenied).await?;
+                    bail!(
+                        "Request from {peer} for '{hostname}' was refused, it uses Encrypted \
+                         Client Hello"
+                    )
+                }
             },
             Err(e) => bail!(e),
-        })?;
+        },
+    };
+
+    // Enforce the route client limits, until the connection is closed.
+    let _client = match client_limits::admit(route.client_limiter.as_ref(), peer) {
+        Ok(slot) => slot,
+        Err(refusal) => {
+            metrics::rejected(Some(route.name()), refusal.into());
+            tls::alert(rb.get_mut(), tls::AlertDescription::AccessDenied).await?;
+            bail!("Request from {peer} for '{hostname}' was refused: {refusal}");
+        }
+    };
+
+    // Take a slot in the connection limiters, until the connection is closed.
+    let limit_reached = |scope| {
+        metrics::rejected(Some(route.name()), metrics::Rejection::LimitReached);
+        (
+            config.connection_limit(route).alert,
+            format!("Reached the {scope} connection limit for '{hostname}'"),
+        )
+    };
+    let mut permits = match limits::acquire(&config, route, backend).await {
+        Ok(permits) => permits,
+        Err(scope) => {
+            let (alert, msg) = limit_reached(scope);
+            tls::alert(rb.get_mut(), alert).await?;
+            bail!(msg);
+        }
+    };
+
+    // Connect to the backend, failing over to alternate addresses and
+    // backends if allowed.
     debug!(
-        "Using backend {:?} (is alpn challenge? {})",
-        backend.to_socket_addr(),
+        "Using backend '{}' (is alpn challenge? {})",
+        &backend.address,
         tls.is_challenge(),
     );
-
-    // Connect to the backend.
-    let conn = match TcpStream::connect_timeout(&backend.to_socket_addr()?, Duration::from_secs(3))
-    {
+    let start = Instant::now();
+    let selected = backend;
+    let (backend, mut conn) = match super::tcp::connect(&config, route, backend).await {
         Ok(conn) => conn,
         Err(e) => {
-            tls::alert(rb.get_mut(), tls::AlertDescription::InternalError)?;
-            bail!("Could not connect to backend '{}': {e}", &backend.address);
+            metrics::rejected(Some(route.name()), metrics::Rejection::ConnectError);
+            tls::alert(rb.get_mut(), tls::AlertDescription::InternalError).await?;
+            bail!("{e} for '{hostname}'");
         }
     };
+    if !std::ptr::eq(backend, selected) {
+        if let Err(scope) = permits.switch_backend(backend) {
+            let (alert, msg) = limit_reached(scope);
+            tls::alert(rb.get_mut(), alert).await?;
+            bail!(msg);
+        }
+    }
+
+    metrics::backend_connected(route.name(), start);
+    metrics::proxied(route.name());
+    access_log::update(|r| {
+        r.backend = Some(backend.address.clone());
+        r.backend_addr = conn.peer_addr().ok().map(|addr| addr.to_string());
+        r.proxy_protocol = backend.proxy_protocol;
+    });
+
+    // Account for the connection to the backend, until the connection is
+    // closed.
+    let _active = backend.connection();
+    let _permits = permits;
+    let _active_metric = metrics::active(route.name());
 
-    // Use a buffered writer to avoid small writes until we start forwarding the
-    // data.
-    let mut bw = BufWriter::new(conn);
+    // Build the data to send before proxying in a single buffer, to avoid
+    // small writes.
+    let mut buf = Vec::with_capacity(rb.len() + 128);
 
     // Send an HAProxy protocol header if needed.
     if let Some(version) = backend.proxy_protocol {
-        proxy_protocol::write_header(&mut bw, version, &context::local_addr()?, peer)?;
+        let authority = context::hostname()?;
+        let unique_id = context::id()?.to_string();
+        let tlvs = proxy_protocol_tlvs(
+            &backend.proxy_protocol_tlvs,
+            tls.alpn_protocols(),
+            authority.as_deref(),
+            &unique_id,
+        );
+        proxy_protocol::write_header(&mut buf, version, &context::local_addr()?, peer, &tlvs)?;
     }
 
-    // Replay the handshake.
-    bw.write_all(rb.buf())?;
-
-    // We can now flush the buffered writer and stop using it to avoid adding
-    // buffering in the middle of the connection.
-    let mut conn = bw.into_inner()?;
-
-    // Do not use read & write timeouts for proxying.
-    if let Err(e) = conn.set_read_timeout(None) {
-        tls::alert(&mut conn, tls::AlertDescription::InternalError)?;
-        bail!("Could not unset the read timeout on TCP stream: {e}");
-    }
-    if let Err(e) = conn.set_write_timeout(None) {
-        tls::alert(&mut conn, tls::AlertDescription::InternalError)?;
-        bail!("Could not unset the write timeout on TCP stream: {e}");
+    // Replay the handshake. The backend is given the connect timeout to
+    // accept it, so a stalled one can't hold the connection forever.
+    let timeouts = route.timeouts(backend, &config.timeouts);
+    buf.extend_from_slice(rb.buf());
+    match time::timeout(timeouts.connect(), conn.write_all(&buf)).await {
+        Ok(result) => result?,
+        Err(_) => bail!(
+            "Timed out replaying the handshake to backend '{}'",
+            backend.address
+        ),
     }
 
-    super::tcp::proxy(rb.into_inner(), conn).await?;
+    // Keep the access log record up to date while proxying, for the
+    // connection registry.
+    let update_bytes = |to_backend: usize, to_client: usize| {
+        access_log::update(|r| {
+            r.bytes_to_backend = (buf.len() + to_backend) as u64;
+            r.bytes_to_client = to_client as u64;
+        })
+    };
+    update_bytes(0, 0);
+
+    let (to_backend, to_client) =
+        super::tcp::proxy(rb.into_inner(), conn, &timeouts, update_bytes).await?;
+    update_bytes(to_backend, to_client);
+    metrics::bytes(route.name(), buf.len() + to_backend, to_client);
     Ok(())
 }
+
+/// Build the HAProxy protocol v2 TLVs configured for a backend.
+/// PP2_TYPE_ALPN holds a single protocol, the negotiated one, which we can't
+/// know as the handshake is not terminated here: it carries the first, and
+/// most preferred, protocol offered by the client only.
+fn proxy_protocol_tlvs<'a>(
+    tlvs: &[ProxyProtocolTlv],
+    alpn_protocols: &'a [Vec<u8>],
+    authority: Option<&'a str>,
+    unique_id: &'a str,
+) -> Vec<proxy_protocol::Tlv<'a>> {
+    tlvs.iter()
+        .filter_map(|tlv| match tlv {
+            ProxyProtocolTlv::Authority => authority.map(proxy_protocol::Tlv::Authority),
+            ProxyProtocolTlv::Alpn => alpn_protocols
+                .first()
+                .map(|alpn| proxy_protocol::Tlv::Alpn(alpn)),
+            ProxyProtocolTlv::UniqueId => Some(proxy_protocol::Tlv::UniqueId(unique_id.as_bytes())),
+            ProxyProtocolTlv::Crc32c => Some(proxy_protocol::Tlv::Crc32c),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::ProxyProtocolTlv, proxy_protocol};
+
+    #[test]
+    fn proxy_protocol_tlvs() {
+        let local = "172.16.99.1:443".parse().unwrap();
+        let peer = "10.0.42.132:1337".parse().unwrap();
+
+        // Only the first protocol offered is sent.
+        let protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let tlvs = super::proxy_protocol_tlvs(&[ProxyProtocolTlv::Alpn], &protocols, None, "42");
+        let mut w = Vec::new();
+        proxy_protocol::write_header(&mut w, 2, &local, &peer, &tlvs).unwrap();
+        assert_eq!(w.len(), 16 + 12 + 5);
+        assert_eq!(&w[28..], &[0x01, 0, 2, b'h', b'2']);
+
+        // No TLV is sent without ALPN extension, or authority.
+        let tlvs = super::proxy_protocol_tlvs(
+            &[ProxyProtocolTlv::Alpn, ProxyProtocolTlv::Authority],
+            &[],
+            None,
+            "42",
+        );
+        assert!(tlvs.is_empty());
+    }
+}
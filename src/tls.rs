@@ -1,42 +1,167 @@
-use std::{
-    io::{Read, Write},
-    mem,
-};
+use std::{fmt, mem};
 
 use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::reader::ReaderBuf;
+use crate::reader::{Framing, ReaderBuf};
 
 pub const RECORD_MAX_LEN: usize = 16 * 1024;
-
-/// Main representation of a TLS connection. We do not parse all the fields and extensions, nor we
-/// read all the data from it. This is mostly used to get to the extensions we care about so we can
-/// make a decision on what to do with a new TLS connection.
+/// Length of a TLS record header.
+const RECORD_HDR_LEN: usize = 5;
+/// Length of a TLS handshake message header.
+const HANDSHAKE_HDR_LEN: usize = 4;
+
+// Extension types.
+// https://www.iana.org/assignments/tls-extensiontype-values
+pub(crate) const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+pub(crate) const EXT_ALPN: u16 = 16;
+const EXT_PRE_SHARED_KEY: u16 = 41;
+const EXT_EARLY_DATA: u16 = 42;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_KEY_SHARE: u16 = 51;
+const EXT_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+
+/// ALPN protocol used by tls-alpn-01 challenges, see RFC 8737.
+pub(crate) const ACME_TLS_ALPN: &str = "acme-tls/1";
+
+/// Main representation of a TLS connection. We do not read all the data from it, only the
+/// ClientHello message, so we can make a decision on what to do with a new TLS connection.
 #[derive(Default)]
 pub(crate) struct Tls {
+    /// ClientHello message sent by the client.
+    client_hello: ClientHello,
+}
+
+/// ClientHello message, as sent by the client. Lists are kept in the client
+/// order, GREASE values included (RFC 8701). Extensions we do not know about
+/// are only listed in `extensions`.
+/// https://www.rfc-editor.org/rfc/rfc8446#section-4.1.2
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub(crate) struct ClientHello {
+    /// Legacy version field, 0x303 for TLS 1.2 and later.
+    pub(crate) legacy_version: u16,
+    /// Versions offered in the supported_versions extension, if any.
+    pub(crate) supported_versions: Vec<u16>,
+    /// Cipher suites offered by the client.
+    pub(crate) cipher_suites: Vec<u16>,
+    /// Compression methods offered by the client.
+    pub(crate) compression_methods: Vec<u8>,
+    /// Groups offered in the supported_groups extension, if any.
+    pub(crate) supported_groups: Vec<u16>,
+    /// Formats offered in the ec_point_formats extension, if any.
+    pub(crate) ec_point_formats: Vec<u8>,
+    /// Algorithms offered in the signature_algorithms extension, if any.
+    pub(crate) signature_algorithms: Vec<u16>,
+    /// Groups of the key shares sent in the key_share extension, if any.
+    pub(crate) key_share_groups: Vec<u16>,
     /// Server name indication hostname, if found.
-    sni_hostname: Option<String>,
-    /// Is the ALPN extension
-    alpn_is_challenge: bool,
+    pub(crate) sni_hostname: Option<String>,
+    /// Protocols offered in the ALPN extension, in the client order of
+    /// preference. Protocol names are opaque bytes, see RFC 7301.
+    pub(crate) alpn_protocols: Vec<Vec<u8>>,
+    /// Types of the extensions, in the order they were sent.
+    pub(crate) extensions: Vec<u16>,
+    /// Outer Encrypted Client Hello extension, if any. It can be GREASE, sent
+    /// by clients without an ECH configuration, which only the configuration
+    /// id can tell.
+    pub(crate) ech: Option<Ech>,
+}
+
+/// Outer Encrypted Client Hello extension. The inner ClientHello it holds can
+/// only be decrypted using the keys of the ECH configuration.
+/// https://datatracker.ietf.org/doc/html/draft-ietf-tls-esni#section-5
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Ech {
+    /// Identifier of the ECH configuration used by the client.
+    pub(crate) config_id: u8,
+    /// HPKE key derivation function.
+    pub(crate) kdf_id: u16,
+    /// HPKE AEAD algorithm.
+    pub(crate) aead_id: u16,
+}
+
+impl ClientHello {
+    /// Highest TLS version offered by the client, using the supported_versions
+    /// extension if any, or the legacy version otherwise.
+    pub(crate) fn max_version(&self) -> u16 {
+        self.supported_versions
+            .iter()
+            .copied()
+            .filter(|v| !is_grease(*v))
+            .max()
+            .unwrap_or(self.legacy_version)
+    }
+
+    /// Does the client offer to resume a session using a pre-shared key.
+    #[allow(dead_code)]
+    pub(crate) fn has_psk(&self) -> bool {
+        self.extensions.contains(&EXT_PRE_SHARED_KEY)
+    }
+
+    /// Does the client intend to send early (0-RTT) data.
+    #[allow(dead_code)]
+    pub(crate) fn has_early_data(&self) -> bool {
+        self.extensions.contains(&EXT_EARLY_DATA)
+    }
+
+    /// Is the ClientHello a tls-alpn-01 challenge, the ALPN extension holding
+    /// `acme-tls/1` as its single protocol.
+    /// https://www.rfc-editor.org/rfc/rfc8737#section-3
+    pub(crate) fn is_challenge(&self) -> bool {
+        self.alpn_protocols == [ACME_TLS_ALPN.as_bytes()]
+    }
 }
 
 impl Tls {
-    pub(crate) fn from<R: Read>(reader: &mut ReaderBuf<R>) -> Result<Tls> {
-        // Start by parsing the message up to the extensions.
-        //
-        // As soon as we know it's likely to be a TLS record, extend the reader
-        // min read to match (MAX_TLS_LEN - RECORD_HDR_LEN).
-        Self::parse_plaintext_record_header(reader)?;
-        reader.set_min_read(RECORD_MAX_LEN - 5);
-        Self::parse_handshake_header(reader)?;
-        Self::parse_client_hello(reader)?;
+    /// Parses the ClientHello read from `reader`, which can be fragmented in
+    /// multiple records as long as its length, handshake header included,
+    /// does not exceed `max_len`. The message is parsed as its records are
+    /// read, and at most twice `max_len` bytes are read, record headers
+    /// included, so tiny records can't be used to make us read and process
+    /// much more than a message. The records are kept in the reader buffer as
+    /// they were received, to be replayed.
+    /// https://www.rfc-editor.org/rfc/rfc8446#section-5.1
+    pub(crate) async fn from<R: AsyncRead + Unpin>(
+        reader: &mut ReaderBuf<R>,
+        max_len: usize,
+    ) -> Result<Tls> {
+        reader.set_framing(Framing {
+            header_len: RECORD_HDR_LEN,
+            parse_header: Self::parse_handshake_record_header,
+            max_len: 2 * max_len,
+        });
+
+        // Start by parsing the handshake message up to the extensions.
+        let len = Self::parse_handshake_header(reader).await? + HANDSHAKE_HDR_LEN;
+        if len > max_len {
+            bail!("TLS handshake length exceed the maximum authorized ({len} > {max_len})");
+        }
+
+        // As soon as we know it's likely to be a TLS record, extend the
+        // reader min read to match (MAX_TLS_LEN - RECORD_HDR_LEN).
+        reader.set_min_read(RECORD_MAX_LEN - RECORD_HDR_LEN);
+
+        let mut tls = Tls {
+            client_hello: Self::parse_client_hello(reader).await?,
+        };
 
         // Now we can access the extensions and see if we can find something interesting.
-        let mut len = Self::read_vector_size(reader, 2)?;
+        let mut len = match Self::read_vector_size(reader, 2).await? {
+            // Check we don't go past the message, into what follows it.
+            size if reader.pos() + size > len => {
+                bail!("ClientHello goes past the handshake message length ({len})")
+            }
+            size => size,
+        };
 
         // No extension, which is valid.
         if len == 0 {
-            return Ok(Tls::default());
+            return Ok(tls);
         }
 
         // We have a len but it can't even hold the extension description.
@@ -46,11 +171,11 @@ impl Tls {
 
         // Loop while we have potential valid extension headers.
         // https://www.rfc-editor.org/rfc/rfc8446#section-4.2
-        let mut tls = Tls::default();
+        let hello = &mut tls.client_hello;
         while len >= 4 {
             // Extension type: u16
             // Vector size:    u16
-            let header = reader.read_as::<[u8; 4]>()?;
+            let header = reader.read_as::<[u8; 4]>().await?;
             len -= mem::size_of_val(header);
 
             let r#type = u16::from_be_bytes(header[0..=1].try_into()?);
@@ -65,6 +190,8 @@ impl Tls {
                 );
             }
 
+            hello.extensions.push(r#type);
+
             // Extension is empty, can happen e.g. on session_ticket
             if size == 0 {
                 continue;
@@ -72,15 +199,31 @@ impl Tls {
 
             // Read the extension data. Even if we do not support the extension, we can't seek as
             // we need to replay the TLS message.
-            let extension = reader.read_exact(size)?;
+            let extension = reader.read_exact(size).await?;
             len -= size;
 
             // Specific handling depending on the extension type.
             match r#type {
-                // Server name indication.
-                0 => tls.sni_hostname = Some(Self::sni_ext_get_hostname(extension)?),
-                // Application layer protocol negotiation.
-                16 => tls.alpn_is_challenge = Self::alpn_ext_is_challenge(extension)?,
+                EXT_SERVER_NAME => {
+                    hello.sni_hostname = Some(Self::sni_ext_get_hostname(extension)?)
+                }
+                EXT_SUPPORTED_GROUPS => {
+                    hello.supported_groups = Self::u16_list_ext_get(extension, 2)?
+                }
+                EXT_EC_POINT_FORMATS => {
+                    hello.ec_point_formats = Self::ec_point_formats_ext_get(extension)?
+                }
+                EXT_SIGNATURE_ALGORITHMS => {
+                    hello.signature_algorithms = Self::u16_list_ext_get(extension, 2)?
+                }
+                EXT_ALPN => hello.alpn_protocols = Self::alpn_ext_get_protocols(extension)?,
+                EXT_SUPPORTED_VERSIONS => {
+                    hello.supported_versions = Self::u16_list_ext_get(extension, 1)?
+                }
+                EXT_KEY_SHARE => {
+                    hello.key_share_groups = Self::key_share_ext_get_groups(extension)?
+                }
+                EXT_ENCRYPTED_CLIENT_HELLO => hello.ech = Self::ech_ext_get(extension)?,
                 _ => (),
             }
         }
@@ -88,15 +231,27 @@ impl Tls {
         Ok(tls)
     }
 
-    /// Parse a TLS plaintext record header.
+    /// Parse the header of a plaintext record holding (a fragment of) the
+    /// ClientHello and return the record length. Handshake records can't be
+    /// empty, and are not interleaved with other record types.
+    fn parse_handshake_record_header(record: &[u8]) -> Result<usize> {
+        match Self::parse_plaintext_record_header(record)? {
+            0 => bail!("TLS handshake record is empty"),
+            len => Ok(len),
+        }
+    }
+
+    /// Parse a TLS plaintext record header and return the record length.
     /// https://www.rfc-editor.org/rfc/rfc8446#section-5.1
-    fn parse_plaintext_record_header<R: Read>(reader: &mut ReaderBuf<R>) -> Result<()> {
+    fn parse_plaintext_record_header(record: &[u8]) -> Result<usize> {
         // Record header:
         //   type:   u8
         //   major:  u8
         //   minor:  u8
         //   length: u16
-        let record = reader.read_as::<[u8; 5]>()?;
+        if record.len() != RECORD_HDR_LEN {
+            bail!("Invalid TLS record header length ({})", record.len());
+        }
 
         // Check if record type is 22, aka handshake.
         if record[0] != 22 {
@@ -110,20 +265,24 @@ impl Tls {
         }
 
         // Check the length does not exceed the maximum authorized.
-        if u16::from_be_bytes(record[3..=4].try_into()?) > RECORD_MAX_LEN as u16 {
+        let len = u16::from_be_bytes(record[3..=4].try_into()?) as usize;
+        if len > RECORD_MAX_LEN {
             bail!("TLS record length exceed the maximum authorized.");
         }
 
-        Ok(())
+        Ok(len)
     }
 
-    /// Parse a TLS handshake header.
+    /// Parse a TLS handshake header and return the message length, header
+    /// excluded.
     /// https://www.rfc-editor.org/rfc/rfc8446#section-4
-    fn parse_handshake_header<R: Read>(reader: &mut ReaderBuf<R>) -> Result<()> {
+    async fn parse_handshake_header<R: AsyncRead + Unpin>(
+        reader: &mut ReaderBuf<R>,
+    ) -> Result<usize> {
         // Handshake header:
         //   Message Type: u8
         //   Message Len:  [u8; 3]
-        let handshake = reader.read_as::<[u8; 4]>()?;
+        let handshake = reader.read_as::<[u8; 4]>().await?;
 
         // Check we're dealing with a ClientHello message.
         if handshake[0] != 1 {
@@ -133,9 +292,7 @@ impl Tls {
             );
         }
 
-        // We're not checking the length here as we'll try to read it anyway.
-
-        Ok(())
+        Ok(u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize)
     }
 
     /// Parse a TLS client hello message up to the extensions section.
@@ -148,25 +305,28 @@ impl Tls {
     ///   Cipher suite:
     ///   Compression method:
     ///   Extensions:
-    fn parse_client_hello<R: Read>(reader: &mut ReaderBuf<R>) -> Result<()> {
+    async fn parse_client_hello<R: AsyncRead + Unpin>(
+        reader: &mut ReaderBuf<R>,
+    ) -> Result<ClientHello> {
         // Start by parsing the two first fields (version & random) as they have a fixed length,
         // which is not true for later fields.
-        let hello = reader.read_as::<[u8; 34]>()?;
+        let hello = reader.read_as::<[u8; 34]>().await?;
 
         // Check the version. 0x301: TLS 1.0, 0x302: TLS 1.1, 0x303: >= TLS 1.2.
-        match u16::from_be_bytes(hello[0..=1].try_into()?) {
-            0x301..=0x303 => (),
+        let legacy_version = match u16::from_be_bytes(hello[0..=1].try_into()?) {
+            x @ 0x301..=0x303 => x,
             x => bail!("Invalid client version in ClientHello ({:#x})", x),
-        }
+        };
 
         // Read the session id.
-        let len = Self::read_vector(reader, 1)?.len();
+        let len = Self::read_vector(reader, 1).await?.len();
         if len > 32 {
             bail!("Session id has an invalid length ({} > 32)", len);
         }
 
         // Read the cipher suites.
-        let len = Self::read_vector(reader, 2)?.len();
+        let cipher_suites = Self::read_vector(reader, 2).await?;
+        let len = cipher_suites.len();
         if len < 2 {
             bail!("Cipher suites length is too small ({} < 2)", len);
         } else if len % 2 != 0 {
@@ -174,21 +334,30 @@ impl Tls {
         }
 
         // Read the compression methods.
-        let len = Self::read_vector(reader, 1)?.len();
+        let compression_methods = Self::read_vector(reader, 1).await?;
+        let len = compression_methods.len();
         if len < 1 {
             bail!("Compression methods length is too small ({} < 1)", len);
         }
 
         // We reached the extensions (or none, which is also valid).
-        Ok(())
+        Ok(ClientHello {
+            legacy_version,
+            cipher_suites: Self::to_u16_list(&cipher_suites),
+            compression_methods,
+            ..Default::default()
+        })
     }
 
     /// Parse and read a vector size field. Takes the length of the field size as a parameter.
-    fn read_vector_size<R: Read>(reader: &mut ReaderBuf<R>, len: usize) -> Result<usize> {
+    async fn read_vector_size<R: AsyncRead + Unpin>(
+        reader: &mut ReaderBuf<R>,
+        len: usize,
+    ) -> Result<usize> {
         Ok(match len {
-            1 => *reader.read_as::<u8>()? as usize,
+            1 => *reader.read_as::<u8>().await? as usize,
             2 => {
-                let size = reader.read_as::<[u8; 2]>()?;
+                let size = reader.read_as::<[u8; 2]>().await?;
                 u16::from_be_bytes(*size) as usize
             }
             x => bail!("Vector length unsupported ({})", x),
@@ -259,15 +428,124 @@ impl Tls {
         Ok(String::from_utf8(ext[cursor..(cursor + size)].into())?)
     }
 
-    /// Parse the ALPN extension, look for TLS challenge strings and return true if found.
+    /// Parse an extension holding a single vector of u16 values, eg. supported_groups, and return
+    /// them. Takes the length of the vector size field as a parameter.
+    fn u16_list_ext_get(ext: &[u8], len: usize) -> Result<Vec<u16>> {
+        let buf_len = ext.len();
+
+        // No need to go further if we can't even read the field size below.
+        if buf_len < len {
+            bail!("Extension len is too small ({} < {})", buf_len, len);
+        }
+
+        // Retrieve the size of the vector and take into account the len field itself.
+        let size = match len {
+            1 => ext[0] as usize,
+            2 => u16::from_be_bytes(ext[0..=1].try_into()?) as usize,
+            x => bail!("Vector length unsupported ({})", x),
+        };
+
+        // Check the buffer we are working on matches the size it contains.
+        if size + len != buf_len {
+            bail!(
+                "Extension len does not match the buffer one ({} != {})",
+                size + len,
+                buf_len
+            );
+        }
+        if size % 2 != 0 {
+            bail!("Extension vector length is invalid ({} % 2 != 0)", size);
+        }
+
+        Ok(Self::to_u16_list(&ext[len..]))
+    }
+
+    /// Parse the EC point formats extension and return the formats it holds.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc8422#section-5.1.2
+    fn ec_point_formats_ext_get(ext: &[u8]) -> Result<Vec<u8>> {
+        // No need to go further if we can't even read the field size below.
+        if ext.is_empty() {
+            bail!("EC point formats extension len is too small (0 < 1)");
+        }
+
+        // Check the buffer we are working on matches the size it contains.
+        let len = ext[0] as usize + mem::size_of::<u8>();
+        if len != ext.len() {
+            bail!(
+                "EC point formats extension len does not match the buffer one ({} != {})",
+                len,
+                ext.len()
+            );
+        }
+
+        Ok(ext[1..].to_vec())
+    }
+
+    /// Parse the encrypted_client_hello extension and return its description
+    /// if it is an outer one. Inner ones are only found in the encrypted
+    /// payload and are not expected here. Unknown types, eg. from a later
+    /// version of the extension, are ignored instead of failing the whole
+    /// ClientHello.
     ///
-    /// https://www.rfc-editor.org/rfc/rfc8737
-    fn alpn_ext_is_challenge(ext: &[u8]) -> Result<bool> {
+    /// https://datatracker.ietf.org/doc/html/draft-ietf-tls-esni#section-5
+    fn ech_ext_get(ext: &[u8]) -> Result<Option<Ech>> {
+        match ext.first() {
+            // Outer ClientHello.
+            Some(0) => (),
+            // Inner ClientHello, or unknown type.
+            Some(_) => return Ok(None),
+            None => bail!("Encrypted client hello extension is empty"),
+        }
+
+        // Type:         u8
+        // KDF id:       u16
+        // AEAD id:      u16
+        // Config id:    u8
+        // Encapsulated key and payload, each prefixed by their u16 length.
+        if ext.len() < 8 {
+            bail!(
+                "Encrypted client hello extension len is too small ({} < 8)",
+                ext.len()
+            );
+        }
+        let ech = Ech {
+            config_id: ext[5],
+            kdf_id: u16::from_be_bytes(ext[1..=2].try_into()?),
+            aead_id: u16::from_be_bytes(ext[3..=4].try_into()?),
+        };
+
+        // Check the encapsulated key and the payload fill the extension.
+        let enc_len = u16::from_be_bytes(ext[6..=7].try_into()?) as usize;
+        let cursor = 8 + enc_len;
+        if cursor + mem::size_of::<u16>() > ext.len() {
+            bail!(
+                "Reached the end of the encrypted client hello extension buffer while processing"
+            );
+        }
+        let len = u16::from_be_bytes(ext[cursor..(cursor + 2)].try_into()?) as usize
+            + cursor
+            + mem::size_of::<u16>();
+        if len != ext.len() {
+            bail!(
+                "Encrypted client hello extension len does not match the buffer one ({} != {})",
+                len,
+                ext.len()
+            );
+        }
+
+        Ok(Some(ech))
+    }
+
+    /// Parse the key share extension and return the groups of the key shares it holds.
+    ///
+    /// https://www.rfc-editor.org/rfc/rfc8446#section-4.2.8
+    fn key_share_ext_get_groups(ext: &[u8]) -> Result<Vec<u16>> {
         let buf_len = ext.len();
 
         // No need to go further if we can't even read the field size below.
         if buf_len < 2 {
-            bail!("ALPN extension len is too small ({} < 2)", buf_len);
+            bail!("Key share extension len is too small ({} < 2)", buf_len);
         }
 
         // Retrieve the size of the extension and take into account the len field itself.
@@ -278,53 +556,96 @@ impl Tls {
         // Check the buffer we are working on matches the size it contains.
         if len != buf_len {
             bail!(
-                "ALPN extension len does not match the buffer one ({} != {})",
+                "Key share extension len does not match the buffer one ({} != {})",
                 len,
                 buf_len
             );
         }
 
-        // Go through the protocol names as defined in RFC7301.
-        //
-        // We're not looping through the names below and instead look for the first valid one.
-        // We're taking this shortcut as RFC8737 explicitly states "the ACME server MUST provide
-        // an ALPN extension with the single protocol name 'acme-tls/1'".
-        //
-        // https://datatracker.ietf.org/doc/html/rfc7301#section-3.1
-        // https://www.rfc-editor.org/rfc/rfc8737#section-3
+        let mut groups = Vec::new();
+        while cursor < len {
+            // Check we won't go past the buffer.
+            if cursor + mem::size_of::<u16>() /* group */ + mem::size_of::<u16>() /* size */ > len {
+                bail!("Reached the end of the key share extension buffer while processing");
+            }
 
-        // Check we won't go past the buffer.
-        if cursor + mem::size_of::<u8>() /* size */ > len {
-            bail!("Reached the end of the ALPN extension buffer while processing");
+            // Parse the group and skip the key exchange data.
+            let group = u16::from_be_bytes(ext[cursor..(cursor + 2)].try_into()?);
+            let size = u16::from_be_bytes(ext[(cursor + 2)..(cursor + 4)].try_into()?) as usize;
+            cursor += 2 * mem::size_of::<u16>() + size;
+
+            if cursor > len {
+                bail!("Reached the end of the key share extension buffer while processing");
+            }
+            groups.push(group);
         }
 
-        // First parse the name string size.
-        let size = ext[cursor] as usize;
-        cursor += mem::size_of::<u8>();
+        Ok(groups)
+    }
 
-        // Check we won't go past the buffer.
-        if cursor + size > len {
-            println!("{cursor} + {size} > {len}");
-            bail!("Reached the end of the ALPN extension buffer while processing");
+    /// Convert a buffer of big endian u16 values, of an even length, to a Vec<u16>.
+    fn to_u16_list(buf: &[u8]) -> Vec<u16> {
+        buf.chunks_exact(2)
+            .map(|x| u16::from_be_bytes([x[0], x[1]]))
+            .collect()
+    }
+
+    /// Parse the ALPN extension and return the list of protocol names it holds.
+    ///
+    /// https://datatracker.ietf.org/doc/html/rfc7301#section-3.1
+    fn alpn_ext_get_protocols(ext: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let buf_len = ext.len();
+
+        // No need to go further if we can't even read the field size below.
+        if buf_len < 2 {
+            bail!("ALPN extension len is too small ({} < 2)", buf_len);
         }
 
-        // Finally retrieve the protocol name.
-        let name = String::from_utf8(ext[cursor..(cursor + size)].into())?;
-        cursor += size;
+        // Retrieve the size of the extension and take into account the len field itself.
+        let len = u16::from_be_bytes(ext[0..=1].try_into()?) as usize + mem::size_of::<u16>();
+        // We read the extension size above, initialize the cursor to go past it.
+        let mut cursor = mem::size_of::<u16>();
 
-        // Check if the protocol name is for tls-alpn-01 and is the only protocol listed in the
-        // extension. https://www.rfc-editor.org/rfc/rfc8737#section-3
-        if cursor == len && name == "acme-tls/1" {
-            return Ok(true);
+        // Check the buffer we are working on matches the size it contains.
+        if len != buf_len {
+            bail!(
+                "ALPN extension len does not match the buffer one ({} != {})",
+                len,
+                buf_len
+            );
+        }
+
+        // The list of protocol names can't be empty.
+        if cursor == len {
+            bail!("ALPN extension has no protocol name");
         }
 
-        Ok(false)
+        let mut protocols = Vec::new();
+        while cursor < len {
+            // First parse the name string size.
+            let size = ext[cursor] as usize;
+            cursor += mem::size_of::<u8>();
+
+            // Check we won't go past the buffer.
+            if size == 0 || cursor + size > len {
+                bail!("Invalid protocol name in the ALPN extension");
+            }
+
+            // Then retrieve the protocol name.
+            protocols.push(ext[cursor..(cursor + size)].to_vec());
+            cursor += size;
+        }
+
+        Ok(protocols)
     }
 
     /// Parse and read a vector, and return a Vec<u8> with its data. Takes the length of the field
     /// size as a parameter.
-    fn read_vector<R: Read>(reader: &mut ReaderBuf<R>, len: usize) -> Result<Vec<u8>> {
-        let size = Self::read_vector_size(reader, len)?;
+    async fn read_vector<R: AsyncRead + Unpin>(
+        reader: &mut ReaderBuf<R>,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let size = Self::read_vector_size(reader, len).await?;
 
         // Valid, can be checked for specific cases outside this helper.
         if size == 0 {
@@ -332,30 +653,107 @@ impl Tls {
         }
 
         // Finally read the vector data.
-        Ok(reader.read_exact(size)?.to_vec())
+        Ok(reader.read_exact(size).await?.to_vec())
     }
 
     /// Get the hostname we read from the SNI extension, if any. None is a valid valid regarding the
     /// TLS spec.
     pub(crate) fn hostname(&self) -> Option<&String> {
-        self.sni_hostname.as_ref()
+        self.client_hello.sni_hostname.as_ref()
+    }
+
+    /// Get the protocols offered in the ALPN extension, if any.
+    pub(crate) fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.client_hello.alpn_protocols
+    }
+
+    /// Get the full ClientHello message.
+    pub(crate) fn client_hello(&self) -> &ClientHello {
+        &self.client_hello
     }
 
     /// Check if the ALPN extension was a valid tls-alpn-01 challenge, if any.
     pub(crate) fn is_challenge(&self) -> bool {
-        self.alpn_is_challenge
+        self.client_hello.is_challenge()
     }
 }
 
+/// TLS protocol versions, as used in the configuration.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, PartialOrd, Serialize)]
+#[serde(try_from = "VersionRepr", into = "String")]
+pub(crate) enum TlsVersion {
+    Tls10 = 0x301,
+    Tls11 = 0x302,
+    Tls12 = 0x303,
+    Tls13 = 0x304,
+}
+
+/// Versions can be written as strings ("1.2") or as numbers (1.2) in YAML.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VersionRepr {
+    Str(String),
+    Num(f64),
+}
+
+impl TryFrom<VersionRepr> for TlsVersion {
+    type Error = String;
+
+    fn try_from(version: VersionRepr) -> Result<Self, Self::Error> {
+        let version = match version {
+            VersionRepr::Str(version) => version,
+            VersionRepr::Num(version) => format!("{version:.1}"),
+        };
+        Ok(match version.as_str() {
+            "1.0" => TlsVersion::Tls10,
+            "1.1" => TlsVersion::Tls11,
+            "1.2" => TlsVersion::Tls12,
+            "1.3" => TlsVersion::Tls13,
+            _ => return Err(format!("Unknown TLS version ({version})")),
+        })
+    }
+}
+
+impl From<TlsVersion> for String {
+    fn from(version: TlsVersion) -> Self {
+        version.to_string()
+    }
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TlsVersion::Tls10 => "1.0",
+            TlsVersion::Tls11 => "1.1",
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        })
+    }
+}
+
+/// Is `value` a GREASE value, reserved to prevent extensibility failures and which can be found
+/// in most of the ClientHello lists.
+/// https://www.rfc-editor.org/rfc/rfc8701
+pub(crate) fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
 /// https://www.rfc-editor.org/rfc/rfc8446#section-6
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum AlertDescription {
+    HandshakeFailure = 40,
     AccessDenied = 49,
+    ProtocolVersion = 70,
     InternalError = 80,
     UnrecognizedName = 112,
 }
 
 /// Send a fatal alert message with the provided desc code to the remote end.
-pub(crate) fn alert<T: Write>(writer: &mut T, desc: AlertDescription) -> Result<()> {
+pub(crate) async fn alert<T: AsyncWrite + Unpin>(
+    writer: &mut T,
+    desc: AlertDescription,
+) -> Result<()> {
     // Send back a crafted alert message.
     // https://www.rfc-editor.org/rfc/rfc8446#section-5.1
     // https://www.rfc-editor.org/rfc/rfc8446#section-6
@@ -365,15 +763,18 @@ pub(crate) fn alert<T: Write>(writer: &mut T, desc: AlertDescription) -> Result<
     // - Length: 2
     // - Level: 2 (fatal)
     // - Desc.
-    writer.write_all(&[21, 3, 0, 0, 2, 2, desc as u8])?;
+    writer.write_all(&[21, 3, 0, 0, 2, 2, desc as u8]).await?;
     Ok(())
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_comparison)]
 pub(crate) mod tests {
     use super::*;
     use crate::reader::ReaderBuf as B;
 
+    const MAX_LEN: usize = 64 * 1024;
+
     // Valid record, including an SNI but no ALPN extension.
     pub(crate) const RECORD_SNI: &[u8] = &[
         22, 3, 1, 1, 54, 1, 0, 1, 50, 3, 3, 203, 69, 166, 24, 168, 5, 235, 3, 40, 94, 250, 34, 63,
@@ -428,103 +829,147 @@ pub(crate) mod tests {
         200, 235, 65, 252, 62, 213, 12, 28, 115, 126, 46, 52, 72, 108, 158, 10,
     ];
 
-    #[test]
-    fn vector() {
+    #[tokio::test]
+    async fn vector() {
         // Valid vectors with no data.
-        assert!(Tls::read_vector(&mut B::from_bytes(&[0]), 1).is_ok());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 0]), 2).is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[0]), 1).await.is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 0]), 2)
+            .await
+            .is_ok());
 
         // Valid vectors with data.
-        assert!(Tls::read_vector(&mut B::from_bytes(&[1, 42]), 1).is_ok());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[5, 42, 0, 10, 255, 3]), 1).is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[1, 42]), 1)
+            .await
+            .is_ok());
+        assert!(
+            Tls::read_vector(&mut B::from_bytes(&[5, 42, 0, 10, 255, 3]), 1)
+                .await
+                .is_ok()
+        );
         let vector = [vec![255], vec![42; 255]].concat();
-        assert!(Tls::read_vector(&mut B::from_bytes(&vector), 1).is_ok());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 1, 42]), 2).is_ok());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 3, 42, 13, 37]), 2).is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&vector), 1)
+            .await
+            .is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 1, 42]), 2)
+            .await
+            .is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 3, 42, 13, 37]), 2)
+            .await
+            .is_ok());
         let vector = [vec![1, 0], vec![10; 256]].concat();
-        assert!(Tls::read_vector(&mut B::from_bytes(&vector), 2).is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&vector), 2)
+            .await
+            .is_ok());
         let vector = [vec![255, 255], vec![99; 255 << 16 | 255]].concat();
-        assert!(Tls::read_vector(&mut B::from_bytes(&vector), 2).is_ok());
+        assert!(Tls::read_vector(&mut B::from_bytes(&vector), 2)
+            .await
+            .is_ok());
 
         // Empty vectors.
-        assert!(Tls::read_vector(&mut B::from_bytes(&[]), 1).is_err());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[]), 2).is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[]), 1).await.is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[]), 2).await.is_err());
 
         // Vectors too small.
-        assert!(Tls::read_vector(&mut B::from_bytes(&[0]), 2).is_err());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[1]), 1).is_err());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[2, 0]), 1).is_err());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[255, 0]), 1).is_err());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 1]), 2).is_err());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 3, 0, 0]), 2).is_err());
-        assert!(Tls::read_vector(&mut B::from_bytes(&[255, 255, 0]), 2).is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[0]), 2).await.is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[1]), 1).await.is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[2, 0]), 1)
+            .await
+            .is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[255, 0]), 1)
+            .await
+            .is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 1]), 2)
+            .await
+            .is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[0, 3, 0, 0]), 2)
+            .await
+            .is_err());
+        assert!(Tls::read_vector(&mut B::from_bytes(&[255, 255, 0]), 2)
+            .await
+            .is_err());
     }
 
-    #[test]
-    fn record() {
+    #[tokio::test]
+    async fn record() {
         // Valid record headers, using different TLS versions and lengths.
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 1, 0, 0])).is_ok());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 2, 0, 0])).is_ok());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 3, 0, 0])).is_ok());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 1, 64, 0])).is_ok());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 2, 0, 42])).is_ok());
-        assert!(
-            Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 3, 13, 37])).is_ok()
-        );
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 1, 0, 0]).is_ok());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 2, 0, 0]).is_ok());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 3, 0, 0]).is_ok());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 1, 64, 0]).is_ok());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 2, 0, 42]).is_ok());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 3, 13, 37]).is_ok());
 
         // Invalid records.
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[0, 0, 0, 0, 0])).is_err());
-        assert!(
-            Tls::parse_plaintext_record_header(&mut B::from_bytes(&[255, 1, 0, 0, 0])).is_err()
-        );
+        assert!(Tls::parse_plaintext_record_header(&[0, 0, 0, 0, 0]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[255, 1, 0, 0, 0]).is_err());
 
         // Invalid versions.
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 0, 3, 0, 0])).is_err());
-        assert!(
-            Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 255, 3, 0, 0])).is_err()
-        );
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 0, 0, 0])).is_err());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 4, 0, 0])).is_err());
-        assert!(
-            Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 255, 0, 0])).is_err()
-        );
+        assert!(Tls::parse_plaintext_record_header(&[22, 0, 3, 0, 0]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 255, 3, 0, 0]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 0, 0, 0]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 4, 0, 0]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 255, 0, 0]).is_err());
 
         // Invalid length field.
-        assert!(
-            Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 3, 64, 1])).is_err()
-        );
-        assert!(
-            Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 3, 255, 255])).is_err()
-        );
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 3, 64, 1]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 3, 255, 255]).is_err());
 
         // Not enough data in the reader.
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 3, 0])).is_err());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3, 3])).is_err());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22, 3])).is_err());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[22])).is_err());
-        assert!(Tls::parse_plaintext_record_header(&mut B::from_bytes(&[])).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 3, 0]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3, 3]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22, 3]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[22]).is_err());
+        assert!(Tls::parse_plaintext_record_header(&[]).is_err());
     }
 
-    #[test]
-    fn handshake() {
+    #[tokio::test]
+    async fn handshake() {
         // Client Hello empty message.
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1, 0, 0, 0])).is_ok());
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1, 255, 255, 255])).is_ok());
+        assert!(
+            Tls::parse_handshake_header(&mut B::from_bytes(&[1, 0, 0, 0]))
+                .await
+                .is_ok()
+        );
+        assert!(
+            Tls::parse_handshake_header(&mut B::from_bytes(&[1, 255, 255, 255]))
+                .await
+                .is_ok()
+        );
 
         // Invalid messages (non-client hello).
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[0, 0, 0, 0])).is_err());
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[42, 0, 0, 0])).is_err());
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[255, 0, 0, 0])).is_err());
+        assert!(
+            Tls::parse_handshake_header(&mut B::from_bytes(&[0, 0, 0, 0]))
+                .await
+                .is_err()
+        );
+        assert!(
+            Tls::parse_handshake_header(&mut B::from_bytes(&[42, 0, 0, 0]))
+                .await
+                .is_err()
+        );
+        assert!(
+            Tls::parse_handshake_header(&mut B::from_bytes(&[255, 0, 0, 0]))
+                .await
+                .is_err()
+        );
 
         // Not enough data in the reader.
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1, 0, 0])).is_err());
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1, 0])).is_err());
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1])).is_err());
-        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[])).is_err());
+        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1, 0, 0]))
+            .await
+            .is_err());
+        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1, 0]))
+            .await
+            .is_err());
+        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[1]))
+            .await
+            .is_err());
+        assert!(Tls::parse_handshake_header(&mut B::from_bytes(&[]))
+            .await
+            .is_err());
     }
 
-    #[test]
-    fn client_hello() {
+    #[tokio::test]
+    async fn client_hello() {
         let protocol_version = vec![0x3, 0x3];
         #[rustfmt::skip]
         let random = vec![
@@ -549,27 +994,45 @@ pub(crate) mod tests {
 
         // Valid protocol versions.
         let mut buf = hello.clone();
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_ok());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_ok());
         buf[1] = 0x1;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_ok());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_ok());
         buf[1] = 0x2;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_ok());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_ok());
 
         // Invalid protocol versions.
         let mut buf = hello.clone();
         buf[0] = 1;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[0] = 0;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[0] = 255;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[0] = 3;
         buf[1] = 0;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[1] = 4;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[1] = 255;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
 
         // Invalid random.
         let invalid = vec![
@@ -584,7 +1047,9 @@ pub(crate) mod tests {
             compression_methods.clone(),
         ]
         .concat();
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
 
         let invalid = vec![
             0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
@@ -599,7 +1064,9 @@ pub(crate) mod tests {
             compression_methods.clone(),
         ]
         .concat();
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
 
         // Valid longer session ids.
         let valid = vec![0x1, 0x42];
@@ -611,7 +1078,9 @@ pub(crate) mod tests {
             compression_methods.clone(),
         ]
         .concat();
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_ok());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_ok());
 
         let valid = vec![0x3, 0x42, 0x13, 0x37];
         let buf = [
@@ -622,33 +1091,51 @@ pub(crate) mod tests {
             compression_methods.clone(),
         ]
         .concat();
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_ok());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_ok());
 
         // Invalid session id.
         let mut buf = hello.clone();
         buf[34] = 33;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[34] = 255;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[34] = 16; /* Valid len but buffer too small */
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
 
         // Invalid cipher suites.
         let mut buf = hello.clone();
         buf[36] = 0;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[36] = 3;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[35] = 3;
         buf[36] = 5;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
 
         // Invalid compression method.
         let mut buf = hello.clone();
         buf[39] = 0;
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
         buf[39] = 255; /* Valid len but buffer too small */
-        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf)).is_err());
+        assert!(Tls::parse_client_hello(&mut B::from_bytes(&buf))
+            .await
+            .is_err());
     }
 
     #[test]
@@ -703,52 +1190,321 @@ pub(crate) mod tests {
     }
 
     #[test]
-    fn alpn_tls_challenge() {
-        // Valid tls-alpn-01 challenge record.
-        let alpn = &[0, 11, 10, 97, 99, 109, 101, 45, 116, 108, 115, 47, 49];
-        assert!(Tls::alpn_ext_is_challenge(alpn).unwrap() == true);
+    fn alpn_protocols() {
+        assert_eq!(
+            Tls::alpn_ext_get_protocols(&[0, 3, 2, 104, 50]).unwrap(),
+            vec![b"h2"]
+        );
+        #[rustfmt::skip]
+        let alpn = &[
+            0, 23,
+            10, 97, 99, 109, 101, 45, 116, 108, 115, 47, 49,
+            2, 104, 50,
+            8, 104, 116, 116, 112, 47, 49, 46, 49,
+        ];
+        assert_eq!(
+            Tls::alpn_ext_get_protocols(alpn).unwrap(),
+            vec![&b"acme-tls/1"[..], b"h2", b"http/1.1"]
+        );
 
-        // Valid non tls-alpn-01 challenge record.
-        assert!(Tls::alpn_ext_is_challenge(&[0, 3, 2, 104, 50]).unwrap() == false);
+        // Protocol names are opaque bytes.
+        assert_eq!(
+            Tls::alpn_ext_get_protocols(&[0, 3, 2, 0xff, 0xfe]).unwrap(),
+            vec![vec![0xff, 0xfe]]
+        );
 
         // Invalid lengths.
-        assert!(Tls::alpn_ext_is_challenge(&[]).is_err());
-        assert!(Tls::alpn_ext_is_challenge(&[0]).is_err());
-        assert!(Tls::alpn_ext_is_challenge(&[0, 0]).is_err());
-        assert!(Tls::alpn_ext_is_challenge(&[255, 255]).is_err());
-        assert!(Tls::alpn_ext_is_challenge(&[0, 10, 0]).is_err());
-        assert!(Tls::alpn_ext_is_challenge(&[0, 10, 255]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0, 0]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0, 1]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0, 1, 0]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[255, 255]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0, 10, 0]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0, 10, 255]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0, 3, 3, 104, 50]).is_err());
+        assert!(Tls::alpn_ext_get_protocols(&[0, 4, 2, 104, 50, 1]).is_err());
 
         // Invalid ALPN length.
         let alpn = &[0, 12, 10, 97, 99, 109, 101, 45, 116, 108, 115, 47];
-        assert!(Tls::alpn_ext_is_challenge(alpn).is_err());
+        assert!(Tls::alpn_ext_get_protocols(alpn).is_err());
         let alpn = &[0, 11, 10, 97, 99, 109, 101, 45, 116, 108, 115, 47];
-        assert!(Tls::alpn_ext_is_challenge(alpn).is_err());
+        assert!(Tls::alpn_ext_get_protocols(alpn).is_err());
         let alpn = &[0, 11, 11, 97, 99, 109, 101, 45, 116, 108, 115, 47, 49];
-        assert!(Tls::alpn_ext_is_challenge(alpn).is_err());
-
-        // Multiple ALPN records. Valid, but not for tls-alpn-01.
-        #[rustfmt::skip]
-        let alpn = &[
-            0, 14,
-            10, 97, 99, 109, 101, 45, 116, 108, 115, 47, 49,
-            2, 104, 50,
-        ];
-        assert!(Tls::alpn_ext_is_challenge(alpn).unwrap() == false);
+        assert!(Tls::alpn_ext_get_protocols(alpn).is_err());
     }
 
     #[test]
-    fn tls() {
-        let tls = Tls::from(&mut B::from_bytes(RECORD_SNI)).unwrap();
+    fn challenge() {
+        // Valid tls-alpn-01 challenge.
+        let hello = ClientHello {
+            alpn_protocols: vec![b"acme-tls/1".to_vec()],
+            ..Default::default()
+        };
+        assert!(hello.is_challenge() == true);
+
+        // Valid non tls-alpn-01 challenge.
+        let hello = ClientHello {
+            alpn_protocols: vec![b"h2".to_vec()],
+            ..Default::default()
+        };
+        assert!(hello.is_challenge() == false);
+
+        // Multiple ALPN protocols. Valid, but not for tls-alpn-01.
+        let hello = ClientHello {
+            alpn_protocols: vec![b"acme-tls/1".to_vec(), b"h2".to_vec()],
+            ..Default::default()
+        };
+        assert!(hello.is_challenge() == false);
+        assert!(ClientHello::default().is_challenge() == false);
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        let tls = Tls::from(&mut B::from_bytes(RECORD_SNI), MAX_LEN)
+            .await
+            .unwrap();
         assert!(tls.hostname().unwrap() == "example.net");
         assert!(tls.is_challenge() == false);
+        assert!(tls.alpn_protocols().is_empty());
 
-        let tls = Tls::from(&mut B::from_bytes(RECORD_SNI_ALPN)).unwrap();
+        let tls = Tls::from(&mut B::from_bytes(RECORD_SNI_ALPN), MAX_LEN)
+            .await
+            .unwrap();
         assert!(tls.hostname().unwrap() == "example.net");
         assert!(tls.is_challenge() == true);
+        assert_eq!(tls.alpn_protocols(), &[b"acme-tls/1"]);
 
-        let tls = Tls::from(&mut B::from_bytes(RECORD_NO_EXT)).unwrap();
+        let tls = Tls::from(&mut B::from_bytes(RECORD_NO_EXT), MAX_LEN)
+            .await
+            .unwrap();
         assert!(tls.hostname().is_none());
         assert!(tls.is_challenge() == false);
     }
+
+    #[tokio::test]
+    async fn client_hello_model() {
+        let tls = Tls::from(&mut B::from_bytes(RECORD_SNI_ALPN), MAX_LEN)
+            .await
+            .unwrap();
+        let hello = tls.client_hello();
+        assert_eq!(hello.legacy_version, 0x303);
+        assert_eq!(hello.supported_versions, vec![0x304, 0x303]);
+        assert_eq!(hello.cipher_suites.len(), 36);
+        assert_eq!(hello.cipher_suites[..4], [0x1302, 0x1303, 0x1301, 0x1304]);
+        assert_eq!(hello.compression_methods, vec![0]);
+        assert_eq!(
+            hello.supported_groups,
+            vec![29, 23, 30, 25, 24, 256, 257, 258, 259, 260]
+        );
+        assert_eq!(hello.ec_point_formats, vec![0, 1, 2]);
+        assert_eq!(hello.signature_algorithms.len(), 16);
+        assert_eq!(hello.signature_algorithms[..2], [0x403, 0x503]);
+        assert_eq!(hello.key_share_groups, vec![29]);
+        assert_eq!(hello.sni_hostname.as_deref(), Some("example.net"));
+        assert_eq!(hello.alpn_protocols, vec![b"acme-tls/1"]);
+        assert_eq!(
+            hello.extensions,
+            vec![0, 11, 10, 35, 16, 22, 23, 13, 43, 45, 51]
+        );
+        assert_eq!(hello.max_version(), 0x304);
+        assert!(!hello.has_psk());
+        assert!(!hello.has_early_data());
+        assert!(hello.ech.is_none());
+
+        let hello = ClientHello {
+            extensions: vec![
+                0,
+                EXT_ENCRYPTED_CLIENT_HELLO,
+                EXT_EARLY_DATA,
+                EXT_PRE_SHARED_KEY,
+            ],
+            ..Default::default()
+        };
+        assert!(hello.has_psk());
+        assert!(hello.has_early_data());
+    }
+
+    #[test]
+    fn u16_list_ext() {
+        assert_eq!(
+            Tls::u16_list_ext_get(&[4, 3, 4, 3, 3], 1).unwrap(),
+            vec![0x304, 0x303]
+        );
+        assert_eq!(Tls::u16_list_ext_get(&[0, 2, 0, 29], 2).unwrap(), vec![29]);
+        assert!(Tls::u16_list_ext_get(&[0], 1).unwrap().is_empty());
+
+        assert!(Tls::u16_list_ext_get(&[], 1).is_err());
+        assert!(Tls::u16_list_ext_get(&[0], 2).is_err());
+        assert!(Tls::u16_list_ext_get(&[0, 2, 0, 29], 4).is_err());
+        assert!(Tls::u16_list_ext_get(&[3, 3, 4, 3], 1).is_err());
+        assert!(Tls::u16_list_ext_get(&[0, 3, 0, 29], 2).is_err());
+        assert!(Tls::u16_list_ext_get(&[0, 1, 0], 2).is_err());
+    }
+
+    #[test]
+    fn ec_point_formats_ext() {
+        assert_eq!(Tls::ec_point_formats_ext_get(&[1, 0]).unwrap(), vec![0]);
+        assert!(Tls::ec_point_formats_ext_get(&[]).is_err());
+        assert!(Tls::ec_point_formats_ext_get(&[2, 0]).is_err());
+        assert!(Tls::ec_point_formats_ext_get(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn versions() {
+        // Legacy version only, or GREASE values ignored.
+        let mut hello = ClientHello {
+            legacy_version: 0x302,
+            ..Default::default()
+        };
+        assert_eq!(hello.max_version(), 0x302);
+        hello.supported_versions = vec![0x7a7a, 0x303];
+        assert_eq!(hello.max_version(), 0x303);
+
+        for (input, version) in [
+            ("1.0", TlsVersion::Tls10),
+            ("\"1.1\"", TlsVersion::Tls11),
+            ("1.2", TlsVersion::Tls12),
+            ("'1.3'", TlsVersion::Tls13),
+        ] {
+            assert_eq!(serde_yaml::from_str::<TlsVersion>(input).unwrap(), version);
+        }
+        assert!(serde_yaml::from_str::<TlsVersion>("1.4").is_err());
+        assert!(serde_yaml::from_str::<TlsVersion>("tls1.2").is_err());
+        assert_eq!(
+            serde_yaml::to_string(&TlsVersion::Tls12).unwrap(),
+            "'1.2'\n"
+        );
+        assert!(TlsVersion::Tls12 < TlsVersion::Tls13);
+    }
+
+    #[test]
+    fn grease() {
+        for value in [0x0a0a, 0x1a1a, 0x7a7a, 0xfafa] {
+            assert!(is_grease(value));
+        }
+        for value in [0x0a1a, 0x0a0b, 0x1301, 0x0000, 0xffff] {
+            assert!(!is_grease(value));
+        }
+    }
+
+    #[test]
+    fn key_share_ext() {
+        assert_eq!(
+            Tls::key_share_ext_get_groups(&[0, 9, 0, 29, 0, 1, 42, 0x11, 0xec, 0, 0]).unwrap(),
+            vec![29, 0x11ec]
+        );
+        assert!(Tls::key_share_ext_get_groups(&[0, 0]).unwrap().is_empty());
+
+        assert!(Tls::key_share_ext_get_groups(&[]).is_err());
+        assert!(Tls::key_share_ext_get_groups(&[0, 1, 0]).is_err());
+        assert!(Tls::key_share_ext_get_groups(&[0, 3, 0, 29, 0]).is_err());
+        assert!(Tls::key_share_ext_get_groups(&[0, 5, 0, 29, 0, 2, 42]).is_err());
+    }
+
+    #[test]
+    fn ech_ext() {
+        assert_eq!(
+            Tls::ech_ext_get(&[0, 0, 1, 0, 3, 42, 0, 2, 0xaa, 0xbb, 0, 1, 0xcc]).unwrap(),
+            Some(Ech {
+                config_id: 42,
+                kdf_id: 1,
+                aead_id: 3,
+            })
+        );
+        // The encapsulated key is empty after a HelloRetryRequest.
+        assert!(Tls::ech_ext_get(&[0, 0, 1, 0, 1, 7, 0, 0, 0, 1, 0xcc])
+            .unwrap()
+            .is_some());
+        assert_eq!(Tls::ech_ext_get(&[1]).unwrap(), None);
+
+        assert!(Tls::ech_ext_get(&[]).is_err());
+        assert_eq!(Tls::ech_ext_get(&[2]).unwrap(), None);
+        assert_eq!(Tls::ech_ext_get(&[255, 0, 1, 0, 1, 7]).unwrap(), None);
+        assert!(Tls::ech_ext_get(&[0, 0, 1, 0, 1, 7, 0]).is_err());
+        assert!(Tls::ech_ext_get(&[0, 0, 1, 0, 1, 7, 0, 2, 0xaa]).is_err());
+        assert!(Tls::ech_ext_get(&[0, 0, 1, 0, 1, 7, 0, 0, 0, 2, 0xcc]).is_err());
+        assert!(Tls::ech_ext_get(&[0, 0, 1, 0, 1, 7, 0, 0, 0, 1, 0xcc, 0]).is_err());
+    }
+
+    /// Splits the handshake message of `record` in records holding at most
+    /// `size` bytes of it.
+    fn fragment(record: &[u8], size: usize) -> Vec<u8> {
+        record[5..]
+            .chunks(size)
+            .flat_map(|chunk| {
+                let len = (chunk.len() as u16).to_be_bytes();
+                [&[22, 3, 1, len[0], len[1]], chunk].concat()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fragmented() {
+        // Records can be split anywhere, even in the handshake header, and are
+        // kept as-is in the reader buffer.
+        for size in [1, 3, 100] {
+            let records = fragment(RECORD_SNI_ALPN, size);
+            let mut rb = B::from_bytes(&records);
+            let tls = Tls::from(&mut rb, MAX_LEN).await.unwrap();
+            assert_eq!(tls.hostname().unwrap(), "example.net");
+            assert_eq!(tls.alpn_protocols(), &[b"acme-tls/1"]);
+            assert_eq!(rb.buf(), records.as_slice());
+        }
+
+        // Data following the ClientHello is not part of it.
+        let records = [RECORD_SNI, &[23, 3, 3, 0, 1, 0]].concat();
+        let tls = Tls::from(&mut B::from_bytes(&records), MAX_LEN)
+            .await
+            .unwrap();
+        assert_eq!(tls.hostname().unwrap(), "example.net");
+
+        // The ClientHello length is limited, its 4 bytes header included.
+        let len = RECORD_SNI.len() - 5;
+        let records = fragment(RECORD_SNI, 100);
+        assert!(Tls::from(&mut B::from_bytes(&records), len).await.is_ok());
+        assert!(Tls::from(&mut B::from_bytes(&records), len - 1)
+            .await
+            .is_err());
+
+        // And so are the records holding it, headers included.
+        let records = fragment(RECORD_SNI, 1);
+        assert!(Tls::from(&mut B::from_bytes(&records), 3 * len)
+            .await
+            .is_ok());
+        assert!(Tls::from(&mut B::from_bytes(&records), 2 * len)
+            .await
+            .is_err());
+
+        // A message of the maximum length split in many tiny records is
+        // accepted, as long as they are not more than twice its length. One
+        // more byte is still too much.
+        let records = fragment(RECORD_SNI, 8);
+        assert!(records.len() <= 2 * len);
+        assert!(Tls::from(&mut B::from_bytes(&records), len).await.is_ok());
+        assert!(Tls::from(&mut B::from_bytes(&records), len - 1)
+            .await
+            .is_err());
+        let records = fragment(RECORD_SNI, 4);
+        assert!(records.len() > 2 * len);
+        assert!(Tls::from(&mut B::from_bytes(&records), len).await.is_err());
+
+        // Truncated messages, empty or non-handshake records.
+        let records = fragment(RECORD_SNI, 100);
+        assert!(
+            Tls::from(&mut B::from_bytes(&records[..records.len() - 1]), MAX_LEN)
+                .await
+                .is_err()
+        );
+        let records = fragment(RECORD_SNI, 100);
+        let records = [&records[..105], &[22, 3, 1, 0, 0], &records[105..]].concat();
+        assert!(Tls::from(&mut B::from_bytes(&records), MAX_LEN)
+            .await
+            .is_err());
+        let mut records = fragment(RECORD_SNI, 100);
+        records[105] = 23;
+        assert!(Tls::from(&mut B::from_bytes(&records), MAX_LEN)
+            .await
+            .is_err());
+    }
 }
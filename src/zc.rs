@@ -1,36 +1,71 @@
 use std::{
-    future::poll_fn,
-    io::{self, Error},
+    future::{poll_fn, Future},
+    io,
     mem,
     os::fd::{AsRawFd, RawFd},
     pin::Pin,
     ptr,
     task::{ready, Context, Poll},
+    time::Duration,
 };
 
 use anyhow::{anyhow, bail, Result};
-use tokio::io::AsyncWriteExt;
+use tokio::{io::AsyncWriteExt, time::Instant};
 
 // Use 1M pipe size as this is the default max pipe buffer size
 // (see /proc/sys/fs/pipe-max-size).
 const PIPE_SIZE: usize = 1 << 20;
 
+/// Errors ending a copy early.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("no data was moved for longer than the idle timeout")]
+    IdleTimeout,
+}
+
 /// Bidirectional copy between two ZcAsyncIo enabled-types, in a zero-copy
-/// fashion.
-pub(crate) async fn copy_bidirectional<T>(a: &mut T, b: &mut T) -> Result<(usize, usize)>
+/// fashion. `progress` is called with the amount of data moved so far in each
+/// direction while the copy is ongoing. If `idle` is set, the copy fails with
+/// `Error::IdleTimeout` when no data was moved in either direction for that
+/// long.
+pub(crate) async fn copy_bidirectional<T, F>(
+    a: &mut T,
+    b: &mut T,
+    idle: Option<Duration>,
+    mut progress: F,
+) -> Result<(usize, usize)>
 where
     T: ZcAsyncIo,
+    F: FnMut(usize, usize),
 {
     let mut ab = Splice::new()?;
     let mut ba = Splice::new()?;
 
+    let mut idle = idle.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+    let mut last = (0, 0);
+
     let ret = poll_fn(|ctx| {
         // Do not wait for both ends to gracefully shutdown.
         match (ab.process(ctx, a, b)?, ba.process(ctx, b, a)?) {
             (Poll::Ready(a), Poll::Ready(b)) => Poll::Ready(Ok((a, b))),
-            (Poll::Ready(a), Poll::Pending) => Poll::Ready(Ok((a, 0))),
-            (Poll::Pending, Poll::Ready(b)) => Poll::Ready(Ok((0, b))),
-            _ => Poll::Pending,
+            (Poll::Ready(a), Poll::Pending) => Poll::Ready(Ok((a, ba.processed()))),
+            (Poll::Pending, Poll::Ready(b)) => Poll::Ready(Ok((ab.processed(), b))),
+            _ => {
+                let processed = (ab.processed(), ba.processed());
+                if let Some((timeout, sleep)) = &mut idle {
+                    // Data was moved, restart the idle timer.
+                    if processed != last {
+                        sleep.as_mut().reset(Instant::now() + *timeout);
+                    }
+                    if sleep.as_mut().poll(ctx).is_ready() {
+                        return Poll::Ready(Err(Error::IdleTimeout.into()));
+                    }
+                }
+                last = processed;
+
+                progress(processed.0, processed.1);
+                Poll::Pending
+            }
         }
     })
     .await;
@@ -109,6 +144,15 @@ impl Splice {
         }))
     }
 
+    /// Amount of data moved so far.
+    fn processed(&self) -> usize {
+        match self {
+            Self::Move(r#move) => r#move.processed,
+            Self::ShuttingDown(processed) => *processed,
+            Self::Done => 0,
+        }
+    }
+
     /// Calls internal logic depending on the current Splice state. This should
     /// be called for transferring data between fds and for all subsequent
     /// operations.
@@ -244,7 +288,7 @@ impl SpliceMove {
             // readiness accordingly.
             match ret {
                 x if x < 0 => {
-                    let err = Error::last_os_error();
+                    let err = io::Error::last_os_error();
                     match err.raw_os_error() {
                         Some(e) if e == libc::EINTR => continue,
                         _ => return Err(err),